@@ -0,0 +1,499 @@
+//! Deciding which pixels of a rendered page are background (paper) and should be recolored.
+
+use crate::{Color, LittleEndianRgbPixel, RGBA_PIXEL_SIZE};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+const WHITE: Color = Color {
+    r: 255,
+    g: 255,
+    b: 255,
+};
+
+/// How the distance between a pixel and the paper color is measured
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Metric {
+    /// The largest difference in any one channel, so tolerance is in channel units (0-255).
+    /// Fast, but blind to how different colors look.
+    QueenWise,
+    /// CIEDE2000 color difference in CIELAB, so tolerance is in ΔE (roughly 0-100, where about
+    /// 2 is barely noticeable). Follows human perception, so it tells cream paper apart from
+    /// light-gray shading.
+    DeltaE2000,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BackgroundDetection {
+    pub metric: Metric,
+    /// The color of the paper, i.e. what counts as background
+    pub paper_color: Color,
+    /// Pixels closer than this to the paper color are background. Units depend on the metric.
+    pub tolerance: f64,
+    /// Estimate the paper color of each page from its pixels instead of using paper_color.
+    /// paper_color is still used for pages where no estimate can be made.
+    pub adaptive: bool,
+}
+
+impl Default for BackgroundDetection {
+    fn default() -> Self {
+        BackgroundDetection {
+            metric: Metric::QueenWise,
+            paper_color: WHITE,
+            tolerance: 90.0,
+            adaptive: false,
+        }
+    }
+}
+
+/// Paper is rarely darker than this, so darker peaks are more likely a dark photo or a
+/// page-sized fill
+const MIN_PAPER_LUMINANCE: usize = 96;
+/// How far from the peak luminance a pixel can be and still count towards the paper color
+const PAPER_LUMINANCE_SPREAD: usize = 4;
+/// We don't need every pixel to find the peak. Prime so we don't alias with the row stride.
+const SAMPLE_EVERY_NTH_PIXEL: usize = 7;
+
+/// Estimate the color of the paper of a rendered page (b, g, r, a pixels) as the average color of
+/// the most common bright luminance. None if the page has no bright pixels.
+pub(crate) fn estimate_paper_color(img_data: &[u8]) -> Option<Color> {
+    let samples = || {
+        img_data
+            .chunks_exact(RGBA_PIXEL_SIZE)
+            .step_by(SAMPLE_EVERY_NTH_PIXEL)
+    };
+
+    let mut histogram = [0usize; 256];
+    for pixel in samples() {
+        histogram[luminance(pixel)] += 1;
+    }
+
+    let (peak, count) = histogram
+        .iter()
+        .enumerate()
+        .skip(MIN_PAPER_LUMINANCE)
+        // max_by_key returns the last maximum, so ties go to the brighter luminance
+        .max_by_key(|(_, count)| **count)?;
+    if *count == 0 {
+        return None;
+    }
+
+    let mut sums = [0usize; 3];
+    let mut included = 0;
+    for pixel in samples() {
+        let distance = (luminance(pixel) as isize - peak as isize).abs() as usize;
+        if distance <= PAPER_LUMINANCE_SPREAD {
+            sums[0] += pixel[0] as usize;
+            sums[1] += pixel[1] as usize;
+            sums[2] += pixel[2] as usize;
+            included += 1;
+        }
+    }
+
+    let average = |sum: usize| (sum / included) as u8;
+    Some(Color::new(
+        average(sums[2]),
+        average(sums[1]),
+        average(sums[0]),
+    ))
+}
+
+/// Rec. 709 luma of a (b, g, r) pixel, in integer math because it runs on every sampled pixel
+pub(crate) fn luminance(pixel: &[u8]) -> usize {
+    (pixel[0] as usize * 18 + pixel[1] as usize * 183 + pixel[2] as usize * 54) >> 8
+}
+
+pub(crate) trait Classifier {
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    fn is_background(&self, pixel: &[u8]) -> bool;
+
+    /// How far the pixel is from the paper color, in the metric's units.
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    fn distance(&self, pixel: &[u8]) -> f64;
+
+    /// The distance at which a pixel is all ink, with no paper showing through
+    fn full_coverage_distance(&self) -> f64;
+
+    /// How much of the pixel is covered by ink, from 0 (bare paper) to 1 (all ink)
+    fn coverage(&self, pixel: &[u8]) -> f64 {
+        (self.distance(pixel) / self.full_coverage_distance()).min(1.0)
+    }
+}
+
+/// Queen-wise distance algorithm
+/// Mahama 2016 <https://doi.org/10.2352/ISSN.2470-1173.2016.20.COLOR-349>
+pub(crate) struct QueenWise {
+    paper: LittleEndianRgbPixel<i16>,
+    tolerance: i16,
+}
+
+impl QueenWise {
+    pub(crate) fn new(paper_color: Color, tolerance: f64) -> Self {
+        let paper: LittleEndianRgbPixel<u8> = paper_color.into();
+        QueenWise {
+            paper: [paper[0] as i16, paper[1] as i16, paper[2] as i16],
+            tolerance: tolerance.ceil().max(0.0).min(256.0) as i16,
+        }
+    }
+}
+
+impl QueenWise {
+    fn dissimilarity(&self, pixel: &[u8]) -> i16 {
+        pixel
+            .iter()
+            .take(RGBA_PIXEL_SIZE - 1)
+            .zip(self.paper.iter())
+            .map(|(candidate, paper)| ((*candidate as i16) - paper).abs())
+            .max()
+            // Won't panic: max() -> None if iterator is empty and pixel always holds 3 items
+            .unwrap()
+    }
+}
+
+impl Classifier for QueenWise {
+    fn is_background(&self, pixel: &[u8]) -> bool {
+        self.dissimilarity(pixel) < self.tolerance
+    }
+
+    fn distance(&self, pixel: &[u8]) -> f64 {
+        self.dissimilarity(pixel) as f64
+    }
+
+    fn full_coverage_distance(&self) -> f64 {
+        255.0
+    }
+}
+
+/// How many answers `DeltaE2000` remembers, about 1.5 MB of them. Text and drawings have far
+/// fewer colors, photos can have millions.
+const MAX_SEEN: usize = 1 << 16;
+
+pub(crate) struct DeltaE2000 {
+    paper: Lab,
+    tolerance: f64,
+    /// ΔE2000 is expensive and most pages only have so many distinct colors, so we remember
+    /// answers for the first `MAX_SEEN` colors we see
+    seen: RefCell<HashMap<LittleEndianRgbPixel<u8>, f64>>,
+}
+
+impl DeltaE2000 {
+    pub(crate) fn new(paper_color: Color, tolerance: f64) -> Self {
+        DeltaE2000 {
+            paper: Lab::from(paper_color),
+            tolerance,
+            seen: RefCell::new(HashMap::new()),
+        }
+    }
+}
+
+impl Classifier for DeltaE2000 {
+    fn is_background(&self, pixel: &[u8]) -> bool {
+        self.distance(pixel) < self.tolerance
+    }
+
+    fn distance(&self, pixel: &[u8]) -> f64 {
+        let key = [pixel[0], pixel[1], pixel[2]];
+        if let Some(distance) = self.seen.borrow().get(&key) {
+            return *distance;
+        }
+
+        let lab = Lab::from(Color::new(pixel[2], pixel[1], pixel[0]));
+        let distance = delta_e_2000(lab, self.paper);
+        let mut seen = self.seen.borrow_mut();
+        if seen.len() < MAX_SEEN {
+            seen.insert(key, distance);
+        }
+        distance
+    }
+
+    /// Black on white paper is about 100
+    fn full_coverage_distance(&self) -> f64 {
+        100.0
+    }
+}
+
+/// A color in CIELAB, relative to a D65 white point
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Lab {
+    pub l: f64,
+    pub a: f64,
+    pub b: f64,
+}
+
+impl From<Color> for Lab {
+    fn from(color: Color) -> Self {
+        // sRGB -> linear RGB -> XYZ -> Lab
+        // See <http://www.brucelindbloom.com/index.html?Math.html>
+        fn linearize(channel: u8) -> f64 {
+            let channel = channel as f64 / 255.0;
+            if channel <= 0.04045 {
+                channel / 12.92
+            } else {
+                ((channel + 0.055) / 1.055).powf(2.4)
+            }
+        }
+        let (r, g, b) = (linearize(color.r), linearize(color.g), linearize(color.b));
+
+        let x = (0.4124564 * r + 0.3575761 * g + 0.1804375 * b) / 0.95047;
+        let y = 0.2126729 * r + 0.7151522 * g + 0.0721750 * b;
+        let z = (0.0193339 * r + 0.1191920 * g + 0.9503041 * b) / 1.08883;
+
+        fn f(t: f64) -> f64 {
+            const EPSILON: f64 = 216.0 / 24389.0;
+            const KAPPA: f64 = 24389.0 / 27.0;
+            if t > EPSILON {
+                t.cbrt()
+            } else {
+                (KAPPA * t + 16.0) / 116.0
+            }
+        }
+        let (fx, fy, fz) = (f(x), f(y), f(z));
+
+        Lab {
+            l: 116.0 * fy - 16.0,
+            a: 500.0 * (fx - fy),
+            b: 200.0 * (fy - fz),
+        }
+    }
+}
+
+impl From<Lab> for Color {
+    /// Colors outside of sRGB are clipped
+    fn from(lab: Lab) -> Self {
+        // The inverse of From<Color> for Lab
+        const EPSILON: f64 = 216.0 / 24389.0;
+        const KAPPA: f64 = 24389.0 / 27.0;
+        fn f_inverse(t: f64) -> f64 {
+            let cubed = t.powi(3);
+            if cubed > EPSILON {
+                cubed
+            } else {
+                (116.0 * t - 16.0) / KAPPA
+            }
+        }
+
+        let fy = (lab.l + 16.0) / 116.0;
+        let fx = fy + lab.a / 500.0;
+        let fz = fy - lab.b / 200.0;
+
+        let x = f_inverse(fx) * 0.95047;
+        let y = if lab.l > KAPPA * EPSILON {
+            fy.powi(3)
+        } else {
+            lab.l / KAPPA
+        };
+        let z = f_inverse(fz) * 1.08883;
+
+        let r = 3.2404542 * x - 1.5371385 * y - 0.4985314 * z;
+        let g = -0.9692660 * x + 1.8760108 * y + 0.0415560 * z;
+        let b = 0.0556434 * x - 0.2040259 * y + 1.0572252 * z;
+
+        fn compand(channel: f64) -> u8 {
+            let channel = if channel <= 0.0031308 {
+                12.92 * channel
+            } else {
+                1.055 * channel.powf(1.0 / 2.4) - 0.055
+            };
+            (channel * 255.0).round().max(0.0).min(255.0) as u8
+        }
+
+        Color::new(compand(r), compand(g), compand(b))
+    }
+}
+
+/// CIEDE2000 color difference
+/// Sharma et al. 2005 <https://doi.org/10.1002/col.20070>
+pub(crate) fn delta_e_2000(first: Lab, second: Lab) -> f64 {
+    use std::f64::consts::PI;
+
+    let to_degrees = |radians: f64| radians * 180.0 / PI;
+    let to_radians = |degrees: f64| degrees * PI / 180.0;
+
+    let c1 = first.a.hypot(first.b);
+    let c2 = second.a.hypot(second.b);
+    let c_mean = (c1 + c2) / 2.0;
+    let g = 0.5 * (1.0 - (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt());
+
+    let a1 = (1.0 + g) * first.a;
+    let a2 = (1.0 + g) * second.a;
+    let c1 = a1.hypot(first.b);
+    let c2 = a2.hypot(second.b);
+
+    let hue = |b: f64, a: f64| {
+        if b == 0.0 && a == 0.0 {
+            0.0
+        } else {
+            let hue = to_degrees(b.atan2(a));
+            if hue < 0.0 {
+                hue + 360.0
+            } else {
+                hue
+            }
+        }
+    };
+    let h1 = hue(first.b, a1);
+    let h2 = hue(second.b, a2);
+
+    let delta_l = second.l - first.l;
+    let delta_c = c2 - c1;
+    let delta_h = if c1 * c2 == 0.0 {
+        0.0
+    } else if (h2 - h1).abs() <= 180.0 {
+        h2 - h1
+    } else if h2 - h1 > 180.0 {
+        h2 - h1 - 360.0
+    } else {
+        h2 - h1 + 360.0
+    };
+    let delta_h = 2.0 * (c1 * c2).sqrt() * to_radians(delta_h / 2.0).sin();
+
+    let l_mean = (first.l + second.l) / 2.0;
+    let c_mean = (c1 + c2) / 2.0;
+    let h_mean = if c1 * c2 == 0.0 {
+        h1 + h2
+    } else if (h1 - h2).abs() <= 180.0 {
+        (h1 + h2) / 2.0
+    } else if h1 + h2 < 360.0 {
+        (h1 + h2 + 360.0) / 2.0
+    } else {
+        (h1 + h2 - 360.0) / 2.0
+    };
+
+    let t = 1.0 - 0.17 * to_radians(h_mean - 30.0).cos()
+        + 0.24 * to_radians(2.0 * h_mean).cos()
+        + 0.32 * to_radians(3.0 * h_mean + 6.0).cos()
+        - 0.20 * to_radians(4.0 * h_mean - 63.0).cos();
+    let delta_theta = 30.0 * (-((h_mean - 275.0) / 25.0).powi(2)).exp();
+    let r_c = 2.0 * (c_mean.powi(7) / (c_mean.powi(7) + 25f64.powi(7))).sqrt();
+    let s_l = 1.0 + (0.015 * (l_mean - 50.0).powi(2)) / (20.0 + (l_mean - 50.0).powi(2)).sqrt();
+    let s_c = 1.0 + 0.045 * c_mean;
+    let s_h = 1.0 + 0.015 * c_mean * t;
+    let r_t = -to_radians(2.0 * delta_theta).sin() * r_c;
+
+    let l_term = delta_l / s_l;
+    let c_term = delta_c / s_c;
+    let h_term = delta_h / s_h;
+
+    (l_term.powi(2) + c_term.powi(2) + h_term.powi(2) + r_t * c_term * h_term).sqrt()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn lab(l: f64, a: f64, b: f64) -> Lab {
+        Lab { l, a, b }
+    }
+
+    #[test]
+    fn matches_sharma_test_data() {
+        // Pairs 1, 7 and 17 of the test data published with the paper
+        let cases = [
+            (
+                lab(50.0, 2.6772, -79.7751),
+                lab(50.0, 0.0, -82.7485),
+                2.0425,
+            ),
+            (lab(50.0, 0.0, 0.0), lab(50.0, -1.0, 2.0), 2.3669),
+            (lab(50.0, 2.5, 0.0), lab(73.0, 25.0, -18.0), 27.1492),
+        ];
+
+        for (first, second, expected) in cases.iter() {
+            let actual = delta_e_2000(*first, *second);
+            assert!(
+                (actual - expected).abs() < 0.0001,
+                "expected {} got {}",
+                expected,
+                actual
+            );
+        }
+    }
+
+    #[test]
+    fn white_is_lab_white() {
+        let white = Lab::from(WHITE);
+        assert!((white.l - 100.0).abs() < 0.01);
+        assert!(white.a.abs() < 0.01);
+        assert!(white.b.abs() < 0.01);
+    }
+
+    #[test]
+    fn lab_round_trips() {
+        for color in [
+            WHITE,
+            Color::new(0, 0, 0),
+            Color::new(226, 97, 255),
+            Color::new(20, 130, 60),
+        ]
+        .iter()
+        {
+            assert_eq!(Color::from(Lab::from(*color)), *color);
+        }
+    }
+
+    #[test]
+    fn delta_e_tells_cream_from_gray() {
+        let cream = Color::new(250, 240, 215);
+        let classifier = DeltaE2000::new(cream, 5.0);
+
+        // b, g, r
+        assert!(classifier.is_background(&[212, 238, 248, 255]));
+        assert!(!classifier.is_background(&[200, 200, 200, 255]));
+        assert!(!classifier.is_background(&[0, 0, 0, 255]));
+    }
+
+    #[test]
+    fn remembers_a_bounded_number_of_colors() {
+        let classifier = DeltaE2000::new(Color::new(250, 240, 215), 5.0);
+        let pixels = (0..MAX_SEEN as u32 + 10).map(|i| [i as u8, (i >> 8) as u8, (i >> 16) as u8]);
+        let distances: Vec<f64> = pixels.clone().map(|p| classifier.distance(&p)).collect();
+
+        assert_eq!(classifier.seen.borrow().len(), MAX_SEEN);
+        // The same answers, whether remembered or not
+        for (pixel, distance) in pixels.zip(distances) {
+            assert_eq!(classifier.distance(&pixel), distance);
+        }
+    }
+
+    #[test]
+    fn estimates_paper_color() {
+        // Mostly cream paper (b, g, r, a) with some black text and a gray box
+        let mut page = Vec::new();
+        for i in 0..10_000 {
+            let pixel = match i % 10 {
+                0 => [0, 0, 0, 255],
+                1 => [128, 128, 128, 255],
+                _ => [215, 240, 250, 255],
+            };
+            page.extend_from_slice(&pixel);
+        }
+
+        assert_eq!(estimate_paper_color(&page), Some(Color::new(250, 240, 215)));
+    }
+
+    #[test]
+    fn dark_page_has_no_paper_estimate() {
+        let page = [10, 10, 10, 255].repeat(1000);
+        assert_eq!(estimate_paper_color(&page), None);
+    }
+
+    #[test]
+    fn queen_wise_matches_previous_behaviour() {
+        let classifier = QueenWise::new(WHITE, 90.0);
+
+        assert!(classifier.is_background(&[255, 255, 255, 255]));
+        assert!(classifier.is_background(&[170, 170, 170, 255]));
+        assert!(!classifier.is_background(&[165, 255, 255, 255]));
+    }
+
+    #[test]
+    fn coverage_follows_distance_from_paper() {
+        let classifier = QueenWise::new(WHITE, 90.0);
+
+        assert_eq!(classifier.coverage(&[255, 255, 255, 255]), 0.0);
+        assert_eq!(classifier.coverage(&[0, 0, 0, 255]), 1.0);
+        let half = classifier.coverage(&[128, 128, 128, 255]);
+        assert!((half - 0.5).abs() < 0.01, "got {}", half);
+    }
+}
@@ -1,7 +1,13 @@
 use anyhow::anyhow;
 use purpleifypdf::{
-    pdf_to_pdf::{transform, Update},
-    Color, Quality,
+    background::BackgroundDetection,
+    encoding::Encoding,
+    encryption::OutputEncryption,
+    ink::InkDetection,
+    pdf_to_pdf::{transform_with_options, Update},
+    protect::Protection,
+    Color, Metadata, Mode, PageOverride, PageSelection, PageSettings, Quality, Rendering, Strategy,
+    TransformationError, TransformationStateOptions,
 };
 use serde::{Deserialize, Serialize};
 use serde_json;
@@ -15,6 +21,11 @@ fn main() {
             b"ERRR",
             &ErrorMessage {
                 message: format!("{:?}", err),
+                password: match err.downcast_ref() {
+                    Some(TransformationError::PasswordRequired) => Some(PasswordError::Required),
+                    Some(TransformationError::IncorrectPassword) => Some(PasswordError::Incorrect),
+                    _ => None,
+                },
             },
         )
         .ok();
@@ -38,11 +49,25 @@ fn handle_pdf() -> Result<(), anyhow::Error> {
 
                         let in_blob = fs::read(&options.in_file)?;
 
-                        let mut state = transform(
+                        let mut state = transform_with_options(
                             in_blob,
-                            None,
-                            options.quality,
-                            Some(options.background_color),
+                            TransformationStateOptions {
+                                quality: options.quality,
+                                background_color: options.background_color,
+                                pages: options.pages,
+                                mode: options.mode,
+                                background_detection: options.background_detection,
+                                strategy: options.strategy,
+                                foreground_color: options.foreground_color,
+                                ink_detection: options.ink_detection,
+                                protection: options.protection,
+                                workers: options.workers,
+                                target_size: options.target_size,
+                                encoding: options.encoding,
+                                overrides: options.overrides,
+                                password: options.password,
+                                encryption: options.encryption,
+                            },
                         )?;
 
                         loop {
@@ -59,10 +84,25 @@ fn handle_pdf() -> Result<(), anyhow::Error> {
                                 Update::Complete(result) => {
                                     let complete = result?;
                                     let original_title = complete.original_title().to_string();
+                                    let renderings = complete.renderings().to_vec();
+                                    let paper_colors = complete.paper_colors().to_vec();
+                                    let settings = complete.settings().to_vec();
+                                    let metadata = complete.metadata().clone();
+                                    let copied_structure = complete.copied_structure();
 
                                     fs::write(&options.out_file, complete.into_bytes())?;
 
-                                    send(b"DONE", &Complete { original_title })?;
+                                    send(
+                                        b"DONE",
+                                        &Complete {
+                                            original_title,
+                                            renderings,
+                                            paper_colors,
+                                            settings,
+                                            metadata,
+                                            copied_structure,
+                                        },
+                                    )?;
                                     break;
                                 }
                             }
@@ -89,8 +129,42 @@ fn handle_pdf() -> Result<(), anyhow::Error> {
 
 #[derive(Debug, Serialize, Deserialize)]
 struct Options {
+    /// e.g. "High", {"Custom": 300} or {"MaxPixels": 4000000}
     quality: Quality,
     background_color: Color,
+    /// e.g. "1-3,7,10-end". All pages if missing.
+    #[serde(default)]
+    pages: Option<PageSelection>,
+    #[serde(default)]
+    mode: Mode,
+    #[serde(default)]
+    background_detection: BackgroundDetection,
+    #[serde(default)]
+    strategy: Strategy,
+    #[serde(default)]
+    foreground_color: Option<Color>,
+    #[serde(default)]
+    ink_detection: InkDetection,
+    #[serde(default)]
+    protection: Protection,
+    /// 0 or 1 transforms one page at a time
+    #[serde(default)]
+    workers: usize,
+    /// In bytes
+    #[serde(default)]
+    target_size: Option<u64>,
+    #[serde(default)]
+    encoding: Encoding,
+    /// e.g. [{"pages": "1", "pass_through": true}, {"pages": "odd", "quality": "Low"}]
+    #[serde(default)]
+    overrides: Vec<PageOverride>,
+    /// For encrypted documents
+    #[serde(default)]
+    password: Option<String>,
+    /// e.g. "Unencrypted" or {"Encrypted": {"user_password": "", "permissions": {"print": true,
+    /// "copy": false, "modify": false}}}. Matches the source if missing.
+    #[serde(default)]
+    encryption: OutputEncryption,
     in_file: String,
     out_file: String,
 }
@@ -103,11 +177,25 @@ struct Status {
 #[derive(Debug, Serialize)]
 struct Complete {
     original_title: String,
+    renderings: Vec<Rendering>,
+    paper_colors: Vec<Option<Color>>,
+    settings: Vec<PageSettings>,
+    metadata: Metadata,
+    /// False if the links, outline and metadata of the source were lost
+    copied_structure: bool,
 }
 
 #[derive(Debug, Serialize)]
 struct ErrorMessage {
     message: String,
+    /// Set if the user should be asked for a password and we should try again
+    password: Option<PasswordError>,
+}
+
+#[derive(Debug, Serialize)]
+enum PasswordError {
+    Required,
+    Incorrect,
 }
 
 fn parse_category(body: &[u8]) -> (&[u8], &[u8]) {
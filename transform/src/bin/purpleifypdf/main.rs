@@ -0,0 +1,416 @@
+//! Transform PDFs from the command line.
+//!
+//! Exits with 0 if every input was transformed, 2 for bad arguments and 1 for failures outside
+//! the transformation itself, like missing inputs. Failed transformations exit with the code of
+//! their `TransformationError` (see `exit_code`), which includes failing to write their output.
+//! If several inputs fail, the first one's code is used.
+//!
+//! With --watch, runs until it's killed, transforming PDFs as they're added to a directory. See
+//! `watch`.
+
+use anyhow::{anyhow, Context};
+use indicatif::{ProgressBar, ProgressStyle};
+use purpleifypdf::{
+    pdf_to_pdf::{transform_to_writer, Update},
+    Color, PageSelection, Quality, TransformationError, TransformationState,
+    TransformationStateOptions,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+mod watch;
+
+/// Added to the name of each input for its output
+const OUTPUT_SUFFIX: &str = "-purple";
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Recolor the background of PDFs")]
+struct Args {
+    /// PDFs, or directories of PDFs, to transform
+    #[structopt(parse(from_os_str), required = true)]
+    inputs: Vec<PathBuf>,
+    /// A file if there's a single input PDF, otherwise a directory. Outputs go next to their
+    /// inputs if missing.
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// extreme, high, normal, low, extremelow or pixels per inch
+    #[structopt(short, long, default_value = "normal", parse(try_from_str = parse_quality))]
+    quality: Quality,
+    /// The background color, e.g. e261ff
+    #[structopt(short, long, parse(try_from_str = parse_color))]
+    color: Option<Color>,
+    /// e.g. 1-3,7,10-end. All pages if missing.
+    #[structopt(short, long)]
+    pages: Option<PageSelection>,
+    /// pdf, or png for an image of each page
+    #[structopt(short, long, default_value = "pdf")]
+    format: Format,
+    /// For encrypted PDFs
+    #[structopt(long)]
+    password: Option<String>,
+    /// How many pages to transform at once
+    #[structopt(short, long, default_value = "1")]
+    workers: usize,
+    /// Keep transforming PDFs as they're added to the input directory. Outputs go in a purple
+    /// directory inside it if --output is missing.
+    #[structopt(long)]
+    watch: bool,
+    /// Where PDFs that fail to transform are moved when watching, each with a report of what
+    /// went wrong. A failed directory inside the input directory if missing.
+    #[structopt(long, parse(from_os_str))]
+    quarantine: Option<PathBuf>,
+    /// Seconds between checks for new PDFs when watching
+    #[structopt(long, default_value = "5")]
+    interval: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Pdf,
+    Png,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src.to_lowercase().as_str() {
+            "pdf" => Ok(Format::Pdf),
+            "png" => Ok(Format::Png),
+            _ => Err(format!("Unknown format {:?}, expected pdf or png", src)),
+        }
+    }
+}
+
+fn parse_quality(src: &str) -> Result<Quality, String> {
+    let src = src.to_lowercase();
+    Quality::from_str(&src)
+        .or_else(|| match src.parse() {
+            Ok(ppi) if ppi > 0.0 => Some(Quality::Custom(ppi)),
+            _ => None,
+        })
+        .ok_or_else(|| format!("Unknown quality {:?}", src))
+}
+
+fn parse_color(src: &str) -> Result<Color, String> {
+    let bytes = hex::decode(src.trim_start_matches('#')).map_err(|err| err.to_string())?;
+    match bytes.as_slice() {
+        [r, g, b] => Ok(Color::new(*r, *g, *b)),
+        _ => Err(format!("Expected a color like e261ff, got {:?}", src)),
+    }
+}
+
+/// Distinct for each kind of error, so scripts can react
+fn exit_code(err: &TransformationError) -> i32 {
+    match err {
+        TransformationError::Receiving(_) => 10,
+        TransformationError::Render(_) => 11,
+        TransformationError::Unknown => 12,
+        TransformationError::NonexistentPage(_) => 13,
+        TransformationError::PixelRead(_) => 14,
+        TransformationError::InsufficientMemory => 15,
+        TransformationError::ZeroPagePdf => 16,
+        TransformationError::PasswordRequired => 17,
+        TransformationError::IncorrectPassword => 18,
+        TransformationError::ImageEncoding(_) => 19,
+        TransformationError::Structure(_) => 20,
+        TransformationError::Output(_) => 21,
+        TransformationError::InvalidQuality(_) => 22,
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let args = Args::from_args_safe().unwrap_or_else(|err| {
+        // Help and the version aren't errors
+        if !err.use_stderr() {
+            err.exit();
+        }
+        eprintln!("{}", err.message);
+        process::exit(2);
+    });
+
+    let result = if args.watch {
+        watch::run(&args).map(|()| 0)
+    } else {
+        run(&args)
+    };
+    let code = match result {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("{:#}", err);
+            1
+        }
+    };
+    process::exit(code);
+}
+
+/// Transform every input, carrying on past failures. Returns the exit code.
+fn run(args: &Args) -> Result<i32, anyhow::Error> {
+    let inputs = find_pdfs(&args.inputs)?;
+    if inputs.is_empty() {
+        return Err(anyhow!("No PDFs found"));
+    }
+    let single_file = inputs.len() == 1 && args.inputs[0].is_file();
+    let outputs = inputs
+        .iter()
+        .map(|input| output_path(args.format, args.output.as_deref(), input, single_file))
+        .collect::<Vec<_>>();
+    // Inputs with the same name from different directories
+    let mut written_by = HashMap::new();
+    for (input, output) in inputs.iter().zip(&outputs) {
+        if let Some(other) = written_by.insert(output, input) {
+            return Err(anyhow!(
+                "{} and {} would both be written to {}",
+                other.display(),
+                input.display(),
+                output.display()
+            ));
+        }
+    }
+
+    if let Some(output) = &args.output {
+        let is_dir = !single_file || args.format == Format::Png;
+        if is_dir {
+            fs::create_dir_all(output)
+                .with_context(|| format!("Creating output directory {}", output.display()))?;
+        }
+    }
+
+    let mut code = 0;
+    for (input, output) in inputs.iter().zip(&outputs) {
+        if let Err(err) = transform_file(args, input, output) {
+            eprintln!("Failed to transform {}: {:#}", input.display(), err);
+            if code == 0 {
+                code = err
+                    .downcast_ref::<TransformationError>()
+                    .map_or(1, exit_code);
+            }
+        }
+    }
+    Ok(code)
+}
+
+/// The PDFs named by inputs, looking one level into directories. Outputs of earlier runs found
+/// in directories are skipped.
+fn find_pdfs(inputs: &[PathBuf]) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let mut pdfs = Vec::new();
+    for input in inputs {
+        if !input.is_dir() {
+            if !input.exists() {
+                return Err(anyhow!("{} doesn't exist", input.display()));
+            }
+            pdfs.push(input.clone());
+            continue;
+        }
+
+        let mut found = fs::read_dir(input)
+            .with_context(|| format!("Reading {}", input.display()))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file() && is_pdf(path) && !is_output(path))
+            .collect::<Vec<_>>();
+        found.sort();
+        pdfs.extend(found);
+    }
+    Ok(pdfs)
+}
+
+fn is_pdf(path: &Path) -> bool {
+    path.extension()
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("pdf"))
+}
+
+fn is_output(path: &Path) -> bool {
+    path.file_stem().map_or(false, |stem| {
+        stem.to_string_lossy().ends_with(OUTPUT_SUFFIX)
+    })
+}
+
+/// A file for PDFs, a directory for images. output is the --output argument.
+fn output_path(format: Format, output: Option<&Path>, input: &Path, single_file: bool) -> PathBuf {
+    let stem = input.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match format {
+        Format::Pdf => format!("{}{}.pdf", stem, OUTPUT_SUFFIX),
+        Format::Png => format!("{}{}", stem, OUTPUT_SUFFIX),
+    };
+    match output {
+        Some(output) if single_file => output.to_path_buf(),
+        Some(output) => output.join(name),
+        None => input.with_file_name(name),
+    }
+}
+
+fn transform_file(args: &Args, input: &Path, output: &Path) -> Result<(), anyhow::Error> {
+    let in_blob = fs::read(input).with_context(|| format!("Reading {}", input.display()))?;
+    let mut options = TransformationStateOptions {
+        quality: args.quality,
+        pages: args.pages.clone(),
+        password: args.password.clone(),
+        workers: args.workers,
+        ..TransformationStateOptions::default()
+    };
+    if let Some(color) = args.color {
+        options.background_color = color;
+    }
+
+    let bar = ProgressBar::new(100);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{msg} [{bar:40}] {percent}%")
+            .progress_chars("=> "),
+    );
+    bar.set_message(&input.file_name().unwrap_or_default().to_string_lossy());
+
+    let result = match args.format {
+        Format::Pdf => write_pdf(in_blob, options, output, &bar),
+        Format::Png => write_pngs(in_blob, options, output, &bar),
+    };
+    bar.finish_and_clear();
+    if result.is_err() && args.format == Format::Pdf {
+        // Don't leave half a PDF behind
+        fs::remove_file(output).ok();
+    }
+    result
+}
+
+fn write_pdf(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+    output: &Path,
+    bar: &ProgressBar,
+) -> Result<(), anyhow::Error> {
+    let file = File::create(output)
+        .map_err(TransformationError::Output)
+        .with_context(|| format!("Creating {}", output.display()))?;
+    let mut progress = transform_to_writer(in_blob, options, Box::new(BufWriter::new(file)))?;
+    loop {
+        match progress.next() {
+            Update::Progress(next) => {
+                bar.set_position((next.percent_done() * 100.0) as u64);
+                progress = next;
+            }
+            Update::Complete(result) => {
+                result?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Writes each page to output/<page number>.png
+fn write_pngs(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+    output: &Path,
+    bar: &ProgressBar,
+) -> Result<(), anyhow::Error> {
+    fs::create_dir_all(output)
+        .map_err(TransformationError::Output)
+        .with_context(|| format!("Creating {}", output.display()))?;
+    let state = TransformationState::try_new_with_options(in_blob, options)?;
+    let page_count = state.included_page_count();
+
+    for offset in 0..page_count {
+        let page = state.transform_page(offset)?;
+        let path = output.join(format!("{}.png", page.page_num() + 1));
+        fs::write(&path, page.to_png()?)
+            .map_err(TransformationError::Output)
+            .with_context(|| format!("Writing {}", path.display()))?;
+        bar.set_position(((offset + 1) * 100 / page_count) as u64);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_qualities() {
+        assert!(matches!(parse_quality("High"), Ok(Quality::High)));
+        assert!(matches!(
+            parse_quality("extremelow"),
+            Ok(Quality::ExtremeLow)
+        ));
+        assert!(matches!(parse_quality("150"), Ok(Quality::Custom(ppi)) if ppi == 150.0));
+        for src in &["0", "-72", "best"] {
+            assert!(parse_quality(src).is_err(), "{:?} parsed", src);
+        }
+    }
+
+    #[test]
+    fn names_outputs_after_inputs() {
+        let input = Path::new("in/paper.pdf");
+        let output_path = |format, output: Option<&str>, single_file| {
+            output_path(format, output.map(Path::new), input, single_file)
+        };
+
+        assert_eq!(
+            output_path(Format::Pdf, None, true),
+            Path::new("in/paper-purple.pdf")
+        );
+        assert_eq!(
+            output_path(Format::Png, None, true),
+            Path::new("in/paper-purple")
+        );
+        assert_eq!(
+            output_path(Format::Pdf, Some("out.pdf"), true),
+            Path::new("out.pdf")
+        );
+        assert_eq!(
+            output_path(Format::Pdf, Some("out"), false),
+            Path::new("out/paper-purple.pdf")
+        );
+    }
+
+    #[test]
+    fn exits_the_same_way_for_pdfs_and_images_it_cant_write() {
+        // Nothing can be created inside a file
+        let file = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&file, b"").unwrap();
+        let output = file.join("out");
+        let bar = ProgressBar::hidden();
+        let options = TransformationStateOptions::default;
+
+        let pdf = write_pdf(Vec::new(), options(), &output, &bar).unwrap_err();
+        let pngs = write_pngs(Vec::new(), options(), &output, &bar).unwrap_err();
+        fs::remove_file(&file).unwrap();
+
+        for err in &[pdf, pngs] {
+            let err = err.downcast_ref::<TransformationError>().unwrap();
+            assert_eq!(exit_code(err), 21);
+        }
+    }
+
+    #[test]
+    fn finds_pdfs_in_directories() {
+        let dir = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::create_dir_all(dir.join("nested")).unwrap();
+        for name in &[
+            "b.pdf",
+            "a.PDF",
+            "a-purple.pdf",
+            "notes.txt",
+            "nested/c.pdf",
+        ] {
+            fs::write(dir.join(name), b"").unwrap();
+        }
+        let single = dir.join("notes.txt");
+
+        let found = find_pdfs(&[dir.clone(), single.clone()]);
+        let missing = find_pdfs(&[dir.join("missing.pdf")]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Sorted, and files named directly are taken whatever they're called
+        assert_eq!(
+            found.unwrap(),
+            vec![dir.join("a.PDF"), dir.join("b.pdf"), single]
+        );
+        assert!(missing.is_err());
+    }
+}
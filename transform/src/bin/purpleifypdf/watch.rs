@@ -0,0 +1,158 @@
+//! Transforming PDFs as they're added to a directory, for scanners that drop files in a shared
+//! folder.
+//!
+//! Finished PDFs are left where they are and recorded in a ledger in the output directory, so a
+//! restart doesn't transform them again. PDFs that fail are moved to the quarantine directory
+//! next to a report of the error.
+
+use crate::{find_pdfs, output_path, transform_file, Args};
+use anyhow::{anyhow, Context};
+use purpleifypdf::list_error_sources;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Kept in the output directory
+const LEDGER_NAME: &str = ".purpleifypdf-done";
+
+/// Only returns if the directories or the ledger can't be used
+pub(crate) fn run(args: &Args) -> Result<(), anyhow::Error> {
+    let input = match args.inputs.as_slice() {
+        [input] if input.is_dir() => input,
+        _ => return Err(anyhow!("--watch needs a single input directory")),
+    };
+    let output = args.output.clone().unwrap_or_else(|| input.join("purple"));
+    let quarantine = args
+        .quarantine
+        .clone()
+        .unwrap_or_else(|| input.join("failed"));
+    if output == *input {
+        // We'd pick up our own outputs
+        return Err(anyhow!("--output can't be the watched directory"));
+    }
+    for dir in &[&output, &quarantine] {
+        fs::create_dir_all(dir).with_context(|| format!("Creating {}", dir.display()))?;
+    }
+    let mut ledger = Ledger::open(&output.join(LEDGER_NAME))?;
+    println!("Watching {} for PDFs", input.display());
+
+    // The size of each PDF when we last looked
+    let mut sizes = HashMap::new();
+    loop {
+        let mut seen = HashMap::new();
+        // The directory could be briefly unavailable, like a network share reconnecting
+        let pdfs = find_pdfs(&[input.clone()]).unwrap_or_default();
+        for pdf in pdfs {
+            let metadata = match fs::metadata(&pdf) {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            let entry = ledger_entry(&pdf, &metadata);
+            if ledger.contains(&entry) {
+                continue;
+            }
+            // Scanners write a file over several seconds, so wait until it stops growing
+            let size = metadata.len();
+            let settled = sizes.get(&pdf) == Some(&size);
+            seen.insert(pdf.clone(), size);
+            if !settled {
+                continue;
+            }
+
+            let out = output_path(args.format, Some(&output), &pdf, false);
+            match transform_file(args, &pdf, &out) {
+                Ok(()) => {
+                    println!("Transformed {}", pdf.display());
+                    ledger.record(entry)?;
+                }
+                Err(err) => {
+                    eprintln!("Failed to transform {}: {:#}", pdf.display(), err);
+                    if let Err(err) = quarantine_pdf(&pdf, &quarantine, &err) {
+                        eprintln!("Failed to quarantine {}: {:#}", pdf.display(), err);
+                    }
+                }
+            }
+        }
+        sizes = seen;
+        thread::sleep(Duration::from_secs(args.interval));
+    }
+}
+
+/// Move a PDF that failed into quarantine, next to <name>.txt describing the error
+fn quarantine_pdf(
+    pdf: &Path,
+    quarantine: &Path,
+    error: &anyhow::Error,
+) -> Result<(), anyhow::Error> {
+    let name = pdf.file_name().unwrap_or_default();
+    let destination = quarantine.join(name);
+    // Renaming fails across file systems
+    if fs::rename(pdf, &destination).is_err() {
+        fs::copy(pdf, &destination)?;
+        fs::remove_file(pdf)?;
+    }
+
+    let mut report = format!("Failed to transform {}: {}\n", pdf.display(), error);
+    let sources = list_error_sources(error.as_ref());
+    if !sources.is_empty() {
+        report.push_str("\nCaused by:\n");
+        for source in sources {
+            report.push_str(&format!("    {}\n", source));
+        }
+    }
+    let report_path = quarantine.join(format!("{}.txt", name.to_string_lossy()));
+    fs::write(report_path, report)?;
+    Ok(())
+}
+
+/// Identifies a version of a file. A PDF that's replaced by one with the same name is
+/// transformed again.
+fn ledger_entry(pdf: &Path, metadata: &Metadata) -> String {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_secs());
+    format!(
+        "{}\t{}\t{}",
+        pdf.file_name().unwrap_or_default().to_string_lossy(),
+        metadata.len(),
+        modified
+    )
+}
+
+/// The PDFs we've finished, one entry per line
+struct Ledger {
+    file: File,
+    entries: HashSet<String>,
+}
+
+impl Ledger {
+    fn open(path: &Path) -> Result<Ledger, anyhow::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Opening {}", path.display()))?;
+        let entries = BufReader::new(&file)
+            .lines()
+            .collect::<Result<_, _>>()
+            .with_context(|| format!("Reading {}", path.display()))?;
+        Ok(Ledger { file, entries })
+    }
+
+    fn contains(&self, entry: &str) -> bool {
+        self.entries.contains(entry)
+    }
+
+    fn record(&mut self, entry: String) -> Result<(), anyhow::Error> {
+        writeln!(self.file, "{}", entry)?;
+        self.file.sync_data()?;
+        self.entries.insert(entry);
+        Ok(())
+    }
+}
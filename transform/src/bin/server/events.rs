@@ -0,0 +1,241 @@
+//! Progress of a background job as Server-Sent Events, for clients that show it live.
+//!
+//! A `progress` event is sent as each page is started, then either a `complete` event with where
+//! to fetch the PDF from or an `error` event with an `ErrorBody`. The PDF isn't sent inline: as
+//! base64 it'd be a third bigger than the file itself, in one event the client has to hold in
+//! memory until it ends.
+
+use crate::{job_result_uri, ApiError};
+use purpleifypdf::jobs::{JobId, JobStatus, Jobs};
+use rocket::http::Status;
+use serde::Serialize;
+use std::io::{self, Cursor, Read};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Rocket only sends part of a response once it has this many bytes, and hyper buffers writes
+/// smaller than this. Events are padded to a multiple of it so each is sent as soon as it's ready.
+/// That costs up to 8 KiB per page, which is still a fraction of the page itself, and there's no
+/// way to flush a response early in Rocket 0.4.
+pub(crate) const CHUNK_SIZE: usize = 8 * 1024;
+
+/// How often we check on the job
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ProgressEvent {
+    /// Zero indexed, the page being transformed. Pages that take less than `POLL_INTERVAL` may
+    /// be skipped.
+    page: usize,
+    percent_done: f64,
+    elapsed_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CompleteEvent {
+    id: String,
+    /// Where to GET the PDF, until the job expires
+    result: String,
+    elapsed_ms: u64,
+}
+
+/// The response body. It ends when the job does, and cancels the job if it's dropped before.
+pub(crate) struct Events<'r> {
+    jobs: &'r Jobs,
+    /// None once the job has finished
+    job: Option<JobId>,
+    started: Instant,
+    /// The page of the last progress event
+    page: Option<usize>,
+    /// The event being read
+    unread: Cursor<Vec<u8>>,
+}
+
+impl<'r> Events<'r> {
+    pub(crate) fn new(jobs: &'r Jobs, job: JobId) -> Events<'r> {
+        Events {
+            jobs,
+            job: Some(job),
+            started: Instant::now(),
+            page: None,
+            unread: Cursor::new(Vec::new()),
+        }
+    }
+
+    /// Waits for the job to move on to another page or finish
+    fn next_event(&mut self) -> Option<Vec<u8>> {
+        let id = self.job?;
+        loop {
+            let status = match self.jobs.status(id) {
+                Ok(status) => status,
+                Err(err) => {
+                    self.job = None;
+                    return Some(event("error", &ApiError::from(err).body));
+                }
+            };
+            let elapsed_ms = self.started.elapsed().as_millis() as u64;
+
+            match status {
+                JobStatus::Queued { .. } | JobStatus::Running { page: None, .. } => {}
+                JobStatus::Running {
+                    percent_done,
+                    page: Some(page),
+                } => {
+                    if self.page != Some(page) {
+                        self.page = Some(page);
+                        return Some(event(
+                            "progress",
+                            &ProgressEvent {
+                                page,
+                                percent_done,
+                                elapsed_ms,
+                            },
+                        ));
+                    }
+                }
+                JobStatus::Done => {
+                    self.job = None;
+                    return Some(event(
+                        "complete",
+                        &CompleteEvent {
+                            id: id.to_string(),
+                            result: job_result_uri(id),
+                            elapsed_ms,
+                        },
+                    ));
+                }
+                JobStatus::Failed(err) => {
+                    self.job = None;
+                    return Some(event("error", &ApiError::from(err.as_ref()).body));
+                }
+                JobStatus::Cancelled => {
+                    self.job = None;
+                    let err = ApiError::new(
+                        Status::Conflict,
+                        "Cancelled",
+                        "The job was cancelled".to_string(),
+                    );
+                    return Some(event("error", &err.body));
+                }
+            }
+
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+impl<'r> Read for Events<'r> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.unread.position() as usize >= self.unread.get_ref().len() {
+            match self.next_event() {
+                Some(event) => self.unread = Cursor::new(event),
+                None => return Ok(0),
+            }
+        }
+        self.unread.read(buf)
+    }
+}
+
+impl<'r> Drop for Events<'r> {
+    /// Nobody is listening for the result any more
+    fn drop(&mut self) {
+        if let Some(id) = self.job {
+            self.jobs.cancel(id).ok();
+        }
+    }
+}
+
+/// An event with data as JSON, after a comment padding it to a multiple of CHUNK_SIZE
+fn event(name: &str, data: &impl Serialize) -> Vec<u8> {
+    let data = serde_json::to_string(data).expect("Events are always valid JSON");
+    let event = format!("event: {}\ndata: {}\n\n", name, data);
+    // The comment is at least a colon and a newline
+    let padding = (CHUNK_SIZE - (event.len() + 2) % CHUNK_SIZE) % CHUNK_SIZE;
+    format!(":{}\n{}", " ".repeat(padding), event).into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use purpleifypdf::jobs::JobsOptions;
+    use purpleifypdf::{Quality, TransformationStateOptions};
+
+    /// The name and data of each event
+    fn read_events(mut events: Events) -> Vec<(String, serde_json::Value)> {
+        let mut body = String::new();
+        events.read_to_string(&mut body).unwrap();
+        assert_eq!(body.len() % CHUNK_SIZE, 0);
+
+        body.split("\n\n")
+            .filter(|event| !event.is_empty())
+            .map(|event| {
+                let field = |prefix: &str| {
+                    let line = event.lines().find(|line| line.starts_with(prefix)).unwrap();
+                    line[prefix.len()..].to_string()
+                };
+                let data = serde_json::from_str(&field("data: ")).unwrap();
+                (field("event: "), data)
+            })
+            .collect()
+    }
+
+    fn options() -> TransformationStateOptions {
+        TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            ..TransformationStateOptions::default()
+        }
+    }
+
+    #[test]
+    fn sends_progress_then_where_the_pdf_is() {
+        let jobs = Jobs::new(JobsOptions::default());
+        let in_blob = include_bytes!("../../../test_assets/multipage_test.pdf").to_vec();
+        let id = jobs.submit(in_blob, options()).unwrap();
+
+        let events = read_events(Events::new(&jobs, id));
+        let (last, pages) = events.split_last().unwrap();
+        let mut previous = None;
+        for (name, data) in pages {
+            assert_eq!(name, "progress");
+            let page = data["page"].as_u64();
+            assert!(page > previous);
+            previous = page;
+        }
+        assert_eq!(last.0, "complete");
+        assert_eq!(last.1["id"], id.to_string());
+        assert_eq!(last.1["result"], job_result_uri(id));
+        // The job is done, so dropping the events left the result alone
+        assert!(jobs.result(id).is_ok());
+    }
+
+    #[test]
+    fn ends_with_errors() {
+        let jobs = Jobs::new(JobsOptions::default());
+        let id = jobs.submit(b"not a pdf".to_vec(), options()).unwrap();
+
+        let events = read_events(Events::new(&jobs, id));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "error");
+        assert!(events[0].1["error"].is_string());
+    }
+
+    #[test]
+    fn cancels_the_job_when_nobody_listens() {
+        let jobs = Jobs::new(JobsOptions::default());
+        let in_blob = include_bytes!("../../../test_assets/multipage_test.pdf").to_vec();
+        let id = jobs.submit(in_blob, options()).unwrap();
+
+        drop(Events::new(&jobs, id));
+        // A running job stops after the page it's on
+        loop {
+            match jobs.status(id).unwrap() {
+                JobStatus::Queued { .. } | JobStatus::Running { .. } => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                status => break assert!(matches!(status, JobStatus::Cancelled)),
+            }
+        }
+    }
+}
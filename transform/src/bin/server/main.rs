@@ -0,0 +1,536 @@
+//! The HTTP server the Chrome extension sends PDFs to.
+//!
+//! PDFs are POSTed as the body, with a `meta` query parameter of JSON (see
+//! `TransformMetadata`). Errors are JSON too (see `ErrorBody`).
+
+#![feature(proc_macro_hygiene, decl_macro)]
+
+#[macro_use]
+extern crate rocket;
+
+mod events;
+
+use events::Events;
+use purpleifypdf::jobs::{JobError, JobId, JobStatus, Jobs, JobsOptions};
+use purpleifypdf::{
+    list_error_sources, pdf_to_images, pdf_to_pdf, Color, PageRange, PageSelection, Quality,
+    TransformationError, TransformationStateOptions,
+};
+use rocket::data::Data;
+use rocket::http::{ContentType, RawStr, Status};
+use rocket::request::{FromFormValue, Request};
+use rocket::response::{self, status, Content, Responder, Response, Stream};
+use rocket::State;
+use rocket_contrib::json::Json;
+use serde::{Deserialize, Serialize};
+use sloggers::terminal::{Destination, TerminalLoggerBuilder};
+use sloggers::types::Severity;
+use sloggers::Build;
+use std::fs::File;
+use std::io::Read;
+use std::time::Duration;
+
+/// Bigger PDFs are rejected
+const MAX_PDF_BYTES: u64 = 256 * 1024 * 1024;
+
+/// Where the routes are mounted
+const BASE: &str = "/purpleifypdf";
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransformMetadata {
+    /// Identifies an install of the extension in the logs
+    client_uid: String,
+    quality: Quality,
+    background_color: Color,
+    #[serde(default)]
+    page_range: Option<PageRange>,
+    /// e.g. "1-3,7,10-end". Takes precedence over page_range.
+    #[serde(default)]
+    pages: Option<PageSelection>,
+    /// For encrypted PDFs
+    #[serde(default)]
+    password: Option<String>,
+    /// Where the PDF came from, for the logs
+    #[serde(default)]
+    source: String,
+}
+
+impl TransformMetadata {
+    fn into_options(self) -> TransformationStateOptions {
+        let page_range = self.page_range;
+        TransformationStateOptions {
+            quality: self.quality,
+            background_color: self.background_color,
+            pages: self.pages.or_else(|| page_range.map(PageSelection::from)),
+            password: self.password,
+            ..TransformationStateOptions::default()
+        }
+    }
+}
+
+impl<'v> FromFormValue<'v> for TransformMetadata {
+    type Error = String;
+
+    fn from_form_value(value: &'v RawStr) -> Result<Self, Self::Error> {
+        let json = value.url_decode().map_err(|err| err.to_string())?;
+        serde_json::from_str(&json).map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody {
+    /// Stable, for clients to match on. The name of the `TransformationError` variant if
+    /// the transformation failed.
+    error: &'static str,
+    message: String,
+    /// Everything that led to the error, for bug reports
+    sources: Vec<String>,
+}
+
+#[derive(Debug)]
+struct ApiError {
+    status: Status,
+    body: ErrorBody,
+}
+
+impl ApiError {
+    fn new(status: Status, error: &'static str, message: String) -> ApiError {
+        ApiError {
+            status,
+            body: ErrorBody {
+                error,
+                message,
+                sources: Vec::new(),
+            },
+        }
+    }
+}
+
+impl From<TransformationError> for ApiError {
+    fn from(err: TransformationError) -> Self {
+        ApiError::from(&err)
+    }
+}
+
+impl From<&TransformationError> for ApiError {
+    fn from(err: &TransformationError) -> Self {
+        let (status, error) = match err {
+            TransformationError::Receiving(_) => (Status::BadRequest, "Receiving"),
+            TransformationError::Render(_) => (Status::UnprocessableEntity, "Render"),
+            TransformationError::Unknown => (Status::InternalServerError, "Unknown"),
+            TransformationError::NonexistentPage(_) => {
+                (Status::UnprocessableEntity, "NonexistentPage")
+            }
+            TransformationError::PixelRead(_) => (Status::InternalServerError, "PixelRead"),
+            TransformationError::InsufficientMemory => {
+                (Status::ServiceUnavailable, "InsufficientMemory")
+            }
+            TransformationError::ZeroPagePdf => (Status::UnprocessableEntity, "ZeroPagePdf"),
+            TransformationError::InvalidQuality(_) => (Status::BadRequest, "InvalidQuality"),
+            TransformationError::PasswordRequired => (Status::Unauthorized, "PasswordRequired"),
+            TransformationError::IncorrectPassword => (Status::Forbidden, "IncorrectPassword"),
+            TransformationError::ImageEncoding(_) => (Status::InternalServerError, "ImageEncoding"),
+            TransformationError::Structure(_) => (Status::UnprocessableEntity, "Structure"),
+            TransformationError::Output(_) => (Status::InternalServerError, "Output"),
+        };
+        ApiError {
+            status,
+            body: ErrorBody {
+                error,
+                message: err.to_string(),
+                sources: list_error_sources(err),
+            },
+        }
+    }
+}
+
+impl From<JobError> for ApiError {
+    fn from(err: JobError) -> Self {
+        let (status, error) = match err {
+            JobError::QueueFull => (Status::ServiceUnavailable, "QueueFull"),
+            JobError::NotFound(_) => (Status::NotFound, "JobNotFound"),
+            JobError::NoResult(_) => (Status::Conflict, "NoResult"),
+        };
+        ApiError::new(status, error, err.to_string())
+    }
+}
+
+impl<'r> Responder<'r> for ApiError {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        Response::build_from(Json(self.body).respond_to(request)?)
+            .status(self.status)
+            .ok()
+    }
+}
+
+/// The meta query parameter, which is None if it's missing
+fn metadata(
+    meta: Option<Result<TransformMetadata, String>>,
+) -> Result<TransformMetadata, ApiError> {
+    match meta {
+        Some(Ok(meta)) => {
+            slog_scope::info!("Transforming";
+                "client" => meta.client_uid.as_str(), "source" => meta.source.as_str());
+            Ok(meta)
+        }
+        Some(Err(err)) => Err(ApiError::new(
+            Status::BadRequest,
+            "InvalidMeta",
+            format!("Invalid meta: {}", err),
+        )),
+        None => Err(ApiError::new(
+            Status::BadRequest,
+            "InvalidMeta",
+            "Missing the meta query parameter".to_string(),
+        )),
+    }
+}
+
+fn read_pdf(pdf: Data) -> Result<Vec<u8>, ApiError> {
+    let mut in_blob = Vec::new();
+    pdf.open()
+        .take(MAX_PDF_BYTES + 1)
+        .read_to_end(&mut in_blob)
+        .map_err(|err| TransformationError::Receiving(err.to_string()))?;
+    if in_blob.len() as u64 > MAX_PDF_BYTES {
+        return Err(ApiError::new(
+            Status::PayloadTooLarge,
+            "TooLarge",
+            format!("PDFs can be at most {} bytes", MAX_PDF_BYTES),
+        ));
+    }
+    Ok(in_blob)
+}
+
+/// Responds with the metadata of the PDF and then each page as an image, see `pdf_to_images`.
+/// Pages are transformed as the response is read.
+#[post("/transform?<meta>", data = "<pdf>")]
+fn transform(
+    meta: Option<Result<TransformMetadata, String>>,
+    pdf: Data,
+) -> Result<Content<Stream<pdf_to_images::Images>>, ApiError> {
+    let options = metadata(meta)?.into_options();
+    let images = pdf_to_images::transform_with_options(read_pdf(pdf)?, options)?;
+    Ok(Content(ContentType::Binary, Stream::from(images)))
+}
+
+#[post("/transform/pdf?<meta>", data = "<pdf>")]
+fn transform_pdf(
+    meta: Option<Result<TransformMetadata, String>>,
+    pdf: Data,
+) -> Result<Content<Vec<u8>>, ApiError> {
+    let options = metadata(meta)?.into_options();
+    let complete = pdf_to_pdf::transform_with_options(read_pdf(pdf)?, options)?.finish()?;
+    Ok(Content(ContentType::PDF, complete.into_bytes()))
+}
+
+/// Like `submit_job`, but responds with Server-Sent Events as pages are transformed, see
+/// `events`
+#[post("/transform/events?<meta>", data = "<pdf>")]
+fn transform_events<'r>(
+    meta: Option<Result<TransformMetadata, String>>,
+    pdf: Data,
+    jobs: State<'r, Jobs>,
+) -> Result<Content<Stream<Events<'r>>>, ApiError> {
+    let options = metadata(meta)?.into_options();
+    let id = jobs.submit(read_pdf(pdf)?, options)?;
+    let events = Events::new(jobs.inner(), id);
+    Ok(Content(
+        ContentType::new("text", "event-stream"),
+        Stream::chunked(events, events::CHUNK_SIZE as u64),
+    ))
+}
+
+/// Where a job is up to, see `JobStatus`
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JobBody {
+    id: String,
+    /// Queued, Running, Done, Failed or Cancelled
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    position: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent_done: Option<f64>,
+    /// Zero indexed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    page: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ErrorBody>,
+}
+
+impl JobBody {
+    fn new(id: JobId, status: JobStatus) -> JobBody {
+        let mut body = JobBody {
+            id: id.to_string(),
+            status: "",
+            position: None,
+            percent_done: None,
+            page: None,
+            error: None,
+        };
+        body.status = match status {
+            JobStatus::Queued { position } => {
+                body.position = Some(position);
+                "Queued"
+            }
+            JobStatus::Running { percent_done, page } => {
+                body.percent_done = Some(percent_done);
+                body.page = page;
+                "Running"
+            }
+            JobStatus::Done => "Done",
+            JobStatus::Failed(err) => {
+                body.error = Some(ApiError::from(err.as_ref()).body);
+                "Failed"
+            }
+            JobStatus::Cancelled => "Cancelled",
+        };
+        body
+    }
+}
+
+fn job_id(id: &RawStr) -> Result<JobId, ApiError> {
+    id.parse().map_err(|_| {
+        ApiError::new(
+            Status::NotFound,
+            "JobNotFound",
+            format!("{} isn't a job ID", id),
+        )
+    })
+}
+
+/// Transforms to a PDF in the background, for documents that take too long for one request.
+/// Responds with the job to poll.
+#[post("/jobs?<meta>", data = "<pdf>")]
+fn submit_job(
+    meta: Option<Result<TransformMetadata, String>>,
+    pdf: Data,
+    jobs: State<Jobs>,
+) -> Result<status::Custom<Json<JobBody>>, ApiError> {
+    let options = metadata(meta)?.into_options();
+    let id = jobs.submit(read_pdf(pdf)?, options)?;
+    let body = JobBody::new(id, jobs.status(id)?);
+    Ok(status::Custom(Status::Accepted, Json(body)))
+}
+
+#[get("/jobs/<id>")]
+fn job_status(id: &RawStr, jobs: State<Jobs>) -> Result<Json<JobBody>, ApiError> {
+    let id = job_id(id)?;
+    Ok(Json(JobBody::new(id, jobs.status(id)?)))
+}
+
+/// The path of `job_result` for a job
+fn job_result_uri(id: JobId) -> String {
+    format!("{}/jobs/{}/pdf", BASE, id)
+}
+
+#[get("/jobs/<id>/pdf")]
+fn job_result(id: &RawStr, jobs: State<Jobs>) -> Result<Content<File>, ApiError> {
+    Ok(Content(ContentType::PDF, jobs.result(job_id(id)?)?))
+}
+
+#[delete("/jobs/<id>")]
+fn cancel_job(id: &RawStr, jobs: State<Jobs>) -> Result<Json<JobBody>, ApiError> {
+    let id = job_id(id)?;
+    jobs.cancel(id)?;
+    Ok(Json(JobBody::new(id, jobs.status(id)?)))
+}
+
+#[get("/version")]
+fn version() -> &'static str {
+    env!("CARGO_PKG_VERSION")
+}
+
+#[catch(400)]
+fn bad_request() -> Json<ErrorBody> {
+    Json(ErrorBody {
+        error: "BadRequest",
+        message: "The request was malformed".to_string(),
+        sources: Vec::new(),
+    })
+}
+
+#[catch(404)]
+fn not_found(request: &Request) -> Json<ErrorBody> {
+    Json(ErrorBody {
+        error: "NotFound",
+        message: format!("Nothing at {}", request.uri()),
+        sources: Vec::new(),
+    })
+}
+
+#[catch(500)]
+fn internal_error() -> Json<ErrorBody> {
+    Json(ErrorBody {
+        error: "Internal",
+        message: "Something went wrong on our end".to_string(),
+        sources: Vec::new(),
+    })
+}
+
+/// From the job_workers, job_queue and job_ttl (in seconds) settings, see Rocket.toml
+fn jobs_options(config: &rocket::Config) -> JobsOptions {
+    let default = JobsOptions::default();
+    let setting = |name: &str, default: u64| {
+        config
+            .get_int(name)
+            .ok()
+            .map_or(default, |value| value.max(0) as u64)
+    };
+    JobsOptions {
+        workers: setting("job_workers", default.workers as u64) as usize,
+        max_queued: setting("job_queue", default.max_queued as u64) as usize,
+        // Results kept for no time at all could never be fetched
+        ttl: Duration::from_secs(setting("job_ttl", default.ttl.as_secs()).max(1)),
+    }
+}
+
+fn rocket() -> rocket::Rocket {
+    let rocket = rocket::ignite();
+    let jobs = Jobs::new(jobs_options(rocket.config()));
+    rocket
+        .manage(jobs)
+        .mount(
+            BASE,
+            routes![
+                transform,
+                transform_pdf,
+                transform_events,
+                submit_job,
+                job_status,
+                job_result,
+                cancel_job,
+                version
+            ],
+        )
+        .register(catchers![bad_request, not_found, internal_error])
+}
+
+fn main() {
+    let mut builder = TerminalLoggerBuilder::new();
+    builder.level(Severity::Info);
+    builder.destination(Destination::Stderr);
+    let logger = builder.build().expect("Failed to build the logger");
+    let _guard = slog_scope::set_global_logger(logger);
+
+    let err = rocket().launch();
+    slog_scope::error!("Failed to launch: {}", err);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rocket::http::uri::Uri;
+    use rocket::local::Client;
+
+    fn get_in_blob() -> Vec<u8> {
+        include_bytes!("../../../test_assets/multipage_test.pdf").to_vec()
+    }
+
+    fn uri(path: &str, meta: &str) -> String {
+        format!("/purpleifypdf{}?meta={}", path, Uri::percent_encode(meta))
+    }
+
+    const META: &str = r#"{"clientUid": "9999", "quality": "ExtraLow",
+        "backgroundColor": {"r": 255, "g": 100, "b": 50}, "source": "file://fake",
+        "pageRange": {"starting_index": 0, "count": 1}}"#;
+
+    #[test]
+    fn transforms_to_pdf_and_images() {
+        let client = Client::new(rocket()).unwrap();
+
+        let mut response = client
+            .post(uri("/transform/pdf", META))
+            .body(get_in_blob())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::PDF));
+        assert!(response.body_bytes().unwrap().starts_with(b"%PDF"));
+
+        let mut response = client
+            .post(uri("/transform", META))
+            .body(get_in_blob())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.body_bytes().unwrap().starts_with(b"PPDF"));
+    }
+
+    #[test]
+    fn runs_jobs() {
+        let client = Client::new(rocket()).unwrap();
+        let job = |mut response: rocket::local::LocalResponse| {
+            serde_json::from_str::<serde_json::Value>(&response.body_string().unwrap()).unwrap()
+        };
+
+        let response = client
+            .post(uri("/jobs", META))
+            .body(get_in_blob())
+            .dispatch();
+        assert_eq!(response.status(), Status::Accepted);
+        let id = job(response)["id"].as_str().unwrap().to_string();
+        let path = format!("/purpleifypdf/jobs/{}", id);
+        loop {
+            let status = job(client.get(path.clone()).dispatch());
+            match status["status"].as_str().unwrap() {
+                "Queued" | "Running" => std::thread::sleep(Duration::from_millis(10)),
+                "Done" => break,
+                other => panic!("Job is {}: {}", other, status),
+            }
+        }
+        let mut response = client.get(format!("{}/pdf", path)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.body_bytes().unwrap().starts_with(b"%PDF"));
+
+        let response = client.delete(path.clone()).dispatch();
+        assert_eq!(job(response)["status"], "Cancelled");
+        let response = client.get(format!("{}/pdf", path)).dispatch();
+        assert_eq!(response.status(), Status::Conflict);
+        let response = client.get("/purpleifypdf/jobs/nothing").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn reports_errors_as_json() {
+        let client = Client::new(rocket()).unwrap();
+        let error = |mut response: rocket::local::LocalResponse| {
+            let body: serde_json::Value =
+                serde_json::from_str(&response.body_string().unwrap()).unwrap();
+            (
+                response.status(),
+                body["error"].as_str().unwrap().to_string(),
+            )
+        };
+
+        let response = client
+            .post(uri("/transform/pdf", META))
+            .body(include_bytes!("../../../test_assets/encrypted_test.pdf").to_vec())
+            .dispatch();
+        assert_eq!(
+            error(response),
+            (Status::Unauthorized, "PasswordRequired".to_string())
+        );
+
+        let response = client
+            .post(uri("/transform", "{}"))
+            .body(get_in_blob())
+            .dispatch();
+        assert_eq!(
+            error(response),
+            (Status::BadRequest, "InvalidMeta".to_string())
+        );
+
+        let response = client.get("/purpleifypdf/nothing").dispatch();
+        assert_eq!(error(response), (Status::NotFound, "NotFound".to_string()));
+    }
+
+    #[test]
+    fn keeps_job_results_for_at_least_a_second() {
+        let config = rocket::Config::build(rocket::config::Environment::Development)
+            .extra("job_ttl", 0)
+            .unwrap();
+
+        assert_eq!(jobs_options(&config).ttl, Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,250 @@
+//! Picking the resolution and image encoding of each page so the output lands under a target
+//! size.
+//!
+//! We start from how well a few sample pages compress, then after every page spread what's left
+//! of the budget over the pages still to come. Pages keep the encoding that was asked for while
+//! the budget affords a comfortable resolution, and switch to JPEG once it doesn't.
+
+use crate::encoding::{EncodedImage, Encoding};
+use crate::streaming::compress;
+use crate::{OutputPage, Quality, Result, TransformationState, PPI};
+use image::{DynamicImage, GenericImageView};
+use lopdf::Object;
+
+/// How many pages we render before starting, spread across the document
+const SAMPLE_PAGES: usize = 3;
+/// Sample pages are rendered at about this many pixels
+const SAMPLE_PIXELS: u64 = 500_000;
+/// Leaves room for the rest of the file
+const IMAGE_SHARE: f64 = 0.9;
+/// Our guesses if we haven't seen a rendered page yet
+const DEFAULT_BYTES_PER_PIXEL: f64 = 0.25;
+const DEFAULT_JPEG_BYTES_PER_PIXEL: f64 = 0.1;
+/// The resolutions of `Quality::Extreme` and `Quality::ExtremeLow`. We don't go past the first
+/// with room to spare, or below the second if we run out.
+const MAX_PPI: f64 = 400.0;
+const MIN_PPI: f64 = 10.0;
+/// Below this resolution we switch to JPEG, if that affords more pixels
+const COMFORTABLE_PPI: f64 = 150.0;
+/// What we switch to. Low enough to be much smaller than lossless, high enough that text
+/// doesn't smear.
+const BUDGET_JPEG: Encoding = Encoding::Jpeg { quality: 75 };
+
+#[derive(Debug)]
+pub(crate) struct SizeBudget {
+    /// Bytes left for the pages still to come
+    remaining: f64,
+    remaining_pages: usize,
+    /// The encoding that was asked for
+    preferred: Encoding,
+    /// How pages written with the preferred encoding and with `BUDGET_JPEG` have compressed,
+    /// samples included
+    preferred_rate: Rate,
+    jpeg_rate: Rate,
+}
+
+/// Compressed bytes per pixel
+#[derive(Debug, Default)]
+struct Rate {
+    bytes: f64,
+    pixels: f64,
+}
+
+impl Rate {
+    fn add(&mut self, bytes: f64, pixels: f64) {
+        self.bytes += bytes;
+        self.pixels += pixels;
+    }
+
+    fn bytes_per_pixel(&self, default: f64) -> f64 {
+        if self.pixels > 0.0 {
+            self.bytes / self.pixels
+        } else {
+            default
+        }
+    }
+}
+
+/// What a page takes out of the budget, apart from what's written as it's added
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct PageCost {
+    pixels: f64,
+    /// Pages that kept their original content are only written at the end, so we count their
+    /// content up front
+    unwritten: f64,
+}
+
+impl PageCost {
+    pub(crate) fn of(page: &OutputPage) -> PageCost {
+        match page {
+            OutputPage::Raster(page) => {
+                let (width, height) = page.image.dimensions();
+                PageCost {
+                    pixels: width as f64 * height as f64,
+                    unwritten: 0.0,
+                }
+            }
+            OutputPage::Vector(page) => PageCost {
+                pixels: 0.0,
+                unwritten: page.content_len() as f64,
+            },
+        }
+    }
+}
+
+impl SizeBudget {
+    /// A budget of target bytes for the pages of state
+    pub(crate) fn sample(state: &TransformationState, target: u64) -> Result<SizeBudget> {
+        let page_count = state.included_page_count();
+        let preferred = state.options.encoding;
+        let mut budget = SizeBudget {
+            remaining: target as f64 * IMAGE_SHARE,
+            remaining_pages: page_count,
+            preferred,
+            preferred_rate: Rate::default(),
+            jpeg_rate: Rate::default(),
+        };
+
+        let step = (page_count / SAMPLE_PAGES).max(1);
+        for offset in (0..page_count).step_by(step).take(SAMPLE_PAGES) {
+            let page = state.transform_page_at(offset, Quality::MaxPixels(SAMPLE_PIXELS))?;
+            let (width, height) = page.image.dimensions();
+            let pixels = width as f64 * height as f64;
+            let bytes = encoded_len(&page.image, preferred)?;
+            budget.preferred_rate.add(bytes, pixels);
+            if budget.may_switch() {
+                let bytes = encoded_len(&page.image, BUDGET_JPEG)?;
+                budget.jpeg_rate.add(bytes, pixels);
+            }
+        }
+
+        Ok(budget)
+    }
+
+    /// The quality to render the next page at and the encoding to store it with, given its
+    /// size in points
+    pub(crate) fn plan(&self, width: f64, height: f64) -> (Quality, Encoding) {
+        let per_page = self.remaining.max(0.0) / self.remaining_pages.max(1) as f64;
+        let ppi = |bytes_per_pixel: f64| {
+            let pixels = (per_page / bytes_per_pixel) as u64;
+            PPI::for_page(Quality::MaxPixels(pixels), width, height).as_f64()
+        };
+
+        let mut plan = (
+            ppi(self.preferred_rate.bytes_per_pixel(DEFAULT_BYTES_PER_PIXEL)),
+            self.preferred,
+        );
+        if plan.0 < COMFORTABLE_PPI && self.may_switch() {
+            let jpeg_ppi = ppi(self.jpeg_rate.bytes_per_pixel(DEFAULT_JPEG_BYTES_PER_PIXEL));
+            if jpeg_ppi > plan.0 {
+                plan = (jpeg_ppi, BUDGET_JPEG);
+            }
+        }
+        (Quality::Custom(plan.0.max(MIN_PPI).min(MAX_PPI)), plan.1)
+    }
+
+    /// Take a page that went into the output out of the budget. written is how many bytes
+    /// adding it wrote, and encoding what `plan` picked for it.
+    pub(crate) fn record(&mut self, cost: PageCost, written: usize, encoding: Encoding) {
+        let bytes = written as f64 + cost.unwritten;
+        self.remaining -= bytes;
+        self.remaining_pages = self.remaining_pages.saturating_sub(1);
+        if cost.pixels > 0.0 {
+            let rate = if encoding == self.preferred {
+                &mut self.preferred_rate
+            } else {
+                &mut self.jpeg_rate
+            };
+            rate.add(written as f64, cost.pixels);
+        }
+    }
+
+    /// If the preferred encoding is already lossy there's nothing to switch to
+    fn may_switch(&self) -> bool {
+        match self.preferred {
+            Encoding::Jpeg { .. } => false,
+            _ => true,
+        }
+    }
+}
+
+/// The compressed size of a sample page's image
+fn encoded_len(image: &DynamicImage, encoding: Encoding) -> Result<f64> {
+    let EncodedImage { dict, data, .. } = EncodedImage::new(image, encoding)?;
+    Ok(match compress(dict, data) {
+        Object::Stream(stream) => stream.content.len() as f64,
+        _ => 0.0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn new_budget(remaining: f64, preferred: Encoding) -> SizeBudget {
+        SizeBudget {
+            remaining,
+            remaining_pages: 2,
+            preferred,
+            preferred_rate: Rate {
+                bytes: 100_000.0,
+                pixels: 1_000_000.0,
+            },
+            jpeg_rate: Rate {
+                bytes: 20_000.0,
+                pixels: 1_000_000.0,
+            },
+        }
+    }
+
+    fn ppi(quality: Quality) -> f64 {
+        match quality {
+            Quality::Custom(ppi) => ppi,
+            _ => panic!("Expected a custom quality"),
+        }
+    }
+
+    #[test]
+    fn spreads_what_is_left_over_the_remaining_pages() {
+        // Already lossy, so it never switches
+        let jpeg = Encoding::Jpeg { quality: 90 };
+        let mut budget = new_budget(2_000_000.0, jpeg);
+        // A letter page at 0.1 bytes per pixel gets about 10 megapixels
+        let (quality, encoding) = budget.plan(612.0, 792.0);
+        let letter = ppi(quality);
+        assert!(letter > 300.0 && letter < 350.0);
+        assert_eq!(encoding, jpeg);
+
+        // Spending most of the budget on one page leaves little for the other
+        budget.remaining = 100_000.0;
+        assert_eq!(budget.plan(612.0, 792.0).1, jpeg);
+        assert!(ppi(budget.plan(612.0, 792.0).0) < letter / 3.0);
+
+        // But never below what's readable
+        budget.remaining = -1.0;
+        assert_eq!(ppi(budget.plan(612.0, 792.0).0), MIN_PPI);
+    }
+
+    #[test]
+    fn switches_to_jpeg_when_the_budget_is_tight() {
+        let mut budget = new_budget(200_000.0, Encoding::Flate);
+
+        let (quality, encoding) = budget.plan(612.0, 792.0);
+        assert_eq!(encoding, BUDGET_JPEG);
+        assert!(ppi(quality) > 100.0);
+
+        // Pages written as JPEG only teach us about JPEG
+        let cost = PageCost {
+            pixels: 1_000_000.0,
+            unwritten: 0.0,
+        };
+        budget.record(cost, 80_000, BUDGET_JPEG);
+        assert_eq!(budget.preferred_rate.bytes, 100_000.0);
+        assert_eq!(budget.jpeg_rate.bytes, 100_000.0);
+        assert_eq!(budget.remaining, 120_000.0);
+
+        // With room to spare we keep what was asked for
+        budget.remaining = 2_000_000.0;
+        assert_eq!(budget.plan(612.0, 792.0).1, Encoding::Flate);
+    }
+}
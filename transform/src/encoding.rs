@@ -0,0 +1,218 @@
+//! How page images are stored in output PDFs.
+//!
+//! A transformed page is mostly the background color, the ink and the anti-aliasing between
+//! them, so a palette of a few colors usually beats storing every pixel.
+
+use crate::Result;
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use lopdf::{Dictionary, Object, StringFormat};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The most colors an indexed image can have
+const MAX_PALETTE_COLORS: usize = 256;
+/// Auto treats pages with more colors than this as photos
+const PHOTO_COLORS: usize = 16_384;
+/// The JPEG quality Auto uses for photos
+const AUTO_JPEG_QUALITY: u8 = 85;
+
+/// How the image of each page is encoded
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Encoding {
+    /// Picked per page by counting its colors. Indexed if there are few enough, Jpeg for
+    /// photos and Flate for everything in between.
+    Auto,
+    /// Every pixel, compressed losslessly
+    Flate,
+    /// Lossy, but much smaller for photos. quality is from 1 to 100.
+    Jpeg { quality: u8 },
+    /// A palette plus 1, 2, 4 or 8 bits per pixel, depending on how many colors the page has.
+    /// Pages with more than 256 colors fall back to Flate.
+    Indexed,
+}
+
+impl Default for Encoding {
+    fn default() -> Self {
+        Encoding::Flate
+    }
+}
+
+/// A page image ready to be written as an image XObject
+pub(crate) struct EncodedImage {
+    /// What was actually used, never Auto
+    pub(crate) encoding: Encoding,
+    pub(crate) dict: Dictionary,
+    /// Still needs compressing unless dict has a Filter
+    pub(crate) data: Vec<u8>,
+}
+
+impl EncodedImage {
+    pub(crate) fn new(image: &DynamicImage, encoding: Encoding) -> Result<EncodedImage> {
+        let image = image.to_rgb();
+        let encoding = match encoding {
+            Encoding::Auto => match count_colors(&image, PHOTO_COLORS + 1) {
+                count if count <= MAX_PALETTE_COLORS => Encoding::Indexed,
+                count if count > PHOTO_COLORS => Encoding::Jpeg {
+                    quality: AUTO_JPEG_QUALITY,
+                },
+                _ => Encoding::Flate,
+            },
+            encoding => encoding,
+        };
+
+        let mut dict = Dictionary::new();
+        dict.set("Type", "XObject");
+        dict.set("Subtype", "Image");
+        dict.set("Width", image.width() as i64);
+        dict.set("Height", image.height() as i64);
+
+        match encoding {
+            Encoding::Indexed => {
+                if let Some((palette, bits, data)) = indexed(&image) {
+                    let high_index = palette.len() as i64 / 3 - 1;
+                    dict.set(
+                        "ColorSpace",
+                        vec![
+                            Object::Name(b"Indexed".to_vec()),
+                            Object::Name(b"DeviceRGB".to_vec()),
+                            Object::Integer(high_index),
+                            Object::String(palette, StringFormat::Hexadecimal),
+                        ],
+                    );
+                    dict.set("BitsPerComponent", bits as i64);
+                    return Ok(EncodedImage {
+                        encoding,
+                        dict,
+                        data,
+                    });
+                }
+                // Too many colors
+                EncodedImage::new(&DynamicImage::ImageRgb8(image), Encoding::Flate)
+            }
+            Encoding::Jpeg { quality } => {
+                let mut data = Vec::new();
+                DynamicImage::ImageRgb8(image)
+                    .write_to(&mut data, ImageOutputFormat::Jpeg(quality.max(1).min(100)))?;
+                dict.set("ColorSpace", "DeviceRGB");
+                dict.set("BitsPerComponent", 8);
+                dict.set("Filter", "DCTDecode");
+                Ok(EncodedImage {
+                    encoding,
+                    dict,
+                    data,
+                })
+            }
+            _ => {
+                dict.set("ColorSpace", "DeviceRGB");
+                dict.set("BitsPerComponent", 8);
+                Ok(EncodedImage {
+                    encoding: Encoding::Flate,
+                    dict,
+                    data: image.into_raw(),
+                })
+            }
+        }
+    }
+}
+
+/// How many distinct colors image has, counting no further than limit
+fn count_colors(image: &RgbImage, limit: usize) -> usize {
+    let mut colors = HashSet::new();
+    for pixel in image.pixels() {
+        if colors.insert(pixel.0) && colors.len() >= limit {
+            break;
+        }
+    }
+    colors.len()
+}
+
+/// The palette (r, g, b for each color), bits per pixel and packed rows of indices, or None if
+/// image has too many colors for a palette
+fn indexed(image: &RgbImage) -> Option<(Vec<u8>, u8, Vec<u8>)> {
+    let mut palette = Vec::new();
+    let mut indices = HashMap::new();
+    let mut pixel_indices = Vec::with_capacity(image.width() as usize * image.height() as usize);
+    for pixel in image.pixels() {
+        let index = match indices.get(&pixel.0) {
+            Some(index) => *index,
+            None => {
+                if indices.len() == MAX_PALETTE_COLORS {
+                    return None;
+                }
+                let index = indices.len() as u8;
+                indices.insert(pixel.0, index);
+                palette.extend_from_slice(&pixel.0);
+                index
+            }
+        };
+        pixel_indices.push(index);
+    }
+
+    let bits: u8 = match indices.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    let per_byte = (8 / bits) as usize;
+    let width = image.width() as usize;
+
+    // Every row starts on a new byte
+    let mut data = Vec::new();
+    if width > 0 {
+        for row in pixel_indices.chunks(width) {
+            for group in row.chunks(per_byte) {
+                let mut byte = 0;
+                for (position, index) in group.iter().enumerate() {
+                    byte |= index << (8 - bits as usize * (position + 1));
+                }
+                data.push(byte);
+            }
+        }
+    }
+
+    Some((palette, bits, data))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn packs_two_colors_into_one_bit() {
+        // Two rows of three pixels: purple, black, purple then black, black, purple
+        let purple = Rgb([226, 97, 255]);
+        let black = Rgb([0, 0, 0]);
+        let image = RgbImage::from_fn(3, 2, |x, y| match (x, y) {
+            (0, 0) | (2, 0) | (2, 1) => purple,
+            _ => black,
+        });
+
+        let (palette, bits, data) = indexed(&image).unwrap();
+
+        assert_eq!(palette, vec![226, 97, 255, 0, 0, 0]);
+        assert_eq!(bits, 1);
+        assert_eq!(data, vec![0b0100_0000, 0b1100_0000]);
+    }
+
+    #[test]
+    fn auto_picks_by_color_count() {
+        let few = RgbImage::from_fn(64, 64, |x, _| Rgb([x as u8 % 4, 0, 0]));
+        let many = RgbImage::from_fn(256, 256, |x, y| Rgb([x as u8, y as u8, 0]));
+
+        let encode = |image: RgbImage| {
+            EncodedImage::new(&DynamicImage::ImageRgb8(image), Encoding::Auto)
+                .unwrap()
+                .encoding
+        };
+
+        assert_eq!(encode(few), Encoding::Indexed);
+        assert_eq!(
+            encode(many),
+            Encoding::Jpeg {
+                quality: AUTO_JPEG_QUALITY
+            }
+        );
+    }
+}
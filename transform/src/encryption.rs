@@ -0,0 +1,682 @@
+//! Encrypting output PDFs with the standard security handler, and decrypting sources that use it.
+//!
+//! We use 128 bit RC4 (revision 3 of the handler), which every reader we care about can open.
+//! Every string and stream is encrypted with a key derived from the user password, and readers
+//! enforce the permissions unless they're given the owner password. Sources encrypted with RC4
+//! can be decrypted the same way, but not ones encrypted with AES.
+
+use lopdf::{Dictionary, Document, Object, ObjectId, StringFormat};
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+
+/// In bytes
+const KEY_LEN: usize = 16;
+/// The revision of the handler we encrypt with
+const REVISION: i64 = 3;
+/// Passwords are padded or truncated to 32 bytes with this
+const PADDING: [u8; 32] = [
+    0x28, 0xBF, 0x4E, 0x5E, 0x4E, 0x75, 0x8A, 0x41, 0x64, 0x00, 0x4E, 0x56, 0xFF, 0xFA, 0x01, 0x08,
+    0x2E, 0x2E, 0x00, 0xB6, 0xD0, 0x68, 0x3E, 0x80, 0x2F, 0x0C, 0xA9, 0xFE, 0x64, 0x53, 0x69, 0x7A,
+];
+
+/// Whether output PDFs are encrypted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum OutputEncryption {
+    /// Encrypt if the source needed a password or restricted what readers can do, with the
+    /// same user password and permissions, even if the source was opened with its owner
+    /// password. The owner password is random, use `Encrypted` to set one.
+    MatchSource,
+    Unencrypted,
+    Encrypted(Encryption),
+}
+
+impl Default for OutputEncryption {
+    fn default() -> Self {
+        OutputEncryption::MatchSource
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Encryption {
+    /// Needed to open the document. If empty anyone can open it, within the permissions.
+    #[serde(default)]
+    pub user_password: String,
+    /// Lifts the permissions. If empty a random one is used, so they can't be lifted.
+    #[serde(default)]
+    pub owner_password: String,
+    #[serde(default)]
+    pub permissions: Permissions,
+}
+
+/// What readers let someone without the owner password do
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Permissions {
+    pub print: bool,
+    /// Copying text and images, including for accessibility tools
+    pub copy: bool,
+    /// Changing the content, annotating, filling in forms and rearranging pages
+    pub modify: bool,
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Permissions {
+            print: true,
+            copy: true,
+            modify: true,
+        }
+    }
+}
+
+impl Permissions {
+    /// From the P entry of an encryption dictionary
+    fn from_flags(flags: i32) -> Permissions {
+        // Bits are numbered from 1
+        let allows = |bit: i32| flags & 1 << (bit - 1) != 0;
+        Permissions {
+            print: allows(3),
+            copy: allows(5),
+            modify: allows(4),
+        }
+    }
+
+    /// The P entry of the encryption dictionary
+    fn flags(self) -> i32 {
+        // Bits 7, 8 and 13 to 32 must be set in revision 3. Bits are numbered from 1.
+        let mut flags: u32 = 0xFFFF_F0C0;
+        if self.print {
+            // Printing at all, then at full quality
+            flags |= 1 << 2 | 1 << 11;
+        }
+        if self.modify {
+            // Content, annotations, forms and assembly
+            flags |= 1 << 3 | 1 << 5 | 1 << 8 | 1 << 10;
+        }
+        if self.copy {
+            // Copying, then extracting for accessibility
+            flags |= 1 << 4 | 1 << 9;
+        }
+        flags as i32
+    }
+}
+
+impl Encryption {
+    /// How to encrypt the output of a source opened with password, so it's as protected as the
+    /// source. None if the source wasn't protected.
+    ///
+    /// granted is what the source let us do, which is everything if password was its owner
+    /// password, so it's only used if we couldn't read the source's handler.
+    pub(crate) fn matching(
+        source: Option<&SourceEncryption>,
+        password: &str,
+        granted: Permissions,
+    ) -> Option<Encryption> {
+        let (user_password, permissions) = match source {
+            Some(source) => (
+                source
+                    .user_password(password)
+                    .unwrap_or_else(|| password.to_string()),
+                source.permissions(),
+            ),
+            None => (password.to_string(), granted),
+        };
+
+        if user_password.is_empty() && permissions == Permissions::default() {
+            return None;
+        }
+        Some(Encryption {
+            user_password,
+            owner_password: String::new(),
+            permissions,
+        })
+    }
+}
+
+/// The standard security handler of a source document
+pub(crate) struct SourceEncryption {
+    /// The P entry
+    flags: i32,
+    /// None for revisions that derive keys with something other than MD5 and RC4 (AES-256)
+    handler: Option<Handler>,
+    /// The U entry
+    user_entry: Vec<u8>,
+    /// Whether strings and streams are encrypted with RC4 rather than AES
+    rc4: bool,
+}
+
+impl SourceEncryption {
+    /// None if doc isn't encrypted with the standard handler
+    pub(crate) fn read(doc: &Document) -> Option<SourceEncryption> {
+        let dict = doc
+            .trailer
+            .get(b"Encrypt")
+            .and_then(|dict| doc.dereference(dict))
+            .and_then(|(_, dict)| dict.as_dict())
+            .ok()?;
+        if dict.get(b"Filter").and_then(Object::as_name).ok()? != b"Standard" {
+            return None;
+        }
+        let string = |key: &[u8]| Some(dict.get(key).and_then(Object::as_str).ok()?.to_vec());
+        let flags = dict.get(b"P").and_then(Object::as_i64).ok()? as i32;
+        let user_entry = string(b"U")?;
+
+        let revision = dict.get(b"R").and_then(Object::as_i64).ok()?;
+        let key_len = match revision {
+            2 => Some(5),
+            3 | 4 => Some(dict.get(b"Length").and_then(Object::as_i64).unwrap_or(40) as usize / 8),
+            _ => None,
+        };
+        let id = doc
+            .trailer
+            .get(b"ID")
+            .and_then(Object::as_array)
+            .ok()
+            .and_then(|id| id.first()?.as_str().ok());
+        let handler = match (key_len, string(b"O"), id) {
+            (Some(key_len), Some(owner_entry), Some(id)) if (5..=KEY_LEN).contains(&key_len) => {
+                Some(Handler {
+                    revision,
+                    key_len,
+                    owner_entry,
+                    flags,
+                    id: id.to_vec(),
+                    encrypt_metadata: !matches!(
+                        dict.get(b"EncryptMetadata"),
+                        Ok(Object::Boolean(false))
+                    ),
+                })
+            }
+            _ => None,
+        };
+        // Version 4 picks the cipher with crypt filters, V2 being RC4
+        let rc4 = match dict.get(b"V").and_then(Object::as_i64).unwrap_or(0) {
+            1 | 2 => true,
+            4 => [&b"StmF"[..], b"StrF"].iter().all(|&filter| {
+                let method = dict
+                    .get(filter)
+                    .and_then(Object::as_name)
+                    .and_then(|name| dict.get(b"CF")?.as_dict()?.get(name)?.as_dict())
+                    .and_then(|filter| filter.get(b"CFM")?.as_name());
+                matches!(method, Ok(b"V2"))
+            }),
+            _ => false,
+        };
+
+        Some(SourceEncryption {
+            flags,
+            handler,
+            user_entry,
+            rc4,
+        })
+    }
+
+    /// What readers without the owner password may do
+    pub(crate) fn permissions(&self) -> Permissions {
+        Permissions::from_flags(self.flags)
+    }
+
+    /// The user password, given either of the document's passwords. If password is the owner
+    /// password the user password is recovered from the O entry. None if password is neither,
+    /// or we can't tell.
+    pub(crate) fn user_password(&self, password: &str) -> Option<String> {
+        let given = padded(password);
+        match self.padded_user_password(&given)? {
+            user if user == given => Some(password.to_string()),
+            user => unpadded(&user),
+        }
+    }
+
+    /// Decrypt doc, which this was read from, with either of its passwords, and remove its
+    /// Encrypt entry so it reads like a document that never was. Returns false and leaves doc
+    /// alone if password is neither or it's encrypted with AES.
+    pub(crate) fn decrypt(&self, doc: &mut Document, password: &str) -> bool {
+        let handler = match &self.handler {
+            Some(handler) if self.rc4 => handler,
+            _ => return false,
+        };
+        let key = match self.padded_user_password(&padded(password)) {
+            Some(user) => handler.file_key(&user),
+            None => return false,
+        };
+
+        let encrypt_id = doc
+            .trailer
+            .get(b"Encrypt")
+            .and_then(Object::as_reference)
+            .ok();
+        for (&id, object) in doc.objects.iter_mut() {
+            // The Encrypt dictionary and cross reference streams are never encrypted, and
+            // revision 4 can leave the XMP metadata alone
+            let type_name = object
+                .as_stream()
+                .and_then(|stream| stream.dict.get(b"Type"));
+            let unencrypted = match type_name.and_then(Object::as_name) {
+                Ok(b"XRef") => true,
+                Ok(b"Metadata") => !handler.encrypt_metadata,
+                _ => false,
+            };
+            if encrypt_id == Some(id) || unencrypted {
+                continue;
+            }
+            decrypt_object(&object_key(&key, id), object);
+        }
+        doc.trailer.remove(b"Encrypt");
+        true
+    }
+
+    /// The padded user password, given either padded password. None if given is neither, or we
+    /// can't tell.
+    fn padded_user_password(&self, given: &[u8; 32]) -> Option<[u8; 32]> {
+        let handler = self.handler.as_ref()?;
+        if self.is_user_password(handler, given) {
+            return Some(*given);
+        }
+
+        // Algorithm 7 of the spec
+        let owner_key = owner_key(given, handler.revision, handler.key_len);
+        let user = if handler.revision == 2 {
+            rc4(&owner_key, &handler.owner_entry)
+        } else {
+            rc4_rounds_undone(&owner_key, handler.owner_entry.clone())
+        };
+        let user: [u8; 32] = user.get(..32)?.try_into().ok()?;
+        Some(user).filter(|user| self.is_user_password(handler, user))
+    }
+
+    /// Algorithm 6 of the spec
+    fn is_user_password(&self, handler: &Handler, user: &[u8; 32]) -> bool {
+        let expected = handler.user_entry(&handler.file_key(user));
+        // Revision 3 and up only fill the first 16 bytes
+        let checked = if handler.revision == 2 { 32 } else { 16 };
+        self.user_entry.get(..checked) == expected.get(..checked)
+    }
+}
+
+/// The entries of a standard security handler the keys are derived from. Covers revisions 2 to
+/// 4, which derive them with MD5 and RC4.
+struct Handler {
+    revision: i64,
+    /// Of the file key, in bytes
+    key_len: usize,
+    /// The O entry
+    owner_entry: Vec<u8>,
+    /// The P entry
+    flags: i32,
+    /// The first part of the document's ID
+    id: Vec<u8>,
+    /// Revision 4 can leave the XMP metadata unencrypted, which changes the key
+    encrypt_metadata: bool,
+}
+
+impl Handler {
+    /// Algorithm 2 of the spec, the key every object key is derived from
+    fn file_key(&self, user: &[u8; 32]) -> Vec<u8> {
+        let mut context = md5::Context::new();
+        context.consume(user);
+        context.consume(&self.owner_entry);
+        context.consume(self.flags.to_le_bytes());
+        context.consume(&self.id);
+        if self.revision >= 4 && !self.encrypt_metadata {
+            context.consume([0xFF; 4]);
+        }
+        let mut hash = context.compute().0;
+        if self.revision >= 3 {
+            for _ in 0..50 {
+                hash = md5::compute(&hash[..self.key_len]).0;
+            }
+        }
+        hash[..self.key_len].to_vec()
+    }
+
+    /// Algorithms 4 and 5 of the spec, the U entry
+    fn user_entry(&self, key: &[u8]) -> Vec<u8> {
+        if self.revision == 2 {
+            return rc4(key, &PADDING);
+        }
+        let mut context = md5::Context::new();
+        context.consume(PADDING);
+        context.consume(&self.id);
+        let mut entry = rc4_rounds(key, context.compute().0.to_vec());
+        // Only the first 16 bytes are checked
+        entry.resize(32, 0);
+        entry
+    }
+}
+
+/// Encrypts the objects of one output PDF
+pub(crate) struct Encryptor {
+    key: Vec<u8>,
+    /// The first part of the document's ID, which the key depends on
+    id: Vec<u8>,
+    dict: Dictionary,
+}
+
+impl Encryptor {
+    pub(crate) fn new(encryption: &Encryption) -> Encryptor {
+        let id = uuid::Uuid::new_v4().as_bytes().to_vec();
+        let owner_password = if encryption.owner_password.is_empty() {
+            uuid::Uuid::new_v4().to_string()
+        } else {
+            encryption.owner_password.clone()
+        };
+        Encryptor::with_id(encryption, &owner_password, id)
+    }
+
+    fn with_id(encryption: &Encryption, owner_password: &str, id: Vec<u8>) -> Encryptor {
+        let user = padded(&encryption.user_password);
+        // Algorithm 3 of the spec, the O entry
+        let owner_key = owner_key(&padded(owner_password), REVISION, KEY_LEN);
+        let handler = Handler {
+            revision: REVISION,
+            key_len: KEY_LEN,
+            owner_entry: rc4_rounds(&owner_key, user.to_vec()),
+            flags: encryption.permissions.flags(),
+            id,
+            encrypt_metadata: true,
+        };
+        let key = handler.file_key(&user);
+        let user_entry = handler.user_entry(&key);
+
+        let mut dict = Dictionary::new();
+        dict.set("Filter", "Standard");
+        dict.set("V", 2);
+        dict.set("R", REVISION);
+        dict.set("Length", (KEY_LEN * 8) as i64);
+        dict.set(
+            "O",
+            Object::String(handler.owner_entry, StringFormat::Hexadecimal),
+        );
+        dict.set("U", Object::String(user_entry, StringFormat::Hexadecimal));
+        dict.set("P", handler.flags as i64);
+
+        Encryptor {
+            key,
+            id: handler.id,
+            dict,
+        }
+    }
+
+    /// The Encrypt entry of the trailer. It's never encrypted, so we keep it direct.
+    pub(crate) fn dict(&self) -> Dictionary {
+        self.dict.clone()
+    }
+
+    /// The ID entry of the trailer
+    pub(crate) fn id(&self) -> Object {
+        let id = Object::String(self.id.clone(), StringFormat::Hexadecimal);
+        Object::Array(vec![id.clone(), id])
+    }
+
+    /// A copy of object with its strings and streams encrypted. id is the indirect object it
+    /// belongs to.
+    pub(crate) fn encrypt(&self, id: ObjectId, object: &Object) -> Object {
+        let key = self.object_key(id);
+        encrypt_object(&key, object)
+    }
+
+    fn object_key(&self, id: ObjectId) -> Vec<u8> {
+        object_key(&self.key, id)
+    }
+}
+
+/// Algorithm 1 of the spec, the key of the strings and streams of one object
+fn object_key(file_key: &[u8], (num, generation): ObjectId) -> Vec<u8> {
+    let mut context = md5::Context::new();
+    context.consume(file_key);
+    context.consume(&num.to_le_bytes()[..3]);
+    context.consume(&generation.to_le_bytes()[..2]);
+    let len = (file_key.len() + 5).min(16);
+    context.compute().0[..len].to_vec()
+}
+
+fn encrypt_object(key: &[u8], object: &Object) -> Object {
+    match object {
+        Object::String(text, _) => Object::String(rc4(key, text), StringFormat::Hexadecimal),
+        Object::Array(array) => {
+            Object::Array(array.iter().map(|item| encrypt_object(key, item)).collect())
+        }
+        Object::Dictionary(dict) => Object::Dictionary(encrypt_dict(key, dict)),
+        Object::Stream(stream) => {
+            let mut copy = stream.clone();
+            copy.dict = encrypt_dict(key, &stream.dict);
+            copy.content = rc4(key, &stream.content);
+            Object::Stream(copy)
+        }
+        other => other.clone(),
+    }
+}
+
+fn encrypt_dict(key: &[u8], dict: &Dictionary) -> Dictionary {
+    let mut copy = Dictionary::new();
+    for (name, value) in dict.iter() {
+        copy.set(name.clone(), encrypt_object(key, value));
+    }
+    copy
+}
+
+fn decrypt_object(key: &[u8], object: &mut Object) {
+    match object {
+        Object::String(text, _) => *text = rc4(key, text),
+        Object::Array(array) => array.iter_mut().for_each(|item| decrypt_object(key, item)),
+        Object::Dictionary(dict) => decrypt_dict(key, dict),
+        Object::Stream(stream) => {
+            decrypt_dict(key, &mut stream.dict);
+            stream.content = rc4(key, &stream.content);
+        }
+        _ => {}
+    }
+}
+
+fn decrypt_dict(key: &[u8], dict: &mut Dictionary) {
+    for (_, value) in dict.iter_mut() {
+        decrypt_object(key, value);
+    }
+}
+
+/// Passwords should be PDFDocEncoding, which matches UTF-8 for ASCII passwords
+fn padded(password: &str) -> [u8; 32] {
+    let mut padded = PADDING;
+    let password = password.as_bytes();
+    let len = password.len().min(32);
+    padded[..len].copy_from_slice(&password[..len]);
+    padded[len..].copy_from_slice(&PADDING[..32 - len]);
+    padded
+}
+
+/// The password a padded password was made from, if it's UTF-8
+fn unpadded(padded: &[u8; 32]) -> Option<String> {
+    let len = (0..32)
+        .find(|&len| padded[len..] == PADDING[..32 - len])
+        .unwrap_or(32);
+    String::from_utf8(padded[..len].to_vec()).ok()
+}
+
+/// Steps a to d of algorithm 3 of the spec, the key the O entry is encrypted with
+fn owner_key(owner: &[u8; 32], revision: i64, key_len: usize) -> Vec<u8> {
+    let mut hash = md5::compute(owner).0;
+    if revision >= 3 {
+        for _ in 0..50 {
+            hash = md5::compute(hash).0;
+        }
+    }
+    hash[..key_len].to_vec()
+}
+
+/// Encrypt with key, then 19 more times with each byte of key xored with the round number
+fn rc4_rounds(key: &[u8], data: Vec<u8>) -> Vec<u8> {
+    (0..20u8).fold(data, |data, round| rc4(&round_key(key, round), &data))
+}
+
+/// Decrypt what `rc4_rounds` encrypted
+fn rc4_rounds_undone(key: &[u8], data: Vec<u8>) -> Vec<u8> {
+    (0..20u8)
+        .rev()
+        .fold(data, |data, round| rc4(&round_key(key, round), &data))
+}
+
+fn round_key(key: &[u8], round: u8) -> Vec<u8> {
+    key.iter().map(|byte| byte ^ round).collect()
+}
+
+/// Encrypting and decrypting are the same
+fn rc4(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut state: Vec<u8> = (0..=255).collect();
+    let mut j: u8 = 0;
+    for i in 0..256 {
+        j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+        state.swap(i, j as usize);
+    }
+
+    let (mut i, mut j) = (0u8, 0u8);
+    data.iter()
+        .map(|byte| {
+            i = i.wrapping_add(1);
+            j = j.wrapping_add(state[i as usize]);
+            state.swap(i as usize, j as usize);
+            let index = state[i as usize].wrapping_add(state[j as usize]);
+            byte ^ state[index as usize]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rc4_matches_known_output() {
+        assert_eq!(
+            rc4(b"Key", b"Plaintext"),
+            vec![0xBB, 0xF3, 0x16, 0xE8, 0xD9, 0x40, 0xAF, 0x0A, 0xD3]
+        );
+        assert_eq!(rc4(b"Key", &rc4(b"Key", b"Plaintext")), b"Plaintext");
+    }
+
+    #[test]
+    fn encrypts_strings_and_streams_per_object() {
+        let encryption = Encryption {
+            user_password: "purple".to_string(),
+            owner_password: "owner".to_string(),
+            permissions: Permissions::default(),
+        };
+        let encryptor = Encryptor::with_id(&encryption, "owner", vec![0; 16]);
+        let stream = Object::Stream(lopdf::Stream::new(Dictionary::new(), b"0 0 m".to_vec()));
+        let array = Object::Array(vec![Object::string_literal("Title"), 1.into()]);
+
+        let first = encryptor.encrypt((1, 0), &array);
+        let second = encryptor.encrypt((2, 0), &array);
+        let title = |object: &Object| object.as_array().unwrap()[0].as_str().unwrap().to_vec();
+        assert_ne!(title(&first), b"Title");
+        assert_ne!(title(&first), title(&second));
+        // Only strings are encrypted
+        assert_eq!(first.as_array().unwrap()[1].as_i64().unwrap(), 1);
+        let encrypted_stream = encryptor.encrypt((3, 0), &stream);
+        let content = &encrypted_stream.as_stream().unwrap().content;
+        assert_ne!(content, b"0 0 m");
+        assert_eq!(rc4(&encryptor.object_key((3, 0)), content), b"0 0 m");
+    }
+
+    #[test]
+    fn sets_required_permission_bits() {
+        let none = Permissions {
+            print: false,
+            copy: false,
+            modify: false,
+        };
+
+        assert_eq!(none.flags(), 0xFFFF_F0C0_u32 as i32);
+        assert_eq!(Permissions::default().flags(), -4);
+        assert_eq!(Permissions::from_flags(none.flags()), none);
+    }
+
+    #[test]
+    fn finds_the_user_password_from_either_password() {
+        let print_only = Permissions {
+            print: true,
+            copy: false,
+            modify: false,
+        };
+        // Revision 3, and revision 2
+        let restricted =
+            Document::load_mem(include_bytes!("../test_assets/restricted_test.pdf")).unwrap();
+        let restricted = SourceEncryption::read(&restricted).unwrap();
+        let encrypted =
+            Document::load_mem(include_bytes!("../test_assets/encrypted_test.pdf")).unwrap();
+        let encrypted = SourceEncryption::read(&encrypted).unwrap();
+
+        for source in &[&restricted, &encrypted] {
+            assert_eq!(source.user_password("purple").unwrap(), "purple");
+            assert_eq!(source.user_password("owner").unwrap(), "purple");
+            assert!(source.user_password("lavender").is_none());
+        }
+        assert_eq!(restricted.permissions(), print_only);
+        assert_eq!(encrypted.permissions(), Permissions::default());
+
+        // Opened with the owner password, which grants everything
+        assert_eq!(
+            Encryption::matching(Some(&restricted), "owner", Permissions::default()),
+            Some(Encryption {
+                user_password: "purple".to_string(),
+                owner_password: String::new(),
+                permissions: print_only,
+            })
+        );
+    }
+
+    #[test]
+    fn decrypts_rc4_but_not_aes() {
+        let mut restricted =
+            Document::load_mem(include_bytes!("../test_assets/restricted_test.pdf")).unwrap();
+        let source = SourceEncryption::read(&restricted).unwrap();
+        assert!(!source.decrypt(&mut restricted, "lavender"));
+        assert!(source.decrypt(&mut restricted, "owner"));
+
+        assert!(restricted.trailer.get(b"Encrypt").is_err());
+        let info = restricted
+            .trailer
+            .get(b"Info")
+            .unwrap()
+            .as_reference()
+            .unwrap();
+        let title = restricted
+            .get_dictionary(info)
+            .unwrap()
+            .get(b"Title")
+            .unwrap();
+        assert_eq!(title.as_str().unwrap(), b"Restricted test");
+        let page = restricted.get_pages()[&1];
+        assert_eq!(
+            restricted.get_page_content(page).unwrap(),
+            b"0 0 1 rg 100 600 200 100 re f"
+        );
+
+        // Revision 4 with AES crypt filters
+        let mut aes = Document::load_mem(include_bytes!("../test_assets/aes_test.pdf")).unwrap();
+        let source = SourceEncryption::read(&aes).unwrap();
+        assert_eq!(source.user_password("owner").unwrap(), "purple");
+        assert!(!source.decrypt(&mut aes, "purple"));
+        assert!(aes.trailer.get(b"Encrypt").is_ok());
+    }
+
+    #[test]
+    fn reads_what_it_writes() {
+        let encryption = Encryption {
+            user_password: String::new(),
+            owner_password: "owner".to_string(),
+            permissions: Permissions {
+                print: false,
+                copy: true,
+                modify: false,
+            },
+        };
+        let encryptor = Encryptor::with_id(&encryption, "owner", vec![7; 16]);
+        let mut doc = Document::new();
+        doc.trailer.set("Encrypt", encryptor.dict());
+        doc.trailer.set("ID", encryptor.id());
+
+        let source = SourceEncryption::read(&doc).unwrap();
+        assert_eq!(source.user_password("owner").unwrap(), "");
+        assert_eq!(source.permissions(), encryption.permissions);
+    }
+}
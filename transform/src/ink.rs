@@ -0,0 +1,120 @@
+//! Recognizing ink (near-black text and line art) in a rendered page so it can be recolored.
+
+use crate::background::luminance;
+use crate::{Color, LittleEndianRgbPixel};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InkDetection {
+    /// Pixels brighter than this (0-255) aren't ink
+    pub max_luminance: u8,
+    /// Pixels whose channels differ by more than this (0-255) are colored content like charts
+    /// or photos rather than ink, and are left alone
+    pub max_chroma: u8,
+}
+
+impl Default for InkDetection {
+    fn default() -> Self {
+        InkDetection {
+            max_luminance: 80,
+            max_chroma: 32,
+        }
+    }
+}
+
+pub(crate) struct InkRecolor {
+    color: LittleEndianRgbPixel<f64>,
+    detection: InkDetection,
+}
+
+impl InkRecolor {
+    pub(crate) fn new(color: Color, detection: InkDetection) -> Self {
+        let color: LittleEndianRgbPixel<u8> = color.into();
+        InkRecolor {
+            color: [color[0] as f64, color[1] as f64, color[2] as f64],
+            detection,
+        }
+    }
+
+    /// Recolors the pixel and returns true if it is ink, otherwise leaves it alone.
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    pub(crate) fn apply(&self, pixel: &mut [u8]) -> bool {
+        match self.strength(pixel) {
+            Some(strength) => {
+                self.blend(pixel, strength);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// How much of the ink color the pixel should get, from 0 to 1, or None if it isn't ink.
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    ///
+    /// Black gets all of it and it fades out towards max_luminance, so anti-aliased edges and
+    /// dark grays don't jump in color at the threshold.
+    pub(crate) fn strength(&self, pixel: &[u8]) -> Option<f64> {
+        let luminance = luminance(pixel);
+        if luminance > self.detection.max_luminance as usize {
+            return None;
+        }
+
+        let channels = &pixel[..3];
+        // Won't panic: channels always holds 3 items
+        let chroma = channels.iter().max().unwrap() - channels.iter().min().unwrap();
+        if chroma > self.detection.max_chroma {
+            return None;
+        }
+
+        Some(1.0 - luminance as f64 / (self.detection.max_luminance as f64 + 1.0))
+    }
+
+    /// Blends pixel towards the ink color. For ink that has already been changed, so its
+    /// strength had to be measured beforehand.
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    pub(crate) fn blend(&self, pixel: &mut [u8], strength: f64) {
+        for (channel, ink) in pixel.iter_mut().zip(&self.color) {
+            let current = *channel as f64;
+            let blended = current + (ink - current) * strength;
+            *channel = blended.round().max(0.0).min(255.0) as u8;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recolors_black_and_leaves_color_alone() {
+        let navy = InkRecolor::new(Color::new(20, 30, 80), InkDetection::default());
+        let recolor = |mut pixel: [u8; 4]| {
+            let is_ink = navy.apply(&mut pixel);
+            (is_ink, pixel)
+        };
+
+        // b, g, r, a
+        assert_eq!(recolor([0, 0, 0, 255]), (true, [80, 30, 20, 255]));
+        // Dark red chart line
+        assert_eq!(recolor([0, 0, 140, 255]), (false, [0, 0, 140, 255]));
+        // Light gray
+        assert_eq!(recolor([200, 200, 200, 255]), (false, [200, 200, 200, 255]));
+
+        // Dark gray is moved only part of the way towards the ink color
+        assert_eq!(recolor([60, 60, 60, 255]), (true, [65, 52, 49, 255]));
+    }
+
+    #[test]
+    fn blends_towards_the_ink_color() {
+        let navy = InkRecolor::new(Color::new(20, 30, 80), InkDetection::default());
+        let blend = |mut pixel: [u8; 4], strength| {
+            navy.blend(&mut pixel, strength);
+            pixel
+        };
+
+        assert_eq!(blend([255, 255, 255, 255], 1.0), [80, 30, 20, 255]);
+        assert_eq!(blend([255, 255, 255, 255], 0.0), [255, 255, 255, 255]);
+        assert_eq!(blend([200, 100, 0, 255], 0.5), [140, 65, 10, 255]);
+    }
+}
@@ -0,0 +1,451 @@
+//! Transforming PDFs in the background, for documents that take longer than a request is allowed
+//! to.
+//!
+//! Submitted jobs wait in a queue of limited length for one of a fixed number of workers, and
+//! report their progress as they run. Finished PDFs are written to the temp directory and removed
+//! once they expire.
+
+use crate::pdf_to_pdf::{self, Update};
+use crate::{TransformationError, TransformationStateOptions};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+/// The least time idle workers sleep between expiring jobs, however short the ttl
+const MIN_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy)]
+pub struct JobsOptions {
+    /// How many jobs run at once
+    pub workers: usize,
+    /// How many jobs can wait for a worker before new ones are refused
+    pub max_queued: usize,
+    /// How long finished jobs are kept, from when they finish. It should leave time to fetch
+    /// the result, zero expires jobs as soon as anything looks at them.
+    pub ttl: Duration,
+}
+
+impl Default for JobsOptions {
+    fn default() -> Self {
+        JobsOptions {
+            workers: 2,
+            max_queued: 16,
+            ttl: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct JobId(Uuid);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for JobId {
+    type Err = uuid::Error;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        src.parse().map(JobId)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum JobStatus {
+    Queued {
+        /// How many jobs will start before this one
+        position: usize,
+    },
+    Running {
+        percent_done: f64,
+        /// Zero indexed, None until the document is opened
+        page: Option<usize>,
+    },
+    Done,
+    Failed(Arc<TransformationError>),
+    Cancelled,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum JobError {
+    #[error("Too many jobs are waiting, try again later")]
+    QueueFull,
+
+    #[error("There is no job {0}, it may have expired")]
+    NotFound(JobId),
+
+    #[error("Job {0} has no result, it hasn't finished or it failed")]
+    NoResult(JobId),
+}
+
+/// Runs jobs until dropped. Dropping cancels every job and removes their results.
+#[derive(Debug)]
+pub struct Jobs {
+    shared: Arc<Shared>,
+    options: JobsOptions,
+    workers: Vec<JoinHandle<()>>,
+}
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Notified when a job is queued or we're shutting down
+    wake: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    jobs: HashMap<JobId, Job>,
+    queue: VecDeque<JobId>,
+    shutting_down: bool,
+}
+
+#[derive(Debug)]
+struct Job {
+    status: JobStatus,
+    /// Taken by the worker that runs it
+    input: Option<(Vec<u8>, TransformationStateOptions)>,
+    /// The transformed PDF, removed with the job
+    output: Option<PathBuf>,
+    /// Tells the worker to stop after the page it's on
+    cancelled: bool,
+    finished: Option<Instant>,
+}
+
+impl Jobs {
+    pub fn new(options: JobsOptions) -> Jobs {
+        let shared = Arc::new(Shared::default());
+        let workers = (0..options.workers.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                thread::spawn(move || work(&shared, options.ttl))
+            })
+            .collect();
+
+        Jobs {
+            shared,
+            options,
+            workers,
+        }
+    }
+
+    pub fn submit(
+        &self,
+        in_blob: Vec<u8>,
+        options: TransformationStateOptions,
+    ) -> Result<JobId, JobError> {
+        let mut state = self.lock();
+        if state.queue.len() >= self.options.max_queued {
+            return Err(JobError::QueueFull);
+        }
+        let id = JobId(Uuid::new_v4());
+        state.jobs.insert(
+            id,
+            Job {
+                status: JobStatus::Queued { position: 0 },
+                input: Some((in_blob, options)),
+                output: None,
+                cancelled: false,
+                finished: None,
+            },
+        );
+        state.queue.push_back(id);
+        self.shared.wake.notify_one();
+        Ok(id)
+    }
+
+    pub fn status(&self, id: JobId) -> Result<JobStatus, JobError> {
+        let state = self.lock();
+        let job = state.jobs.get(&id).ok_or(JobError::NotFound(id))?;
+        Ok(match job.status {
+            JobStatus::Queued { .. } => JobStatus::Queued {
+                position: state
+                    .queue
+                    .iter()
+                    .position(|queued| *queued == id)
+                    .unwrap_or(0),
+            },
+            ref status => status.clone(),
+        })
+    }
+
+    /// The transformed PDF, once the job is done
+    pub fn result(&self, id: JobId) -> Result<File, JobError> {
+        let state = self.lock();
+        let job = state.jobs.get(&id).ok_or(JobError::NotFound(id))?;
+        match (&job.status, &job.output) {
+            (JobStatus::Done, Some(path)) => File::open(path).map_err(|_| JobError::NoResult(id)),
+            _ => Err(JobError::NoResult(id)),
+        }
+    }
+
+    /// A running job stops after the page it's on. Cancelling a job that's done removes its
+    /// result, while failed jobs keep their error until they expire.
+    pub fn cancel(&self, id: JobId) -> Result<(), JobError> {
+        let mut state = self.lock();
+        state.queue.retain(|queued| *queued != id);
+        let job = state.jobs.get_mut(&id).ok_or(JobError::NotFound(id))?;
+        match job.status {
+            JobStatus::Running { .. } => job.cancelled = true,
+            JobStatus::Failed(_) | JobStatus::Cancelled => {}
+            _ => job.finish(JobStatus::Cancelled, None),
+        }
+        Ok(())
+    }
+
+    /// Also removes expired jobs, so they don't wait for a worker to be idle
+    fn lock(&self) -> MutexGuard<'_, State> {
+        let mut state = self.shared.lock();
+        state.expire(self.options.ttl);
+        state
+    }
+}
+
+impl Drop for Jobs {
+    fn drop(&mut self) {
+        self.shared.lock().shutting_down = true;
+        self.shared.wake.notify_all();
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // A panic while it's locked leaves the state usable
+        self.state.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+impl State {
+    fn expire(&mut self, ttl: Duration) {
+        let now = Instant::now();
+        self.jobs.retain(|_, job| match job.finished {
+            Some(finished) => now.duration_since(finished) < ttl,
+            None => true,
+        });
+    }
+}
+
+impl Job {
+    fn finish(&mut self, status: JobStatus, output: Option<PathBuf>) {
+        self.status = status;
+        self.input = None;
+        self.remove_output();
+        self.output = output;
+        self.finished.get_or_insert_with(Instant::now);
+    }
+
+    fn remove_output(&mut self) {
+        if let Some(path) = self.output.take() {
+            fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.remove_output();
+    }
+}
+
+/// Run queued jobs until we're shutting down
+fn work(shared: &Shared, ttl: Duration) {
+    loop {
+        let (id, in_blob, options) = {
+            let mut state = shared.lock();
+            loop {
+                if state.shutting_down {
+                    return;
+                }
+                state.expire(ttl);
+                if let Some(id) = state.queue.pop_front() {
+                    let job = state.jobs.get_mut(&id);
+                    if let Some((job, (in_blob, options))) =
+                        job.and_then(|job| job.input.take().map(|input| (job, input)))
+                    {
+                        job.status = JobStatus::Running {
+                            percent_done: 0.0,
+                            page: None,
+                        };
+                        break (id, in_blob, options);
+                    }
+                    continue;
+                }
+                // Waking up now and then expires jobs even if nobody is polling
+                let interval = ttl.max(MIN_EXPIRY_INTERVAL);
+                state = match shared.wake.wait_timeout(state, interval) {
+                    Ok((state, _)) => state,
+                    Err(err) => err.into_inner().0,
+                };
+            }
+        };
+
+        // A panic fails the job instead of taking the worker with it
+        let mut output = None;
+        let status = panic::catch_unwind(AssertUnwindSafe(|| {
+            run(shared, id, in_blob, options, &mut output)
+        }))
+        .unwrap_or_else(|_| JobStatus::Failed(Arc::new(TransformationError::Unknown)));
+        if !matches!(status, JobStatus::Done) {
+            if let Some(path) = output.take() {
+                fs::remove_file(path).ok();
+            }
+        }
+
+        match shared.lock().jobs.get_mut(&id) {
+            Some(job) => job.finish(status, output),
+            None => {
+                if let Some(path) = output {
+                    fs::remove_file(path).ok();
+                }
+            }
+        }
+    }
+}
+
+/// Transform into a temp file, recording progress and checking if we've been cancelled between
+/// pages. output is set to the temp file as soon as it exists, for the caller to keep or remove.
+fn run(
+    shared: &Shared,
+    id: JobId,
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+    output: &mut Option<PathBuf>,
+) -> JobStatus {
+    let mut progress = match pdf_to_pdf::transform_to_temp_file(in_blob, options) {
+        Ok((progress, path)) => {
+            *output = Some(path);
+            progress
+        }
+        Err(err) => return JobStatus::Failed(Arc::new(err)),
+    };
+
+    loop {
+        {
+            let mut state = shared.lock();
+            let shutting_down = state.shutting_down;
+            match state.jobs.get_mut(&id) {
+                Some(job) if !job.cancelled && !shutting_down => {
+                    job.status = JobStatus::Running {
+                        percent_done: progress.percent_done(),
+                        page: progress.current_page(),
+                    };
+                }
+                _ => return JobStatus::Cancelled,
+            }
+        }
+
+        match progress.next() {
+            Update::Progress(next) => progress = next,
+            Update::Complete(Ok(_)) => return JobStatus::Done,
+            Update::Complete(Err(err)) => return JobStatus::Failed(Arc::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Quality;
+    use std::io::Read;
+
+    fn get_in_blob() -> Vec<u8> {
+        include_bytes!("../test_assets/multipage_test.pdf").to_vec()
+    }
+
+    fn options() -> TransformationStateOptions {
+        TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            ..TransformationStateOptions::default()
+        }
+    }
+
+    fn wait_until_finished(jobs: &Jobs, id: JobId) -> JobStatus {
+        loop {
+            match jobs.status(id).unwrap() {
+                JobStatus::Queued { .. } | JobStatus::Running { .. } => {
+                    thread::sleep(Duration::from_millis(10))
+                }
+                status => return status,
+            }
+        }
+    }
+
+    #[test]
+    fn runs_jobs_in_the_background() {
+        let jobs = Jobs::new(JobsOptions::default());
+        let id = jobs.submit(get_in_blob(), options()).unwrap();
+
+        assert!(matches!(wait_until_finished(&jobs, id), JobStatus::Done));
+        let mut pdf = Vec::new();
+        jobs.result(id).unwrap().read_to_end(&mut pdf).unwrap();
+        assert!(pdf.starts_with(b"%PDF"));
+
+        let failed = jobs.submit(b"not a pdf".to_vec(), options()).unwrap();
+        assert!(matches!(
+            wait_until_finished(&jobs, failed),
+            JobStatus::Failed(_)
+        ));
+        assert_eq!(jobs.result(failed).err(), Some(JobError::NoResult(failed)));
+
+        // Cancelling a failed job doesn't hide why it failed
+        jobs.cancel(failed).unwrap();
+        assert!(matches!(jobs.status(failed).unwrap(), JobStatus::Failed(_)));
+    }
+
+    #[test]
+    fn limits_the_queue() {
+        let jobs = Jobs::new(JobsOptions {
+            workers: 1,
+            max_queued: 1,
+            ..JobsOptions::default()
+        });
+        let running = jobs.submit(get_in_blob(), options()).unwrap();
+        // Make sure the worker has taken the first job off the queue
+        while let JobStatus::Queued { .. } = jobs.status(running).unwrap() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let queued = jobs.submit(get_in_blob(), options()).unwrap();
+
+        assert_eq!(
+            jobs.submit(get_in_blob(), options()).err(),
+            Some(JobError::QueueFull)
+        );
+        assert!(matches!(
+            jobs.status(queued).unwrap(),
+            JobStatus::Queued { position: 0 }
+        ));
+
+        jobs.cancel(queued).unwrap();
+        assert!(matches!(jobs.status(queued).unwrap(), JobStatus::Cancelled));
+        jobs.submit(get_in_blob(), options()).unwrap();
+    }
+
+    #[test]
+    fn expires_finished_jobs() {
+        let jobs = Jobs::new(JobsOptions {
+            ttl: Duration::from_millis(200),
+            ..JobsOptions::default()
+        });
+        let id = jobs.submit(get_in_blob(), options()).unwrap();
+        wait_until_finished(&jobs, id);
+        let path = jobs.lock().jobs[&id].output.clone().unwrap();
+        assert!(path.exists());
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(jobs.status(id).err(), Some(JobError::NotFound(id)));
+        assert!(!path.exists());
+    }
+}
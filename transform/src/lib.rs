@@ -1,17 +1,48 @@
-use cairo::{Context, Format, ImageSurface, ImageSurfaceData, Operator};
-use image::{DynamicImage, ImageBuffer, ImageOutputFormat};
+use background::{BackgroundDetection, Classifier, DeltaE2000, Metric, QueenWise};
+use cairo::{Context, Format, ImageSurface, Operator};
+use encoding::Encoding;
+use encryption::{Encryption, OutputEncryption, Permissions, SourceEncryption};
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use ink::{InkDetection, InkRecolor};
+use night::Night;
 use poppler::{PopplerDocument, PopplerPage};
-use printpdf;
+use protect::{ProtectedAreas, Protection};
 use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::io::Write;
+use streaming::StreamingPdf;
+use text_layer::TextLayer;
 use thiserror::Error;
-
+use tiles::{Tile, TILE_SIDE};
+
+pub use metadata::Metadata;
+pub use pages::{InvalidPageSelection, PageSelection};
+
+pub mod background;
+mod budget;
+pub mod encoding;
+pub mod encryption;
+pub mod ink;
+pub mod jobs;
+mod links;
+mod metadata;
+mod night;
+mod pages;
 pub mod pdf_to_images;
 pub mod pdf_to_pdf;
+mod pool;
+mod poppler_ext;
+pub mod protect;
+mod streaming;
+mod text_layer;
+mod tiles;
+mod vector;
 
 // Pixels are little-endian (b, g, r, a) to match Cairo & Poppler
 type LittleEndianRgbPixel<T> = [T; 3];
-const WHITE_PIXEL: LittleEndianRgbPixel<i16> = [255, 255, 255];
 const RGBA_PIXEL_SIZE: usize = 4;
+/// Pages are rendered this much smaller than their size in points (see `Pt::to_px`)
+const IMAGE_SCALE: f64 = 0.996264;
 
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Color {
@@ -40,9 +71,6 @@ const DEFAULT_BACKGROUND_COLOR: Color = Color {
 
 // TODO: figure out licensing b/c/o gpl and what it applies to
 
-// TODO: overlay text back on so you can copy/paste if you could before
-// A decent example is https://docs.fcc.gov/public/attachments/DOC-363399A1.pdf
-
 // TODO: consider adding build stage to cairo-rs that pulls in docs
 
 #[derive(Error, Debug)]
@@ -65,14 +93,26 @@ pub enum TransformationError {
     #[error("Insufficient memory.")]
     InsufficientMemory,
 
-    #[error("Error writing the transformed pages with printpdf")]
-    PdfWrite(#[from] printpdf::errors::Error),
-
     #[error("PDF has zero pages")]
     ZeroPagePdf,
 
+    #[error("Invalid quality {0:?}, resolutions and pixel counts must be positive")]
+    InvalidQuality(Quality),
+
+    #[error("The PDF is encrypted and needs a password")]
+    PasswordRequired,
+
+    #[error("The password for the PDF is incorrect")]
+    IncorrectPassword,
+
     #[error("Error outputting the transformed page as an image")]
     ImageEncoding(#[from] image::error::ImageError),
+
+    #[error("Error reading or writing the structure of the PDF (with lopdf)")]
+    Structure(#[from] lopdf::Error),
+
+    #[error("Error writing the output")]
+    Output(#[from] std::io::Error),
 }
 
 impl From<cairo::Status> for TransformationError {
@@ -83,11 +123,135 @@ impl From<cairo::Status> for TransformationError {
 
 pub type Result<T> = std::result::Result<T, TransformationError>;
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-struct TransformationStateOptions {
-    quality: Quality,
-    background_color: Color,
-    page_range: PageRange,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransformationStateOptions {
+    pub quality: Quality,
+    pub background_color: Color,
+    /// If None the entire document is transformed
+    pub pages: Option<PageSelection>,
+    pub mode: Mode,
+    pub background_detection: BackgroundDetection,
+    pub strategy: Strategy,
+    /// If set, ink is recolored to this. With `Strategy::Night` it is the color ink ends up
+    /// after inverting.
+    pub foreground_color: Option<Color>,
+    pub ink_detection: InkDetection,
+    pub protection: Protection,
+    /// How many pages to transform at once. Every worker holds its own copy of the document.
+    pub workers: usize,
+    /// In bytes. If set, output PDFs pick the resolution and image encoding of each page to stay
+    /// under this size and quality is ignored. encoding is kept while the budget allows a
+    /// comfortable resolution. Pages are transformed one at a time, whatever the workers.
+    pub target_size: Option<u64>,
+    /// How page images are stored in output PDFs
+    pub encoding: Encoding,
+    /// Settings for pages that differ from the rest of the document. Where overrides
+    /// overlap the first one wins.
+    pub overrides: Vec<PageOverride>,
+    /// For encrypted documents. Output PDFs only keep the links and metadata of documents
+    /// encrypted with RC4, see `Complete::copied_structure`.
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    /// Output PDFs only
+    pub encryption: OutputEncryption,
+}
+
+impl Default for TransformationStateOptions {
+    fn default() -> Self {
+        TransformationStateOptions {
+            quality: Quality::Normal,
+            background_color: DEFAULT_BACKGROUND_COLOR,
+            pages: None,
+            mode: Mode::default(),
+            background_detection: BackgroundDetection::default(),
+            strategy: Strategy::default(),
+            foreground_color: None,
+            ink_detection: InkDetection::default(),
+            protection: Protection::default(),
+            workers: 1,
+            target_size: None,
+            encoding: Encoding::default(),
+            overrides: Vec::new(),
+            password: None,
+            encryption: OutputEncryption::default(),
+        }
+    }
+}
+
+/// Options for some of the pages of a document. Unset options are taken from the document's.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PageOverride {
+    pub pages: PageSelection,
+    #[serde(default)]
+    pub background_color: Option<Color>,
+    /// Ignored with a target size, like the document's quality
+    #[serde(default)]
+    pub quality: Option<Quality>,
+    /// Copy the pages into output PDFs unmodified, for covers and photo plates. Pages are still
+    /// rendered for images, just not recolored.
+    #[serde(default)]
+    pub pass_through: bool,
+}
+
+/// How pages are written into an output PDF
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    /// Render every page to a bitmap and tint the background pixels
+    Raster,
+    /// Keep each page's original content and paint the background color underneath it.
+    /// Pages whose content can't be safely rewritten fall back to `Raster`.
+    Vector,
+}
+
+impl Default for Mode {
+    fn default() -> Self {
+        Mode::Raster
+    }
+}
+
+/// How background pixels of a rendered page are recolored
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Strategy {
+    /// Replace background pixels with the background color and leave everything else alone
+    Replace,
+    /// Treat each pixel's distance from the paper color as how much of it is covered by ink
+    /// and blend between the background color and the ink. Avoids halos around anti-aliased
+    /// edges, at the cost of tinting light colors. Ignores the tolerance.
+    Blend,
+    /// For night reading. Invert the lightness of every pixel, keeping its hue and chroma, so
+    /// pages become light on dark. If tint is set the dark background is tinted with a
+    /// darkened background color. Pages are always rasterized, even in `Mode::Vector`.
+    Night { tint: bool },
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Replace
+    }
+}
+
+/// The path a page actually took into the output
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub enum Rendering {
+    Raster,
+    Vector,
+    /// Passed through with its original content
+    Original,
+    /// Rasterized although the options asked to keep its content, because the document's
+    /// structure couldn't be read. That's the case for documents encrypted with AES, which we
+    /// can't decrypt.
+    RasterFallback,
+}
+
+/// The settings a page was written into the output with
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct PageSettings {
+    /// The resolution the page was rendered at. None for pages that kept their original
+    /// content.
+    pub ppi: Option<f64>,
+    /// How the page image was stored, never `Encoding::Auto`. None for pages that kept their
+    /// original content.
+    pub encoding: Option<Encoding>,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
@@ -96,7 +260,13 @@ pub enum Quality {
     High,
     Normal,
     Low,
+    /// The Chrome extension calls this ExtraLow
+    #[serde(alias = "ExtraLow")]
     ExtremeLow,
+    /// Render at this many pixels per inch
+    Custom(f64),
+    /// Pick the resolution of each page so it has about this many pixels, whatever its size
+    MaxPixels(u64),
 }
 
 impl Quality {
@@ -112,6 +282,7 @@ impl Quality {
     }
 }
 
+/// A single span of pages. See `PageSelection` for anything else.
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct PageRange {
     /// Zero indexed, if starting_index is greater than the index of the last page
@@ -121,38 +292,33 @@ pub struct PageRange {
     pub count: usize,
 }
 
-impl PageRange {
-    fn includes(&self, offset: usize, pages_in_doc: usize) -> bool {
-        if offset >= self.count {
-            return false;
-        }
-
-        if offset + self.starting_index >= pages_in_doc {
-            return false;
-        }
-
-        return true;
-    }
-}
-
 #[derive(Debug)]
 pub struct TransformationState {
     /// The document to transform
     doc: TransformationStateDoc,
     /// How to transform
     options: TransformationStateOptions,
+    /// The zero indexed page numbers to transform, resolved against the document. Offsets
+    /// index into this.
+    pages: Vec<usize>,
 }
 
 #[derive(Debug)]
 pub struct TransformationStateDoc {
     original_title: String,
     poppler: PopplerDocument,
+    /// A second handle on the document for what the poppler crate doesn't expose. None if it
+    /// couldn't be opened, in which case we skip the features that need it.
+    raw: Option<poppler_ext::RawDocument>,
     page_count: usize,
     // We get a segfault if we try to read the document without keeping it around
     // TODO: Figure out why the rust bindings for poppler allow us to get a segfault
     // and file an issue / fix it.
     #[allow(dead_code)]
     bytes: Vec<u8>,
+    /// The parsed document structure. Only loaded when we need to read or copy the original
+    /// content, and None if lopdf couldn't parse the document.
+    structure: Option<lopdf::Document>,
 }
 
 impl TransformationState {
@@ -165,18 +331,50 @@ impl TransformationState {
     }
 
     pub fn includes_offset(&self, offset: usize) -> bool {
-        self.options
-            .page_range
-            .includes(offset, self.doc.page_count)
+        offset < self.pages.len()
     }
 
+    /// A `PageRange` can be passed as selected_pages with `Some(range.into())`
     pub fn try_new(
-        mut in_blob: Vec<u8>,
-        selected_page_range: Option<PageRange>,
+        in_blob: Vec<u8>,
+        selected_pages: Option<PageSelection>,
         quality: Quality,
         background_color: Option<Color>,
     ) -> Result<TransformationState> {
-        let poppler = PopplerDocument::new_from_data(&mut in_blob, "")?;
+        let options = TransformationStateOptions {
+            quality,
+            background_color: background_color.unwrap_or(DEFAULT_BACKGROUND_COLOR),
+            pages: selected_pages,
+            ..TransformationStateOptions::default()
+        };
+
+        TransformationState::try_new_with_options(in_blob, options)
+    }
+
+    pub fn try_new_with_options(
+        mut in_blob: Vec<u8>,
+        options: TransformationStateOptions,
+    ) -> Result<TransformationState> {
+        let qualities = options.overrides.iter().filter_map(|o| o.quality);
+        if let Some(quality) = qualities
+            .chain(Some(options.quality))
+            .find(|quality| !quality.is_valid())
+        {
+            return Err(TransformationError::InvalidQuality(quality));
+        }
+
+        let password = options.password.clone().unwrap_or_default();
+        let poppler = PopplerDocument::new_from_data(&mut in_blob, &password).map_err(|err| {
+            match err.kind::<poppler_ext::PopplerError>() {
+                Some(poppler_ext::PopplerError::Encrypted) if password.is_empty() => {
+                    TransformationError::PasswordRequired
+                }
+                Some(poppler_ext::PopplerError::Encrypted) => {
+                    TransformationError::IncorrectPassword
+                }
+                _ => TransformationError::Render(err),
+            }
+        })?;
         let page_count = poppler.get_n_pages();
         let original_title = poppler.get_title().unwrap_or("".into());
 
@@ -184,104 +382,434 @@ impl TransformationState {
             return Err(TransformationError::ZeroPagePdf);
         }
 
-        let page_range = selected_page_range.unwrap_or_else(|| PageRange {
-            starting_index: 0,
-            count: page_count,
-        });
+        let raw = poppler_ext::RawDocument::new(&mut in_blob, &password);
 
-        let background_color = background_color.unwrap_or(DEFAULT_BACKGROUND_COLOR);
+        let pages = match &options.pages {
+            Some(selection) => selection.resolve(page_count),
+            None => (0..page_count).collect(),
+        };
 
-        let options = TransformationStateOptions {
-            background_color,
-            page_range,
-            quality,
+        // If lopdf can't parse the document every page falls back to the raster path, and no
+        // figures are protected
+        let needs_structure = options.mode == Mode::Vector
+            || options.protection == Protection::ImagesAndFigures
+            || options.overrides.iter().any(|o| o.pass_through);
+        let structure = if needs_structure {
+            load_structure(&in_blob, &password)
+        } else {
+            None
         };
 
         let doc = TransformationStateDoc {
             original_title,
             poppler,
+            raw,
             page_count,
             bytes: in_blob,
+            structure,
+        };
+
+        Ok(TransformationState {
+            doc,
+            options,
+            pages,
+        })
+    }
+
+    /// How many pages are selected
+    pub fn included_page_count(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Transform the selected pages on options.workers threads, if there's more than one
+    fn pool<T: Send + 'static>(
+        &self,
+        work: fn(&TransformationState, usize) -> Result<T>,
+    ) -> Option<pool::Pool<T>> {
+        // With a target size each page depends on the size of the ones before it
+        if self.options.workers <= 1 || self.options.target_size.is_some() {
+            return None;
+        }
+        Some(pool::Pool::new(
+            &self.doc.bytes,
+            self.options.clone(),
+            self.options.workers,
+            self.included_page_count(),
+            work,
+        ))
+    }
+
+    fn page_num(&self, offset: usize) -> Result<usize> {
+        self.pages
+            .get(offset)
+            .copied()
+            .ok_or(TransformationError::NonexistentPage(offset))
+    }
+
+    /// The first override that applies to a page
+    fn page_override(&self, page_num: usize) -> Option<&PageOverride> {
+        self.options
+            .overrides
+            .iter()
+            .find(|o| o.pages.contains(page_num, self.doc.page_count))
+    }
+
+    fn quality(&self, page_num: usize) -> Quality {
+        self.page_override(page_num)
+            .and_then(|o| o.quality)
+            .unwrap_or(self.options.quality)
+    }
+
+    fn background_color(&self, page_num: usize) -> Color {
+        self.page_override(page_num)
+            .and_then(|o| o.background_color)
+            .unwrap_or(self.options.background_color)
+    }
+
+    fn passes_through(&self, page_num: usize) -> bool {
+        self.page_override(page_num)
+            .map_or(false, |o| o.pass_through)
+    }
+
+    /// How to encrypt output PDFs, if at all
+    fn output_encryption(&self) -> Option<Encryption> {
+        match &self.options.encryption {
+            OutputEncryption::MatchSource => {
+                let password = self.options.password.as_deref().unwrap_or("");
+                // If we can't tell, we only know whether it needed a password
+                let granted = self
+                    .doc
+                    .raw
+                    .as_ref()
+                    .map(|raw| raw.permissions())
+                    .unwrap_or_default();
+                if password.is_empty() && granted == Permissions::default() {
+                    return None;
+                }
+
+                // Reading the source's handler means parsing the whole document, so we only do
+                // it for sources that were protected
+                let source = lopdf::Document::load_mem(&self.doc.bytes).ok();
+                let source = source.as_ref().and_then(SourceEncryption::read);
+                Encryption::matching(source.as_ref(), password, granted)
+            }
+            OutputEncryption::Unencrypted => None,
+            OutputEncryption::Encrypted(encryption) => Some(encryption.clone()),
+        }
+    }
+
+    /// The width and height of a page in points
+    fn page_points(&self, offset: usize) -> Result<(f64, f64)> {
+        let page_num = self.page_num(offset)?;
+        let page = self
+            .doc
+            .poppler
+            .get_page(page_num)
+            .ok_or(TransformationError::Unknown)?;
+        Ok(page.get_size())
+    }
+
+    /// Transform a page for inclusion in an output PDF, keeping the original content if the
+    /// mode and the page allow it
+    fn transform_page_for_pdf(&self, offset: usize) -> Result<OutputPage> {
+        let page_num = self.page_num(offset)?;
+        self.transform_page_for_pdf_at(offset, self.quality(page_num))
+    }
+
+    /// Like `transform_page_for_pdf`, at a quality other than the one in the options
+    fn transform_page_for_pdf_at(&self, offset: usize, quality: Quality) -> Result<OutputPage> {
+        let page_num = self.page_num(offset)?;
+        let size = || -> Result<PageSize> {
+            let page = self
+                .doc
+                .poppler
+                .get_page(page_num)
+                .ok_or(TransformationError::Unknown)?;
+            Ok(PageSize::from(&page, quality))
         };
 
-        Ok(TransformationState { doc, options })
+        // Falls back to a render without recoloring if the content can't be read
+        if self.passes_through(page_num) {
+            if let Some(content_len) = self
+                .doc
+                .structure
+                .as_ref()
+                .and_then(|structure| vector::original_content_len(structure, page_num))
+            {
+                return Ok(OutputPage::Vector(vector::VectorPage::original(
+                    page_num,
+                    size()?,
+                    content_len,
+                )));
+            }
+        }
+
+        // Night needs every pixel, so the original content can't be kept
+        let mode = match self.options.strategy {
+            Strategy::Night { .. } => Mode::Raster,
+            _ => self.options.mode,
+        };
+        if let (Mode::Vector, Some(structure)) = (mode, &self.doc.structure) {
+            if let Some(content) =
+                vector::rewrite_page(structure, page_num, self.background_color(page_num))
+            {
+                return Ok(OutputPage::Vector(vector::VectorPage::new(
+                    page_num,
+                    size()?,
+                    content,
+                )));
+            }
+        }
+
+        let mut page = self.transform_page_at(offset, quality)?;
+        page.fell_back =
+            (mode == Mode::Vector || self.passes_through(page_num)) && self.doc.structure.is_none();
+        // A page without text gets no text layer
+        page.text_layer = self
+            .doc
+            .raw
+            .as_ref()
+            .and_then(|raw| raw.page(page_num))
+            .and_then(|page| TextLayer::extract(&page))
+            .filter(|layer| !layer.is_empty());
+        Ok(OutputPage::Raster(page))
     }
 
     pub fn transform_page(&self, offset: usize) -> Result<TransformedPage> {
+        let page_num = self.page_num(offset)?;
+        self.transform_page_at(offset, self.quality(page_num))
+    }
+
+    /// Like `transform_page`, at a quality other than the one in the options
+    fn transform_page_at(&self, offset: usize, quality: Quality) -> Result<TransformedPage> {
         let options = &self.options;
         let doc = &self.doc;
 
-        let page_num = options.page_range.starting_index + offset;
-        if page_num > doc.page_count - 1 {
-            return Err(TransformationError::NonexistentPage(page_num));
-        }
+        let page_num = self.page_num(offset)?;
 
         let page = doc
             .poppler
             .get_page(page_num)
             .ok_or(TransformationError::Unknown)?;
 
-        let size = PageSize::from(&page, options.quality);
-
-        let mut page_surface = render_poppler_page(&page, size)?;
-
-        // Panics with runtime borrow error if refs to the page surface exist.
-        // We don't make any except for in render_poppler_page, and we drop it at the
-        // end of that function
-        let mut img_data = page_surface
-            .get_data()
-            .map_err(|_| TransformationError::Unknown)?;
-
-        transform_page_data(&mut img_data, options.background_color.into());
-
-        let image = page_data_to_pdf_image(&img_data, size)?;
-
-        Ok(TransformedPage { image, size })
-    }
-
-    fn to_pdf(self, pages: Vec<TransformedPage>) -> Result<Vec<u8>> {
-        use printpdf::{Image, PdfDocument};
-        use std::io::BufWriter;
-
-        let doc = PdfDocument::empty(self.doc.original_title);
-        for TransformedPage { image, size } in pages {
-            // TODO: somewhere the math here is probably slightly wrong, because the
-            // pages are slightly too large
-            let (page, layer) = doc.add_page(
-                size.width_to_mm().into(),
-                size.height_to_mm().into(),
-                "img_layer",
-            );
-            let page = doc.get_page(page);
-            let layer = page.get_layer(layer);
-
-            let image = Image::from_dynamic_image(&image);
-            // NOTE: I'm pretty sure printpdf confuses DPI with PPI. The argument name is dpi but I
-            // believe it's interpreted as if it is PPI
-            image.add_to_layer(
-                layer.clone(),
-                None,
-                None,
-                None,
-                None,
-                None,
-                Some(size.ppi.as_f64()),
-            );
+        let size = PageSize::from(&page, quality);
+        let (width, height) = (size.width_to_px().as_u32(), size.height_to_px().as_u32());
+        // Most pages fit in one tile, posters and drawings at high quality don't
+        let tiles = Tile::covering(width, height);
+
+        let BackgroundDetection {
+            metric,
+            paper_color: configured_paper_color,
+            tolerance,
+            adaptive,
+        } = options.background_detection;
+        // Estimated from the first tile if the page fits in one, otherwise from a smaller
+        // render of the whole page
+        let mut paper_color = if !adaptive {
+            Some(configured_paper_color)
+        } else if tiles.len() > 1 {
+            Some(estimate_paper_color(&page, size)?.unwrap_or(configured_paper_color))
+        } else {
+            None
+        };
+
+        let ink = options
+            .foreground_color
+            .map(|color| InkRecolor::new(color, options.ink_detection));
+        let raw_page = doc.raw.as_ref().and_then(|raw| raw.page(page_num));
+        let protected = ProtectedAreas::find(
+            options.protection,
+            raw_page.as_ref(),
+            doc.structure.as_ref(),
+            page_num,
+        );
+        let scale_factor = Pt::new(1.0, size.ppi).to_px().as_f64();
+        let background_color = self.background_color(page_num);
+        let pass_through = self.passes_through(page_num);
+
+        let mut image = RgbImage::new(width, height);
+        for tile in tiles {
+            let mut tile_surface = render_poppler_page(&page, size, tile)?;
+
+            // Panics with runtime borrow error if refs to the tile surface exist.
+            // We don't make any except for in render_poppler_page, and we drop it at the
+            // end of that function
+            let mut img_data = tile_surface
+                .get_data()
+                .map_err(|_| TransformationError::Unknown)?;
+
+            let paper_color = *paper_color.get_or_insert_with(|| {
+                background::estimate_paper_color(&img_data).unwrap_or(configured_paper_color)
+            });
+            let recolor = Recolor {
+                strategy: options.strategy,
+                background_color: background_color.into(),
+                paper_color: paper_color.into(),
+            };
+            if !pass_through {
+                protected.preserve(&mut img_data, tile, scale_factor, |img_data| match metric {
+                    Metric::QueenWise => transform_page_data(
+                        img_data,
+                        recolor,
+                        ink.as_ref(),
+                        &QueenWise::new(paper_color, tolerance),
+                    ),
+                    Metric::DeltaE2000 => transform_page_data(
+                        img_data,
+                        recolor,
+                        ink.as_ref(),
+                        &DeltaE2000::new(paper_color, tolerance),
+                    ),
+                });
+            }
+
+            tile.copy_into(&img_data, &mut image);
+        }
+        let image = DynamicImage::ImageRgb8(image);
+        // Only unset if the page has no pixels at all
+        let paper_color = paper_color.unwrap_or(configured_paper_color);
+
+        Ok(TransformedPage {
+            image,
+            size,
+            page_num,
+            paper_color,
+            text_layer: None,
+            fell_back: false,
+        })
+    }
+
+    /// Write the end of a streamed PDF. Returns the sink and the metadata of the source
+    /// document, which is None if its structure couldn't be read.
+    fn finish_stream<W: Write>(self, stream: StreamingPdf<W>) -> Result<(W, Option<Metadata>)> {
+        let TransformationStateDoc {
+            structure, bytes, ..
+        } = self.doc;
+
+        let password = self.options.password.unwrap_or_default();
+        let source = structure.or_else(|| load_structure(&bytes, &password));
+        let page_nums = stream.page_nums().to_vec();
+        let sink = stream.finish(|out, vector_pages| match &source {
+            Some(source) => copy_structure(out, source, vector_pages, &page_nums),
+            None => Ok(()),
+        })?;
+
+        let metadata = source.map(|source| Metadata::read(&source, &page_nums));
+        Ok((sink, metadata))
+    }
+}
+
+/// None if lopdf can't parse the document, or it's encrypted and we can't decrypt it. Their
+/// pages are rasterized (see `Rendering::RasterFallback`) and their links, outline and metadata
+/// aren't copied (see `Complete::copied_structure`).
+///
+/// Encrypted documents are decrypted with password, if they're encrypted with RC4. Not if
+/// they're encrypted with AES, or keep objects in object streams, which lopdf drops when it
+/// can't decompress them.
+fn load_structure(bytes: &[u8], password: &str) -> Option<lopdf::Document> {
+    let mut doc = lopdf::Document::load_mem(bytes).ok()?;
+    if doc.trailer.get(b"Encrypt").is_err() {
+        return Some(doc);
+    }
+    let decrypted = SourceEncryption::read(&doc)?.decrypt(&mut doc, password);
+    let compressed = doc
+        .reference_table
+        .entries
+        .values()
+        .any(|entry| entry.is_compressed());
+    if decrypted && !compressed {
+        Some(doc)
+    } else {
+        None
+    }
+}
+
+/// Copy what we can't write ourselves from the source: the original content of vector pages,
+/// links and metadata. page_nums is the source page number of each page of out.
+fn copy_structure(
+    out: &mut lopdf::Document,
+    source: &lopdf::Document,
+    vector_pages: Vec<(usize, vector::VectorPage)>,
+    page_nums: &[usize],
+) -> Result<()> {
+    let mut imported = BTreeMap::new();
+    let vector_indices = vector_pages.iter().map(|(index, _)| *index).collect();
+    vector::splice(out, source, vector_pages, &mut imported)?;
+    links::copy(out, source, page_nums, &vector_indices, &mut imported)?;
+    metadata::copy(out, source, page_nums, &mut imported)
+}
+
+/// A page ready to be written into an output PDF
+enum OutputPage {
+    Raster(TransformedPage),
+    Vector(vector::VectorPage),
+}
+
+impl OutputPage {
+    /// Zero indexed page number in the source document
+    fn page_num(&self) -> usize {
+        match self {
+            OutputPage::Raster(page) => page.page_num,
+            OutputPage::Vector(page) => page.page_num(),
         }
+    }
+
+    /// None for pages that kept their original content
+    fn paper_color(&self) -> Option<Color> {
+        match self {
+            OutputPage::Raster(page) => Some(page.paper_color),
+            OutputPage::Vector(_) => None,
+        }
+    }
 
-        let mut blob: Vec<u8> = Vec::new();
-        doc.save(&mut BufWriter::new(&mut blob))?;
+    fn settings(&self) -> PageSettings {
+        match self {
+            OutputPage::Raster(page) => PageSettings {
+                ppi: Some(page.size.ppi.as_f64()),
+                // Picked when the page is written
+                encoding: None,
+            },
+            OutputPage::Vector(_) => PageSettings {
+                ppi: None,
+                encoding: None,
+            },
+        }
+    }
 
-        Ok(blob)
+    fn rendering(&self) -> Rendering {
+        match self {
+            OutputPage::Raster(page) if page.fell_back => Rendering::RasterFallback,
+            OutputPage::Raster(_) => Rendering::Raster,
+            OutputPage::Vector(page) if page.is_original() => Rendering::Original,
+            OutputPage::Vector(_) => Rendering::Vector,
+        }
     }
 }
 
 pub struct TransformedPage {
     image: image::DynamicImage,
     pub size: PageSize,
+    /// Zero indexed page number in the source document
+    page_num: usize,
+    /// What we treated as the paper color, estimated from the page if background detection
+    /// is adaptive
+    paper_color: Color,
+    /// Only filled in for pages going into a PDF
+    text_layer: Option<TextLayer>,
+    /// See `Rendering::RasterFallback`
+    fell_back: bool,
 }
 
 impl TransformedPage {
+    /// Zero indexed page number in the source document
+    pub fn page_num(&self) -> usize {
+        self.page_num
+    }
+
+    pub fn paper_color(&self) -> Color {
+        self.paper_color
+    }
+
     pub fn to_png(&self) -> Result<Vec<u8>> {
         let mut vec = Vec::new();
         self.image.write_to(&mut vec, ImageOutputFormat::Png)?;
@@ -289,9 +817,19 @@ impl TransformedPage {
     }
 }
 
-fn transform_page_data(
-    img_data: &mut ImageSurfaceData,
+/// Everything needed to recolor a pixel besides the classifier
+#[derive(Debug, Clone, Copy)]
+struct Recolor {
+    strategy: Strategy,
     background_color: LittleEndianRgbPixel<u8>,
+    paper_color: LittleEndianRgbPixel<u8>,
+}
+
+fn transform_page_data(
+    img_data: &mut [u8],
+    recolor: Recolor,
+    ink: Option<&InkRecolor>,
+    classifier: &impl Classifier,
 ) {
     // NOTE: By default poppler renders in ARgb32
     // 32 means 4 8-bit parts
@@ -311,14 +849,53 @@ fn transform_page_data(
 
     // So long as img_data.len() % PIXEL_SIZE == 0 every chunk will be of size PIXEL_SIZE and
     // transform_pixel won't panic
+    match recolor.strategy {
+        Strategy::Replace => recolor_ink(img_data, ink)
+            .for_each(|pixel| transform_pixel(pixel, recolor.background_color, classifier)),
+        Strategy::Blend => {
+            recolor_ink(img_data, ink).for_each(|pixel| blend_pixel(pixel, recolor, classifier))
+        }
+        Strategy::Night { tint } => {
+            let tint_color = if tint {
+                Some(recolor.background_color)
+            } else {
+                None
+            };
+            let night = Night::new(recolor.paper_color, tint_color);
+            // Inverting recolored ink would turn it into the opposite color, so ink is found
+            // before inverting and colored after
+            for pixel in img_data.chunks_exact_mut(RGBA_PIXEL_SIZE) {
+                let ink_strength = ink.and_then(|ink| Some((ink, ink.strength(pixel)?)));
+                night.transform_pixel(pixel, classifier);
+                if let Some((ink, strength)) = ink_strength {
+                    ink.blend(pixel, strength);
+                }
+            }
+        }
+    }
+}
+
+/// Recolor the ink, and return the pixels that are left. Ink is never background, so pixels
+/// recolored as ink are done.
+fn recolor_ink<'a>(
+    img_data: &'a mut [u8],
+    ink: Option<&'a InkRecolor>,
+) -> impl Iterator<Item = &'a mut [u8]> + 'a {
     img_data
         .chunks_exact_mut(RGBA_PIXEL_SIZE)
-        .for_each(|pixel| transform_pixel(pixel, background_color));
+        .filter_map(move |pixel| match ink {
+            Some(ink) if ink.apply(pixel) => None,
+            _ => Some(pixel),
+        })
 }
 
-fn transform_pixel(pixel: &mut [u8], background_color: LittleEndianRgbPixel<u8>) {
+fn transform_pixel(
+    pixel: &mut [u8],
+    background_color: LittleEndianRgbPixel<u8>,
+    classifier: &impl Classifier,
+) {
     //! Pixel must hold 4 items (b, g, r, a) or may panic
-    if is_background(pixel) {
+    if classifier.is_background(pixel) {
         // NOTE: This is about 10% faster than using iter_mut and zip
         pixel[0] = background_color[0];
         pixel[1] = background_color[1];
@@ -326,22 +903,24 @@ fn transform_pixel(pixel: &mut [u8], background_color: LittleEndianRgbPixel<u8>)
     }
 }
 
-fn is_background(pixel: &[u8]) -> bool {
-    //! Queen-wise distance algorithm
-    //! Mahama 2016 <https://doi.org/10.2352/ISSN.2470-1173.2016.20.COLOR-349>
-    let dissimilarity = pixel
-        .iter()
-        .take(RGBA_PIXEL_SIZE - 1)
-        .zip(WHITE_PIXEL.iter())
-        .map(|(candidate, white)| ((*candidate as i16) - white).abs())
-        .max()
-        // Won't panic: max() -> None if iterator is empty and pixel is always &mut[u8; 4]
-        .unwrap();
-
-    dissimilarity < 90
+fn blend_pixel(pixel: &mut [u8], recolor: Recolor, classifier: &impl Classifier) {
+    //! Pixel must hold 4 items (b, g, r, a) or may panic
+    //!
+    //! An anti-aliased pixel is paper * (1 - coverage) + ink * coverage. Swapping the paper for
+    //! the background color means shifting the pixel by (background - paper) * (1 - coverage).
+    let paper_showing = 1.0 - classifier.coverage(pixel);
+    if paper_showing <= 0.0 {
+        return;
+    }
+    let colors = recolor.background_color.iter().zip(&recolor.paper_color);
+    for (channel, (background, paper)) in pixel.iter_mut().zip(colors) {
+        let shift = (*background as f64 - *paper as f64) * paper_showing;
+        *channel = (*channel as f64 + shift).round().max(0.0).min(255.0) as u8;
+    }
 }
 
-fn render_poppler_page(page: &PopplerPage, size: PageSize) -> Result<ImageSurface> {
+/// Render the part of the page covered by tile
+fn render_poppler_page(page: &PopplerPage, size: PageSize, tile: Tile) -> Result<ImageSurface> {
     // Directly ported from pdftoimage.c example code
     // See <https://web.archive.org/web/20200421162328/https://www.cairographics.org/cookbook/renderpdf/>
     // Interleaved with original C code (marked --> ), manual memory management in source omitted
@@ -356,15 +935,16 @@ fn render_poppler_page(page: &PopplerPage, size: PageSize) -> Result<ImageSurfac
     // -->                                   IMAGE_DPI*width/72.0,
     // -->                                   IMAGE_DPI*height/72.0);
 
-    let surface = ImageSurface::create(
-        Format::ARgb32,
-        size.width_to_px().as_i32(),
-        size.height_to_px().as_i32(),
-    )?;
+    // We only create a surface the size of the tile
+    let surface = ImageSurface::create(Format::ARgb32, tile.width as i32, tile.height as i32)?;
 
     // --> cr = cairo_create (surface);
     let cr = Context::new(&surface);
 
+    // Move the tile's corner of the page to the corner of the surface. Everything else is
+    // clipped.
+    cr.translate(-(tile.x as f64), -(tile.y as f64));
+
     // --> cairo_scale (cr, IMAGE_DPI/72.0, IMAGE_DPI/72.0);
     let scale_factor = Pt::new(1.0, size.ppi).to_px().as_f64();
     cr.scale(scale_factor, scale_factor);
@@ -394,6 +974,17 @@ fn render_poppler_page(page: &PopplerPage, size: PageSize) -> Result<ImageSurfac
     Ok(surface)
 }
 
+/// Estimate the paper color of a page from a render small enough to fit in one tile
+fn estimate_paper_color(page: &PopplerPage, size: PageSize) -> Result<Option<Color>> {
+    let size = size.fit_within(TILE_SIDE);
+    let tile = Tile::whole(size.width_to_px().as_u32(), size.height_to_px().as_u32());
+    let mut surface = render_poppler_page(page, size, tile)?;
+    let img_data = surface
+        .get_data()
+        .map_err(|_| TransformationError::Unknown)?;
+    Ok(background::estimate_paper_color(&img_data))
+}
+
 #[derive(Debug, Clone, Copy)]
 struct PPI(f64);
 
@@ -412,14 +1003,40 @@ impl Serialize for PPI {
     }
 }
 
-impl From<Quality> for PPI {
-    fn from(quality: Quality) -> Self {
+impl Quality {
+    /// Custom needs a finite resolution above zero and MaxPixels at least one pixel, or pages
+    /// would have no size
+    fn is_valid(self) -> bool {
+        match self {
+            Quality::Custom(ppi) => ppi.is_finite() && ppi > 0.0,
+            Quality::MaxPixels(max_pixels) => max_pixels > 0,
+            _ => true,
+        }
+    }
+}
+
+impl PPI {
+    /// The resolution to render a page of width x height points at
+    fn for_page(quality: Quality, width: f64, height: f64) -> Self {
         PPI(match quality {
             Quality::Extreme => 400.0,
             Quality::High => 200.0,
             Quality::Normal => 120.0,
             Quality::Low => 72.0,
             Quality::ExtremeLow => 10.0,
+            Quality::Custom(ppi) => ppi,
+            Quality::MaxPixels(max_pixels) => {
+                // The pixel count grows with the square of the resolution
+                let one_ppi = PPI(1.0);
+                let pixels_at_one_ppi = Pt::new(width, one_ppi).to_px().as_f64()
+                    * Pt::new(height, one_ppi).to_px().as_f64();
+                if pixels_at_one_ppi > 0.0 {
+                    (max_pixels as f64 / pixels_at_one_ppi).sqrt()
+                } else {
+                    // Nothing to render anyway
+                    1.0
+                }
+            }
         })
     }
 }
@@ -445,16 +1062,6 @@ impl Px {
         //! See <https://web.archive.org/web/20200421150714/https://stackoverflow.com/questions/47786322/why-is-type-conversion-from-u64-to-usize-allowed-using-as-but-not-from/47786517>
         self.as_usize() as u32
     }
-
-    fn as_i32(&self) -> i32 {
-        self.as_usize() as i32
-    }
-}
-
-impl From<Px> for printpdf::Px {
-    fn from(px: Px) -> Self {
-        Self(px.as_usize())
-    }
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -470,13 +1077,9 @@ impl Pt {
     }
 
     fn to_px(&self) -> Px {
-        let inches = self.as_f64() * (0.996264 / 72.0);
+        let inches = self.as_f64() * (IMAGE_SCALE / 72.0);
         Px::new(inches * self.1.as_f64())
     }
-
-    fn to_mm(&self) -> Mm {
-        Mm::new(self.as_f64() * 0.352778)
-    }
 }
 
 impl Serialize for Pt {
@@ -488,31 +1091,6 @@ impl Serialize for Pt {
     }
 }
 
-impl From<Pt> for printpdf::Pt {
-    fn from(pt: Pt) -> Self {
-        Self(pt.as_f64())
-    }
-}
-
-#[derive(Clone, Copy, Debug)]
-struct Mm(f64);
-
-impl Mm {
-    fn new(millimeters: f64) -> Self {
-        Mm(millimeters)
-    }
-
-    fn as_f64(&self) -> f64 {
-        self.0
-    }
-}
-
-impl From<Mm> for printpdf::Mm {
-    fn from(mm: Mm) -> Self {
-        Self(mm.as_f64())
-    }
-}
-
 #[derive(Serialize, Clone, Copy, Debug)]
 pub struct PageSize {
     width: Pt,
@@ -527,10 +1105,25 @@ impl PageSize {
 
     fn from(page: &PopplerPage, quality: Quality) -> Self {
         let (width, height) = page.get_size();
-        let ppi = PPI::from(quality);
+        let ppi = PPI::for_page(quality, width, height);
         Self::new(Pt::new(width, ppi), Pt::new(height, ppi), ppi)
     }
 
+    /// The same page at a lower resolution, so neither side is much longer than max_side
+    /// pixels
+    fn fit_within(&self, max_side: u32) -> Self {
+        let longest = self
+            .width_to_px()
+            .as_f64()
+            .max(self.height_to_px().as_f64());
+        let ppi = PPI(self.ppi.as_f64() * (max_side as f64 / longest).min(1.0));
+        Self::new(
+            Pt::new(self.width.as_f64(), ppi),
+            Pt::new(self.height.as_f64(), ppi),
+            ppi,
+        )
+    }
+
     fn width_to_px(&self) -> Px {
         self.width.to_px()
     }
@@ -538,31 +1131,6 @@ impl PageSize {
     fn height_to_px(&self) -> Px {
         self.height.to_px()
     }
-
-    fn width_to_mm(&self) -> Mm {
-        self.width.to_mm()
-    }
-
-    fn height_to_mm(&self) -> Mm {
-        self.height.to_mm()
-    }
-}
-
-fn page_data_to_pdf_image(
-    bgra_data: &ImageSurfaceData,
-    size: PageSize,
-) -> Result<image::DynamicImage> {
-    let bgra_data: Vec<u8> = (**bgra_data).into();
-    let image = DynamicImage::ImageBgra8(
-        ImageBuffer::from_raw(
-            size.width_to_px().as_u32(),
-            size.height_to_px().as_u32(),
-            bgra_data,
-        )
-        .ok_or(TransformationError::InsufficientMemory)?,
-    );
-    let image = DynamicImage::ImageRgb8(image.to_rgb());
-    Ok(image)
 }
 
 pub fn list_error_sources(error: &dyn std::error::Error) -> Vec<String> {
@@ -622,6 +1190,190 @@ mod test {
         );
     }
 
+    #[test]
+    fn blends_anti_aliased_edges() {
+        let recolor = Recolor {
+            strategy: Strategy::Blend,
+            background_color: DEFAULT_BACKGROUND_COLOR.into(),
+            paper_color: [255, 255, 255],
+        };
+        let classifier = QueenWise::new(Color::new(255, 255, 255), 90.0);
+        let blend = |mut pixel: [u8; 4]| {
+            blend_pixel(&mut pixel, recolor, &classifier);
+            pixel
+        };
+
+        // b, g, r, a
+        assert_eq!(blend([255, 255, 255, 255]), [255, 97, 226, 255]);
+        assert_eq!(blend([0, 0, 0, 255]), [0, 0, 0, 255]);
+        // Half covered by black ink ends up halfway between black and the background
+        assert_eq!(blend([128, 128, 128, 255]), [128, 49, 113, 255]);
+    }
+
+    #[test]
+    fn colors_ink_after_inverting_for_night() {
+        let recolor = Recolor {
+            strategy: Strategy::Night { tint: false },
+            background_color: DEFAULT_BACKGROUND_COLOR.into(),
+            paper_color: [255, 255, 255],
+        };
+        let classifier = QueenWise::new(Color::new(255, 255, 255), 90.0);
+        let ink = InkRecolor::new(Color::new(255, 220, 120), InkDetection::default());
+        // b, g, r, a: black ink, then white paper
+        let mut img_data = vec![0, 0, 0, 255, 255, 255, 255, 255];
+
+        transform_page_data(&mut img_data, recolor, Some(&ink), &classifier);
+
+        // Ink ends up the foreground color on a dark page
+        assert_eq!(img_data, vec![120, 220, 255, 255, 0, 0, 0, 255]);
+    }
+
+    #[test]
+    fn max_pixels_fits_the_page_to_the_budget() {
+        let quality = Quality::MaxPixels(1_000_000);
+        // US letter and A0, in points
+        for (width, height) in [(612.0, 792.0), (2384.0, 3370.0)].iter() {
+            let ppi = PPI::for_page(quality, *width, *height);
+            let size = PageSize::new(Pt::new(*width, ppi), Pt::new(*height, ppi), ppi);
+            let pixels = size.width_to_px().as_f64() * size.height_to_px().as_f64();
+            assert!((pixels - 1_000_000.0).abs() < 1.0);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_qualities() {
+        let open = |quality, overridden| {
+            TransformationState::try_new_with_options(
+                get_in_blob(),
+                TransformationStateOptions {
+                    quality,
+                    overrides: vec![PageOverride {
+                        pages: "1".parse().unwrap(),
+                        background_color: None,
+                        quality: Some(overridden),
+                        pass_through: false,
+                    }],
+                    ..TransformationStateOptions::default()
+                },
+            )
+        };
+        let invalid = [
+            Quality::Custom(0.0),
+            Quality::Custom(-72.0),
+            Quality::Custom(std::f64::NAN),
+            Quality::MaxPixels(0),
+        ];
+
+        for quality in invalid.iter().copied() {
+            assert!(matches!(
+                open(quality, Quality::Low),
+                Err(TransformationError::InvalidQuality(_))
+            ));
+            assert!(matches!(
+                open(Quality::Low, quality),
+                Err(TransformationError::InvalidQuality(_))
+            ));
+        }
+        assert!(open(Quality::Custom(0.5), Quality::MaxPixels(1)).is_ok());
+    }
+
+    #[test]
+    fn opens_encrypted_documents() {
+        let open = |password: Option<&str>| {
+            TransformationState::try_new_with_options(
+                include_bytes!("../test_assets/encrypted_test.pdf").to_vec(),
+                TransformationStateOptions {
+                    quality: Quality::ExtremeLow,
+                    password: password.map(String::from),
+                    ..TransformationStateOptions::default()
+                },
+            )
+        };
+
+        assert!(matches!(
+            open(None),
+            Err(TransformationError::PasswordRequired)
+        ));
+        assert!(matches!(
+            open(Some("lavender")),
+            Err(TransformationError::IncorrectPassword)
+        ));
+        let state = open(Some("purple")).unwrap();
+        assert_eq!(state.original_title(), "Encrypted test");
+        assert!(state.transform_page(0).is_ok());
+    }
+
+    #[test]
+    fn reports_pages_rasterized_without_structure() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            password: Some("purple".to_string()),
+            mode: Mode::Vector,
+            ..TransformationStateOptions::default()
+        };
+        // Encrypted with AES, which we can't decrypt
+        let complete = pdf_to_pdf::transform_with_options(
+            include_bytes!("../test_assets/aes_test.pdf").to_vec(),
+            options,
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        assert!(!complete.renderings().is_empty());
+        assert!(complete
+            .renderings()
+            .iter()
+            .all(|rendering| *rendering == Rendering::RasterFallback));
+        assert!(!complete.copied_structure());
+        assert_eq!(complete.metadata().title.as_deref(), Some("AES test"));
+    }
+
+    #[test]
+    fn copies_the_structure_of_encrypted_documents() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            password: Some("purple".to_string()),
+            encryption: OutputEncryption::Unencrypted,
+            ..TransformationStateOptions::default()
+        };
+        let complete = pdf_to_pdf::transform_with_options(
+            include_bytes!("../test_assets/restricted_test.pdf").to_vec(),
+            options,
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        assert!(complete.copied_structure());
+        assert_eq!(
+            complete.metadata().title.as_deref(),
+            Some("Restricted test")
+        );
+        assert_eq!(complete.metadata().page_labels, vec!["i"]);
+
+        let out = lopdf::Document::load_mem(&complete.into_bytes()).unwrap();
+        let page = out.get_pages()[&1];
+        let annots = out.get_dictionary(page).unwrap().get(b"Annots").unwrap();
+        let link = out.dereference(&annots.as_array().unwrap()[0]).unwrap().1;
+        let action = link
+            .as_dict()
+            .unwrap()
+            .get(b"A")
+            .unwrap()
+            .as_dict()
+            .unwrap();
+        assert_eq!(
+            action.get(b"URI").unwrap().as_str().unwrap(),
+            b"https://example.com/restricted"
+        );
+        let outlines = out.catalog().unwrap().get(b"Outlines").unwrap();
+        let outlines = out.dereference(outlines).unwrap().1.as_dict().unwrap();
+        let first = out.dereference(outlines.get(b"First").unwrap()).unwrap().1;
+        let title = first.as_dict().unwrap().get(b"Title").unwrap();
+        assert_eq!(title.as_str().unwrap(), b"Start");
+    }
+
     fn get_in_blob() -> Vec<u8> {
         include_bytes!("../test_assets/multipage_test.pdf").to_vec()
     }
@@ -0,0 +1,746 @@
+//! Carry the outline (bookmarks) and link annotations of the source document over to the output.
+//!
+//! Destinations are remapped onto the output pages. Links to pages that weren't transformed are
+//! dropped, and bookmarks to them are kept only if they have children that survived. On
+//! rasterized pages, link areas and destinations are moved to where the image shows them.
+
+use crate::vector::{as_dict, import_object, inherited, page_id, rectangle};
+use crate::{Result, TransformationError, IMAGE_SCALE};
+use lopdf::{Dictionary, Document, Object, ObjectId};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Guards against cycles in malformed outlines and name trees
+const MAX_DEPTH: usize = 32;
+const MAX_SIBLINGS: usize = 100_000;
+
+/// Link annotation keys that don't depend on where the link goes
+const LINK_APPEARANCE_KEYS: [&[u8]; 3] = [b"Border", b"C", b"H"];
+
+/// Where a source page went in the output
+#[derive(Debug, Clone, Copy)]
+struct MappedPage {
+    id: ObjectId,
+    /// None for pages that kept their original content, and with it their coordinates
+    transform: Option<PageTransform>,
+}
+
+/// Takes coordinates on a source page to the same spot on its rasterized output page. Poppler
+/// renders the crop box, turned by /Rotate, and we draw that image from the origin of a page
+/// with a MediaBox of [0 0 width height], slightly scaled down (see `IMAGE_SCALE`).
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct PageTransform {
+    /// The bottom left corner and size of the crop box
+    x0: f64,
+    y0: f64,
+    width: f64,
+    height: f64,
+    /// Clockwise, one of 0, 90, 180 and 270
+    rotate: i64,
+}
+
+impl PageTransform {
+    fn of(source: &Document, page_id: ObjectId) -> Option<PageTransform> {
+        let [x0, y0, x1, y1] = inherited(source, page_id, b"CropBox")
+            .or_else(|| inherited(source, page_id, b"MediaBox"))
+            .and_then(|crop_box| rectangle(source, crop_box))?;
+        let rotate = inherited(source, page_id, b"Rotate")
+            .and_then(|rotate| rotate.as_i64().ok())
+            .unwrap_or(0);
+
+        Some(PageTransform {
+            x0: x0.min(x1),
+            y0: y0.min(y1),
+            width: (x1 - x0).abs(),
+            height: (y1 - y0).abs(),
+            rotate: rotate.rem_euclid(360) / 90 * 90,
+        })
+    }
+
+    fn point(&self, x: f64, y: f64) -> (f64, f64) {
+        let (x, y) = (x - self.x0, y - self.y0);
+        let (x, y) = match self.rotate {
+            90 => (y, self.width - x),
+            180 => (self.width - x, self.height - y),
+            270 => (self.height - y, x),
+            _ => (x, y),
+        };
+        (x * IMAGE_SCALE, y * IMAGE_SCALE)
+    }
+
+    /// A position in a destination, where a missing coordinate means "keep the current one".
+    /// Turning the page by 90 degrees swaps which coordinate is missing.
+    fn position(&self, x: Option<f64>, y: Option<f64>) -> (Option<f64>, Option<f64>) {
+        let (out_x, out_y) = self.point(x.unwrap_or(self.x0), y.unwrap_or(self.y0));
+        if self.rotate % 180 == 0 {
+            (x.map(|_| out_x), y.map(|_| out_y))
+        } else {
+            (y.map(|_| out_x), x.map(|_| out_y))
+        }
+    }
+
+    fn rect(&self, [x1, y1, x2, y2]: [f64; 4]) -> [f64; 4] {
+        let (x1, y1) = self.point(x1, y1);
+        let (x2, y2) = self.point(x2, y2);
+        [x1.min(x2), y1.min(y2), x1.max(x2), y1.max(y2)]
+    }
+
+    /// The fit type and its coordinates, everything after the page in an explicit destination
+    fn view(&self, view: &[Object]) -> Vec<Object> {
+        let fit = match view.first().and_then(|fit| fit.as_name().ok()) {
+            Some(fit) => fit,
+            None => return view.to_vec(),
+        };
+        let coordinate = |index: usize| view.get(index).and_then(number);
+        let or_null = |value: Option<f64>| value.map(Object::Real).unwrap_or(Object::Null);
+
+        match fit {
+            b"XYZ" => {
+                let (left, top) = self.position(coordinate(1), coordinate(2));
+                let zoom = view.get(3).cloned().unwrap_or(Object::Null);
+                vec![view[0].clone(), or_null(left), or_null(top), zoom]
+            }
+            b"FitH" | b"FitBH" | b"FitV" | b"FitBV" => {
+                let (left, top) = if fit.ends_with(b"H") {
+                    self.position(None, coordinate(1))
+                } else {
+                    self.position(coordinate(1), None)
+                };
+                let fit = if fit.starts_with(b"FitB") {
+                    "FitB"
+                } else {
+                    "Fit"
+                };
+                match (left, top) {
+                    (None, Some(top)) => vec![
+                        Object::Name(format!("{}H", fit).into_bytes()),
+                        Object::Real(top),
+                    ],
+                    (Some(left), None) => vec![
+                        Object::Name(format!("{}V", fit).into_bytes()),
+                        Object::Real(left),
+                    ],
+                    _ => view.to_vec(),
+                }
+            }
+            b"FitR" => match (coordinate(1), coordinate(2), coordinate(3), coordinate(4)) {
+                (Some(left), Some(bottom), Some(right), Some(top)) => {
+                    let mut mapped = vec![view[0].clone()];
+                    mapped.extend(
+                        self.rect([left, bottom, right, top])
+                            .iter()
+                            .map(|value| Object::Real(*value)),
+                    );
+                    mapped
+                }
+                _ => vec![Object::Name(b"Fit".to_vec())],
+            },
+            // Fit and FitB have no coordinates
+            _ => view.to_vec(),
+        }
+    }
+}
+
+struct OutlineItem {
+    title: Object,
+    /// An explicit destination whose first element is already an output page
+    dest: Option<Vec<Object>>,
+    children: Vec<OutlineItem>,
+}
+
+/// page_nums are the zero indexed source page numbers of each output page, in output order,
+/// and vector_pages the indices of output pages that kept their original content. See
+/// `vector::import_object` for imported.
+pub(crate) fn copy(
+    out: &mut Document,
+    source: &Document,
+    page_nums: &[usize],
+    vector_pages: &BTreeSet<usize>,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Result<()> {
+    let out_pages = out.get_pages();
+    let mut page_map = BTreeMap::new();
+    for (index, page_num) in page_nums.iter().enumerate() {
+        let out_id = *out_pages
+            .get(&(index as u32 + 1))
+            .ok_or(TransformationError::NonexistentPage(index))?;
+        if let Some(source_id) = page_id(source, *page_num) {
+            let transform = if vector_pages.contains(&index) {
+                None
+            } else {
+                PageTransform::of(source, source_id)
+            };
+            page_map.insert(
+                source_id,
+                MappedPage {
+                    id: out_id,
+                    transform,
+                },
+            );
+        }
+    }
+
+    for (source_id, out_page) in &page_map {
+        copy_annotations(out, source, *source_id, *out_page, &page_map, imported)?;
+    }
+
+    copy_outline(out, source, &page_map)
+}
+
+fn copy_annotations(
+    out: &mut Document,
+    source: &Document,
+    source_id: ObjectId,
+    out_page: MappedPage,
+    page_map: &BTreeMap<ObjectId, MappedPage>,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Result<()> {
+    let annotations = match source
+        .get_dictionary(source_id)
+        .and_then(|page| page.get(b"Annots"))
+        .and_then(|annots| source.dereference(annots))
+        .and_then(|(_, annots)| annots.as_array())
+    {
+        Ok(annotations) => annotations,
+        Err(_) => return Ok(()),
+    };
+
+    let mut copied = Vec::new();
+    for annotation in annotations {
+        let annotation = match as_dict(source, annotation) {
+            Some(annotation) if name_is(annotation, b"Subtype", b"Link") => annotation,
+            _ => continue,
+        };
+
+        if let Some(link) = copy_link(
+            out,
+            source,
+            annotation,
+            out_page.transform,
+            page_map,
+            imported,
+        ) {
+            copied.push(Object::Reference(out.add_object(link)));
+        }
+    }
+
+    if !copied.is_empty() {
+        let out_page = out.get_object_mut(out_page.id)?.as_dict_mut()?;
+        out_page.set("Annots", Object::Array(copied));
+    }
+    Ok(())
+}
+
+/// transform is that of the page the link is on
+fn copy_link(
+    out: &mut Document,
+    source: &Document,
+    annotation: &Dictionary,
+    transform: Option<PageTransform>,
+    page_map: &BTreeMap<ObjectId, MappedPage>,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Option<Dictionary> {
+    let mut link = Dictionary::new();
+    link.set("Type", Object::Name(b"Annot".to_vec()));
+    link.set("Subtype", Object::Name(b"Link".to_vec()));
+    let rect = annotation.get(b"Rect").ok()?;
+    match transform {
+        Some(transform) => {
+            let rect = transform.rect(rectangle(source, rect)?);
+            link.set("Rect", reals(&rect));
+        }
+        None => link.set("Rect", import_object(out, source, rect, imported)),
+    }
+    for key in LINK_APPEARANCE_KEYS.iter() {
+        if let Ok(value) = annotation.get(key) {
+            link.set(key.to_vec(), import_object(out, source, value, imported));
+        }
+    }
+    if let Ok(quad_points) = annotation.get(b"QuadPoints") {
+        match transform {
+            Some(transform) => {
+                if let Some(quad_points) = map_quad_points(source, quad_points, transform) {
+                    link.set("QuadPoints", quad_points);
+                }
+            }
+            None => link.set(
+                "QuadPoints",
+                import_object(out, source, quad_points, imported),
+            ),
+        }
+    }
+
+    if let Ok(dest) = annotation.get(b"Dest") {
+        link.set("Dest", Object::Array(resolve_dest(source, dest, page_map)?));
+        return Some(link);
+    }
+
+    let action = as_dict(source, annotation.get(b"A").ok()?)?;
+    if name_is(action, b"S", b"GoTo") {
+        let dest = resolve_dest(source, action.get(b"D").ok()?, page_map)?;
+        link.set("Dest", Object::Array(dest));
+    } else {
+        // URIs, named actions, links to other files etc. don't refer to our pages
+        let mut action = action.clone();
+        action.remove(b"Next");
+        link.set(
+            "A",
+            import_object(out, source, &Object::Dictionary(action), imported),
+        );
+    }
+    Some(link)
+}
+
+fn copy_outline(
+    out: &mut Document,
+    source: &Document,
+    page_map: &BTreeMap<ObjectId, MappedPage>,
+) -> Result<()> {
+    let first = source
+        .catalog()
+        .and_then(|catalog| catalog.get(b"Outlines"))
+        .ok()
+        .and_then(|outlines| as_dict(source, outlines))
+        .and_then(|outlines| outlines.get(b"First").ok());
+    let items = match first {
+        Some(first) => read_items(source, first, page_map, 0),
+        None => return Ok(()),
+    };
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let outlines_id = out.new_object_id();
+    let (first, last, count) = write_items(out, items, outlines_id);
+    let mut outlines = Dictionary::new();
+    outlines.set("Type", Object::Name(b"Outlines".to_vec()));
+    outlines.set("First", Object::Reference(first));
+    outlines.set("Last", Object::Reference(last));
+    outlines.set("Count", Object::Integer(count));
+    out.objects
+        .insert(outlines_id, Object::Dictionary(outlines));
+
+    let catalog_id = out.trailer.get(b"Root").and_then(Object::as_reference)?;
+    let catalog = out.get_object_mut(catalog_id)?.as_dict_mut()?;
+    catalog.set("Outlines", Object::Reference(outlines_id));
+    Ok(())
+}
+
+/// Read an item and its following siblings, dropping those that only point at excluded pages
+fn read_items(
+    source: &Document,
+    first: &Object,
+    page_map: &BTreeMap<ObjectId, MappedPage>,
+    depth: usize,
+) -> Vec<OutlineItem> {
+    let mut items = Vec::new();
+    if depth > MAX_DEPTH {
+        return items;
+    }
+
+    let mut next = as_dict(source, first);
+    let mut visited = 0;
+    while let Some(item) = next {
+        visited += 1;
+        if visited > MAX_SIBLINGS {
+            // A cycle in the sibling list
+            break;
+        }
+
+        let children = item
+            .get(b"First")
+            .map(|first| read_items(source, first, page_map, depth + 1))
+            .unwrap_or_default();
+        let dest = item
+            .get(b"Dest")
+            .ok()
+            .or_else(|| {
+                as_dict(source, item.get(b"A").ok()?)
+                    .filter(|action| name_is(action, b"S", b"GoTo"))
+                    .and_then(|action| action.get(b"D").ok())
+            })
+            .and_then(|dest| resolve_dest(source, dest, page_map));
+
+        if dest.is_some() || !children.is_empty() {
+            items.push(OutlineItem {
+                title: item
+                    .get(b"Title")
+                    .ok()
+                    .and_then(|title| source.dereference(title).ok())
+                    .map(|(_, title)| title.clone())
+                    .unwrap_or_else(|| Object::string_literal("")),
+                dest,
+                children,
+            });
+        }
+
+        next = item
+            .get(b"Next")
+            .ok()
+            .and_then(|next| as_dict(source, next));
+    }
+
+    items
+}
+
+/// Returns the ids of the first and last item and the number of visible descendants
+fn write_items(
+    out: &mut Document,
+    items: Vec<OutlineItem>,
+    parent: ObjectId,
+) -> (ObjectId, ObjectId, i64) {
+    let ids: Vec<ObjectId> = items.iter().map(|_| out.new_object_id()).collect();
+    let mut count = 0;
+
+    for (index, item) in items.into_iter().enumerate() {
+        let mut dict = Dictionary::new();
+        dict.set("Title", item.title);
+        dict.set("Parent", Object::Reference(parent));
+        if index > 0 {
+            dict.set("Prev", Object::Reference(ids[index - 1]));
+        }
+        if let Some(next) = ids.get(index + 1) {
+            dict.set("Next", Object::Reference(*next));
+        }
+        if let Some(dest) = item.dest {
+            dict.set("Dest", Object::Array(dest));
+        }
+        if !item.children.is_empty() {
+            let (first, last, child_count) = write_items(out, item.children, ids[index]);
+            dict.set("First", Object::Reference(first));
+            dict.set("Last", Object::Reference(last));
+            // Negative means closed, which is how most readers show long outlines anyway
+            dict.set("Count", Object::Integer(-child_count));
+        }
+
+        out.objects.insert(ids[index], Object::Dictionary(dict));
+        count += 1;
+    }
+
+    (ids[0], ids[ids.len() - 1], count)
+}
+
+/// Turn a destination (explicit, named or a dictionary holding one) into an explicit destination
+/// on an output page, or None if it points at a page we didn't transform
+fn resolve_dest(
+    source: &Document,
+    dest: &Object,
+    page_map: &BTreeMap<ObjectId, MappedPage>,
+) -> Option<Vec<Object>> {
+    let (_, dest) = source.dereference(dest).ok()?;
+    match dest {
+        Object::Array(dest) => {
+            let (page, view) = dest.split_first()?;
+            let out_page = page_map.get(&page.as_reference().ok()?)?;
+            // Coordinates can be indirect, but the objects they refer to aren't copied
+            let view: Vec<Object> = view
+                .iter()
+                .map(|value| {
+                    source
+                        .dereference(value)
+                        .map_or(Object::Null, |(_, value)| value.clone())
+                })
+                .collect();
+            let mut resolved = vec![Object::Reference(out_page.id)];
+            match out_page.transform {
+                Some(transform) => resolved.extend(transform.view(&view)),
+                None => resolved.extend(view),
+            }
+            Some(resolved)
+        }
+        Object::Dictionary(dest) => resolve_dest(source, dest.get(b"D").ok()?, page_map),
+        Object::Name(name) | Object::String(name, _) => {
+            let named = lookup_named_dest(source, name)?;
+            resolve_dest(source, named, page_map)
+        }
+        _ => None,
+    }
+}
+
+/// QuadPoints are pairs of x and y coordinates, four pairs to each area
+fn map_quad_points(
+    source: &Document,
+    quad_points: &Object,
+    transform: PageTransform,
+) -> Option<Object> {
+    let (_, quad_points) = source.dereference(quad_points).ok()?;
+    let values = quad_points
+        .as_array()
+        .ok()?
+        .iter()
+        .map(number)
+        .collect::<Option<Vec<f64>>>()?;
+
+    let mut mapped = Vec::with_capacity(values.len());
+    for pair in values.chunks_exact(2) {
+        let (x, y) = transform.point(pair[0], pair[1]);
+        mapped.extend_from_slice(&[x, y]);
+    }
+    Some(reals(&mapped))
+}
+
+fn number(object: &Object) -> Option<f64> {
+    match object {
+        Object::Integer(value) => Some(*value as f64),
+        Object::Real(value) => Some(*value),
+        _ => None,
+    }
+}
+
+fn reals(values: &[f64]) -> Object {
+    Object::Array(values.iter().map(|value| Object::Real(*value)).collect())
+}
+
+pub(crate) fn name_is(dict: &Dictionary, key: &[u8], name: &[u8]) -> bool {
+    dict.get(key).and_then(Object::as_name).ok() == Some(name)
+}
+
+fn lookup_named_dest<'a>(source: &'a Document, name: &[u8]) -> Option<&'a Object> {
+    let catalog = source.catalog().ok()?;
+
+    // PDF 1.1 style, a dictionary keyed by name
+    let legacy = catalog
+        .get(b"Dests")
+        .ok()
+        .and_then(|dests| as_dict(source, dests))
+        .and_then(|dests| dests.get(name).ok());
+    if legacy.is_some() {
+        return legacy;
+    }
+
+    let tree = catalog
+        .get(b"Names")
+        .ok()
+        .and_then(|names| as_dict(source, names))
+        .and_then(|names| names.get(b"Dests").ok())
+        .and_then(|dests| as_dict(source, dests))?;
+    lookup_name_tree(source, tree, name, 0)
+}
+
+fn lookup_name_tree<'a>(
+    source: &'a Document,
+    node: &'a Dictionary,
+    name: &[u8],
+    depth: usize,
+) -> Option<&'a Object> {
+    if depth > MAX_DEPTH {
+        return None;
+    }
+
+    if let Ok(names) = node.get(b"Names").and_then(Object::as_array) {
+        // Alternating keys and values
+        return names
+            .chunks_exact(2)
+            .find(|pair| pair[0].as_str().ok() == Some(name))
+            .map(|pair| &pair[1]);
+    }
+
+    node.get(b"Kids")
+        .and_then(Object::as_array)
+        .ok()?
+        .iter()
+        .filter_map(|kid| as_dict(source, kid))
+        .find_map(|kid| lookup_name_tree(source, kid, name, depth + 1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn page_map() -> BTreeMap<ObjectId, MappedPage> {
+        let mut page_map = BTreeMap::new();
+        page_map.insert(
+            (3, 0),
+            MappedPage {
+                id: (10, 0),
+                transform: None,
+            },
+        );
+        page_map
+    }
+
+    /// A source page with its origin away from the bottom left corner
+    fn offset_page(rotate: i64) -> (Document, ObjectId) {
+        let mut source = Document::new();
+        let mut page = Dictionary::new();
+        page.set("Type", "Page");
+        page.set(
+            "MediaBox",
+            vec![
+                Object::Integer(100),
+                Object::Integer(200),
+                Object::Integer(712),
+                Object::Integer(992),
+            ],
+        );
+        page.set("Rotate", rotate);
+        let page_id = source.add_object(page);
+        (source, page_id)
+    }
+
+    fn numbers(objects: &[Object]) -> Vec<f64> {
+        objects
+            .iter()
+            .map(|object| (number(object).unwrap() / IMAGE_SCALE).round())
+            .collect()
+    }
+
+    #[test]
+    fn remaps_explicit_dest() {
+        let source = Document::new();
+        let dest = Object::Array(vec![
+            Object::Reference((3, 0)),
+            Object::Name(b"Fit".to_vec()),
+        ]);
+
+        let resolved = resolve_dest(&source, &dest, &page_map()).unwrap();
+        assert_eq!(resolved[0].as_reference().unwrap(), (10, 0));
+        assert_eq!(resolved[1].as_name().unwrap(), b"Fit");
+    }
+
+    #[test]
+    fn drops_dest_to_excluded_page() {
+        let source = Document::new();
+        let dest = Object::Array(vec![
+            Object::Reference((4, 0)),
+            Object::Name(b"Fit".to_vec()),
+        ]);
+
+        assert!(resolve_dest(&source, &dest, &page_map()).is_none());
+    }
+
+    #[test]
+    fn moves_links_on_offset_pages_onto_the_image() {
+        let (source, page_id) = offset_page(0);
+        let transform = PageTransform::of(&source, page_id);
+        let mut page_map = BTreeMap::new();
+        page_map.insert(
+            page_id,
+            MappedPage {
+                id: (10, 0),
+                transform,
+            },
+        );
+
+        let mut annotation = Dictionary::new();
+        annotation.set("Rect", reals(&[150.0, 250.0, 250.0, 300.0]));
+        annotation.set(
+            "Dest",
+            vec![
+                Object::Reference(page_id),
+                Object::Name(b"XYZ".to_vec()),
+                Object::Integer(150),
+                Object::Integer(900),
+                Object::Null,
+            ],
+        );
+        let mut out = Document::new();
+        let mut imported = BTreeMap::new();
+        let link = copy_link(
+            &mut out,
+            &source,
+            &annotation,
+            transform,
+            &page_map,
+            &mut imported,
+        )
+        .unwrap();
+
+        let rect = link.get(b"Rect").unwrap().as_array().unwrap();
+        assert_eq!(numbers(rect), vec![50.0, 50.0, 150.0, 100.0]);
+        let dest = link.get(b"Dest").unwrap().as_array().unwrap();
+        assert_eq!(dest[0].as_reference().unwrap(), (10, 0));
+        assert_eq!(numbers(&dest[2..4]), vec![50.0, 700.0]);
+        assert!(matches!(dest[4], Object::Null));
+    }
+
+    #[test]
+    fn resolves_indirect_entries_of_links_on_vector_pages() {
+        let mut source = Document::new();
+        let rect = source.add_object(vec![
+            Object::Integer(10),
+            Object::Integer(20),
+            Object::Integer(30),
+            Object::Integer(40),
+        ]);
+        let zoom = source.add_object(Object::Integer(2));
+        let mut annotation = Dictionary::new();
+        annotation.set("Rect", Object::Reference(rect));
+        annotation.set(
+            "Dest",
+            vec![
+                Object::Reference((3, 0)),
+                Object::Name(b"XYZ".to_vec()),
+                Object::Null,
+                Object::Null,
+                Object::Reference(zoom),
+            ],
+        );
+
+        let mut out = Document::new();
+        let mut imported = BTreeMap::new();
+        let link = copy_link(
+            &mut out,
+            &source,
+            &annotation,
+            None,
+            &page_map(),
+            &mut imported,
+        )
+        .unwrap();
+
+        let (_, rect) = out.dereference(link.get(b"Rect").unwrap()).unwrap();
+        let rect = rect.as_array().unwrap();
+        assert_eq!(
+            rect.iter().filter_map(number).collect::<Vec<_>>(),
+            vec![10.0, 20.0, 30.0, 40.0]
+        );
+        let dest = link.get(b"Dest").unwrap().as_array().unwrap();
+        assert_eq!(dest[4].as_i64().unwrap(), 2);
+    }
+
+    #[test]
+    fn turns_destinations_with_the_page() {
+        let (source, page_id) = offset_page(90);
+        let transform = PageTransform::of(&source, page_id).unwrap();
+
+        // The top left corner of the crop box ends up at the top right
+        assert_eq!(
+            transform.point(100.0, 992.0),
+            (792.0 * IMAGE_SCALE, 612.0 * IMAGE_SCALE)
+        );
+
+        // Scrolling to a height on the page becomes scrolling to a distance from the left
+        let view = transform.view(&[Object::Name(b"FitH".to_vec()), Object::Integer(900)]);
+        assert_eq!(view[0].as_name().unwrap(), b"FitV");
+        assert_eq!(numbers(&view[1..]), vec![700.0]);
+
+        let view = transform.view(&[
+            Object::Name(b"XYZ".to_vec()),
+            Object::Null,
+            Object::Integer(900),
+            Object::Null,
+        ]);
+        assert_eq!(numbers(&view[1..2]), vec![700.0]);
+        assert!(matches!(view[2], Object::Null));
+    }
+
+    #[test]
+    fn finds_name_in_name_tree() {
+        let source = Document::new();
+        let mut leaf = Dictionary::new();
+        leaf.set(
+            "Names",
+            Object::Array(vec![
+                Object::string_literal("intro"),
+                Object::Integer(1),
+                Object::string_literal("outro"),
+                Object::Integer(2),
+            ]),
+        );
+        let mut root = Dictionary::new();
+        root.set("Kids", Object::Array(vec![Object::Dictionary(leaf)]));
+
+        let found = lookup_name_tree(&source, &root, b"outro", 0).unwrap();
+        assert_eq!(found.as_i64().unwrap(), 2);
+    }
+}
@@ -0,0 +1,527 @@
+//! Read the document information of the source and copy it into the output: the Info
+//! dictionary, the XMP metadata stream and page labels.
+
+use crate::vector::{as_dict, import_object};
+use crate::Result;
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+const PRODUCER_NOTE: &str = concat!("transformed by purpleifypdf ", env!("CARGO_PKG_VERSION"));
+
+/// Guards against cycles in malformed number trees
+const MAX_DEPTH: usize = 32;
+
+const XMP_PDF_NAMESPACE: &str = "http://ns.adobe.com/pdf/1.3/";
+
+/// The metadata of the source document
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub keywords: Option<String>,
+    pub creator: Option<String>,
+    pub producer: Option<String>,
+    /// In PDF date format, e.g. "D:20200421162328+01'00'"
+    pub creation_date: Option<String>,
+    /// In PDF date format, e.g. "D:20200421162328+01'00'"
+    pub modification_date: Option<String>,
+    /// Any other text entries in the Info dictionary
+    pub custom: BTreeMap<String, String>,
+    /// The raw XMP packet
+    pub xmp: Option<String>,
+    /// The label of each output page, in output order. Empty if the source has no page labels.
+    pub page_labels: Vec<String>,
+}
+
+impl Metadata {
+    /// page_nums are the zero indexed source page numbers of each output page, in output order
+    pub(crate) fn read(source: &Document, page_nums: &[usize]) -> Metadata {
+        let mut metadata = Metadata::default();
+
+        if let Some(info) = info(source) {
+            for (key, value) in info.iter() {
+                let value = match source
+                    .dereference(value)
+                    .ok()
+                    .and_then(|(_, value)| value.as_str().ok())
+                {
+                    Some(value) => decode_text_string(value),
+                    None => continue,
+                };
+
+                match key.as_slice() {
+                    b"Title" => metadata.title = Some(value),
+                    b"Author" => metadata.author = Some(value),
+                    b"Subject" => metadata.subject = Some(value),
+                    b"Keywords" => metadata.keywords = Some(value),
+                    b"Creator" => metadata.creator = Some(value),
+                    b"Producer" => metadata.producer = Some(value),
+                    b"CreationDate" => metadata.creation_date = Some(value),
+                    b"ModDate" => metadata.modification_date = Some(value),
+                    _ => {
+                        metadata
+                            .custom
+                            .insert(String::from_utf8_lossy(key).into_owned(), value);
+                    }
+                }
+            }
+        }
+
+        metadata.xmp = xmp(source).map(|xmp| {
+            let mut xmp = xmp.clone();
+            xmp.decompress();
+            String::from_utf8_lossy(&xmp.content).into_owned()
+        });
+
+        let ranges = page_label_ranges(source);
+        if !ranges.is_empty() {
+            metadata.page_labels = page_nums
+                .iter()
+                .map(|page_num| label(source, &ranges, *page_num))
+                .collect();
+        }
+
+        metadata
+    }
+
+    /// All we know when the source couldn't be parsed
+    pub(crate) fn titled(title: String) -> Metadata {
+        Metadata {
+            title: Some(title).filter(|title| !title.is_empty()),
+            ..Metadata::default()
+        }
+    }
+}
+
+/// Copy the Info dictionary, XMP metadata and page labels of source into out, noting in the
+/// producer of both that the document was transformed
+pub(crate) fn copy(
+    out: &mut Document,
+    source: &Document,
+    page_nums: &[usize],
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Result<()> {
+    // Start from what we wrote so we keep anything the source doesn't have
+    let existing_id = info_id(out);
+    let mut merged = existing_id
+        .and_then(|id| out.get_dictionary(id).ok())
+        .cloned()
+        .unwrap_or_else(Dictionary::new);
+    if let Some(source_info) = info(source) {
+        for (key, value) in source_info.iter() {
+            merged.set(key.clone(), import_object(out, source, value, imported));
+        }
+    }
+    let producer = match source_info_text(source, b"Producer") {
+        Some(producer) => format!("{}; {}", producer, PRODUCER_NOTE),
+        None => PRODUCER_NOTE.to_string(),
+    };
+    merged.set("Producer", encode_text_string(&producer));
+    match existing_id {
+        Some(id) => {
+            out.objects.insert(id, Object::Dictionary(merged));
+        }
+        None => {
+            let id = out.add_object(merged);
+            out.trailer.set("Info", Object::Reference(id));
+        }
+    }
+
+    let catalog_id = out.trailer.get(b"Root").and_then(Object::as_reference)?;
+
+    let xmp = updated_xmp(source, &producer).map(|xmp| out.add_object(xmp));
+
+    let page_labels = output_page_labels(source, page_nums);
+
+    let catalog = out.get_object_mut(catalog_id)?.as_dict_mut()?;
+    if let Some(xmp) = xmp {
+        catalog.set("Metadata", Object::Reference(xmp));
+    }
+    if let Some(page_labels) = page_labels {
+        catalog.set("PageLabels", page_labels);
+    }
+
+    Ok(())
+}
+
+fn info_id(doc: &Document) -> Option<ObjectId> {
+    doc.trailer.get(b"Info").and_then(Object::as_reference).ok()
+}
+
+fn info(doc: &Document) -> Option<&Dictionary> {
+    as_dict(doc, doc.trailer.get(b"Info").ok()?)
+}
+
+fn source_info_text(doc: &Document, key: &[u8]) -> Option<String> {
+    info(doc)?
+        .get(key)
+        .ok()
+        .and_then(|value| doc.dereference(value).ok())
+        .and_then(|(_, value)| value.as_str().ok())
+        .map(decode_text_string)
+}
+
+fn xmp(doc: &Document) -> Option<&lopdf::Stream> {
+    let xmp = doc.catalog().ok()?.get(b"Metadata").ok()?;
+    doc.dereference(xmp)
+        .ok()
+        .and_then(|(_, xmp)| xmp.as_stream().ok())
+}
+
+/// The source's XMP packet with its producer replaced, so it agrees with the Info dictionary.
+/// None if there's no packet or it isn't UTF-8, since leaving it out is better than carrying
+/// over a producer that's no longer true.
+fn updated_xmp(source: &Document, producer: &str) -> Option<Stream> {
+    let mut xmp = xmp(source)?.clone();
+    xmp.decompress();
+    let packet = String::from_utf8(xmp.content).ok()?;
+    let packet = set_xmp_producer(&packet, &escape_xml(producer));
+
+    // Uncompressed, so tools that scan files for XMP can find it
+    let mut dict = Dictionary::new();
+    dict.set("Type", Object::Name(b"Metadata".to_vec()));
+    dict.set("Subtype", Object::Name(b"XML".to_vec()));
+    Some(Stream::new(dict, packet.into_bytes()))
+}
+
+/// Replace pdf:Producer, written either as an element or an attribute, or add it in a
+/// description of its own. producer must already be escaped.
+fn set_xmp_producer(packet: &str, producer: &str) -> String {
+    let replace_between = |open: &str, close: &str| {
+        let start = packet.find(open)? + open.len();
+        let end = start + packet[start..].find(close)?;
+        Some(format!(
+            "{}{}{}",
+            &packet[..start],
+            producer,
+            &packet[end..]
+        ))
+    };
+
+    if let Some(packet) = replace_between("<pdf:Producer>", "</pdf:Producer>")
+        .or_else(|| replace_between("pdf:Producer=\"", "\""))
+        .or_else(|| replace_between("pdf:Producer='", "'"))
+    {
+        return packet;
+    }
+
+    match packet.rfind("</rdf:RDF>") {
+        Some(end) => format!(
+            "{}<rdf:Description rdf:about=\"\" xmlns:pdf=\"{}\">\
+             <pdf:Producer>{}</pdf:Producer></rdf:Description>{}",
+            &packet[..end],
+            XMP_PDF_NAMESPACE,
+            producer,
+            &packet[end..]
+        ),
+        None => packet.to_string(),
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Page label ranges of the document, as (index of first page, label dictionary), sorted by
+/// index
+fn page_label_ranges(doc: &Document) -> Vec<(usize, &Dictionary)> {
+    let mut ranges = Vec::new();
+    if let Some(tree) = doc
+        .catalog()
+        .and_then(|catalog| catalog.get(b"PageLabels"))
+        .ok()
+        .and_then(|tree| as_dict(doc, tree))
+    {
+        collect_number_tree(doc, tree, &mut ranges, 0);
+    }
+    ranges.sort_by_key(|(index, _)| *index);
+    ranges
+}
+
+fn collect_number_tree<'a>(
+    doc: &'a Document,
+    node: &'a Dictionary,
+    entries: &mut Vec<(usize, &'a Dictionary)>,
+    depth: usize,
+) {
+    if depth > MAX_DEPTH {
+        return;
+    }
+
+    if let Ok(nums) = node.get(b"Nums").and_then(Object::as_array) {
+        // Alternating keys and values
+        for pair in nums.chunks_exact(2) {
+            if let (Ok(index), Some(value)) = (pair[0].as_i64(), as_dict(doc, &pair[1])) {
+                if index >= 0 {
+                    entries.push((index as usize, value));
+                }
+            }
+        }
+    }
+
+    if let Ok(kids) = node.get(b"Kids").and_then(Object::as_array) {
+        for kid in kids.iter().filter_map(|kid| as_dict(doc, kid)) {
+            collect_number_tree(doc, kid, entries, depth + 1);
+        }
+    }
+}
+
+/// The range a page belongs to, as (index of the first page of the range, label dictionary)
+fn range_of<'a>(
+    ranges: &[(usize, &'a Dictionary)],
+    page_num: usize,
+) -> Option<(usize, &'a Dictionary)> {
+    ranges
+        .iter()
+        .rev()
+        .find(|(start, _)| *start <= page_num)
+        .copied()
+}
+
+fn start_number(range: &Dictionary) -> i64 {
+    range.get(b"St").and_then(Object::as_i64).unwrap_or(1)
+}
+
+fn label(doc: &Document, ranges: &[(usize, &Dictionary)], page_num: usize) -> String {
+    let (start, range) = match range_of(ranges, page_num) {
+        Some(range) => range,
+        // Pages before the first range have no label, readers fall back to the page number
+        None => return (page_num + 1).to_string(),
+    };
+
+    let prefix = range
+        .get(b"P")
+        .ok()
+        .and_then(|prefix| doc.dereference(prefix).ok())
+        .and_then(|(_, prefix)| prefix.as_str().ok())
+        .map(decode_text_string)
+        .unwrap_or_default();
+    let number = start_number(range) + (page_num - start) as i64;
+
+    let numeric = match range.get(b"S").and_then(Object::as_name) {
+        Ok(b"D") => number.to_string(),
+        Ok(b"R") => roman(number).to_uppercase(),
+        Ok(b"r") => roman(number),
+        Ok(b"A") => letters(number).to_uppercase(),
+        Ok(b"a") => letters(number),
+        // No style means the label is just the prefix
+        _ => String::new(),
+    };
+
+    prefix + &numeric
+}
+
+/// Build a page label tree for the output, continuing the source's numbering on each page
+fn output_page_labels(source: &Document, page_nums: &[usize]) -> Option<Object> {
+    let ranges = page_label_ranges(source);
+    if ranges.is_empty() {
+        return None;
+    }
+
+    // The spec requires a range starting at the first page, but not every writer follows it
+    let mut decimal = Dictionary::new();
+    decimal.set("S", Object::Name(b"D".to_vec()));
+
+    let mut nums = Vec::new();
+    let mut previous: Option<(usize, usize)> = None;
+    for (index, page_num) in page_nums.iter().enumerate() {
+        let (start, range) = range_of(&ranges, *page_num).unwrap_or((0, &decimal));
+
+        // Consecutive pages of the same range continue the existing entry
+        let continues = previous
+            .map(|(previous_start, previous_page)| {
+                previous_start == start && previous_page + 1 == *page_num
+            })
+            .unwrap_or(false);
+        previous = Some((start, *page_num));
+        if continues {
+            continue;
+        }
+
+        let mut entry = Dictionary::new();
+        for (key, value) in range.iter() {
+            // Only the prefix could be indirect, and labels are small enough to inline
+            let value = source
+                .dereference(value)
+                .map(|(_, value)| value.clone())
+                .unwrap_or(Object::Null);
+            entry.set(key.clone(), value);
+        }
+        let skipped = (*page_num).saturating_sub(start) as i64;
+        if skipped > 0 || entry.has(b"St") {
+            entry.set("St", Object::Integer(start_number(range) + skipped));
+        }
+
+        nums.push(Object::Integer(index as i64));
+        nums.push(Object::Dictionary(entry));
+    }
+
+    let mut tree = Dictionary::new();
+    tree.set("Nums", Object::Array(nums));
+    Some(Object::Dictionary(tree))
+}
+
+fn roman(mut number: i64) -> String {
+    const NUMERALS: [(i64, &str); 13] = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+
+    let mut roman = String::new();
+    for (value, numeral) in NUMERALS.iter() {
+        while number >= *value {
+            roman.push_str(numeral);
+            number -= value;
+        }
+    }
+    roman
+}
+
+/// a to z, then aa to zz, etc.
+fn letters(number: i64) -> String {
+    if number < 1 {
+        return String::new();
+    }
+    let letter = (b'a' + ((number - 1) % 26) as u8) as char;
+    let repeat = ((number - 1) / 26 + 1) as usize;
+    std::iter::repeat(letter).take(repeat).collect()
+}
+
+/// Text strings are either UTF-16BE with a byte order mark or PDFDocEncoding, which matches
+/// Latin-1 for everything but a few rarely used characters
+fn decode_text_string(bytes: &[u8]) -> String {
+    if bytes.starts_with(&[0xfe, 0xff]) {
+        let units: Vec<u16> = bytes[2..]
+            .chunks_exact(2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        bytes.iter().map(|byte| *byte as char).collect()
+    }
+}
+
+fn encode_text_string(text: &str) -> Object {
+    if text.is_ascii() {
+        return Object::string_literal(text);
+    }
+
+    let mut bytes = vec![0xfe, 0xff];
+    for unit in text.encode_utf16() {
+        bytes.extend_from_slice(&unit.to_be_bytes());
+    }
+    Object::String(bytes, StringFormat::Hexadecimal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decodes_text_strings() {
+        assert_eq!(decode_text_string(b"plain"), "plain");
+        assert_eq!(
+            decode_text_string(&[0xfe, 0xff, 0x00, 0x68, 0x00, 0xe9]),
+            "hé"
+        );
+    }
+
+    #[test]
+    fn formats_label_styles() {
+        assert_eq!(roman(14), "xiv");
+        assert_eq!(roman(1999), "mcmxcix");
+        assert_eq!(letters(1), "a");
+        assert_eq!(letters(28), "bb");
+    }
+
+    fn large_source() -> Document {
+        Document::load_mem(include_bytes!("../test_assets/large_test.pdf")).unwrap()
+    }
+
+    #[test]
+    fn reads_metadata() {
+        // Front matter numbered i to xvii, then the body from 1
+        let metadata = Metadata::read(&large_source(), &[0, 16, 17, 20]);
+        assert_eq!(
+            metadata.title.as_deref(),
+            Some("The Public Domain: Enclosing the Commons of the Mind")
+        );
+        assert_eq!(metadata.author.as_deref(), Some("James Boyle"));
+        assert_eq!(
+            metadata.producer.as_deref(),
+            Some("Acrobat Distiller 4.05 for Macintosh")
+        );
+        assert_eq!(metadata.creation_date.as_deref(), Some("D:20080828110732Z"));
+        assert!(metadata.custom.contains_key("Appligent"));
+        assert!(metadata
+            .xmp
+            .unwrap()
+            .contains("<pdf:Producer>Acrobat Distiller 4.05 for Macintosh</pdf:Producer>"));
+        assert_eq!(metadata.page_labels, vec!["i", "xvii", "1", "4"]);
+
+        let source =
+            Document::load_mem(include_bytes!("../test_assets/multipage_test.pdf")).unwrap();
+        let metadata = Metadata::read(&source, &[0, 1]);
+        assert_eq!(
+            metadata.title.as_deref(),
+            Some("1498381157634149588-00225467")
+        );
+        assert!(metadata.xmp.is_none());
+        assert!(metadata.page_labels.is_empty());
+    }
+
+    #[test]
+    fn notes_the_transformation_in_both_producers() {
+        let source = large_source();
+        let mut out = Document::new();
+        let catalog_id = out.add_object(Dictionary::new());
+        out.trailer.set("Root", Object::Reference(catalog_id));
+
+        copy(&mut out, &source, &[0, 17], &mut BTreeMap::new()).unwrap();
+
+        let producer = format!("Acrobat Distiller 4.05 for Macintosh; {}", PRODUCER_NOTE);
+        assert_eq!(source_info_text(&out, b"Producer"), Some(producer.clone()));
+        let xmp = String::from_utf8(xmp(&out).unwrap().content.clone()).unwrap();
+        assert!(xmp.contains(&format!("<pdf:Producer>{}</pdf:Producer>", producer)));
+    }
+
+    #[test]
+    fn adds_a_missing_xmp_producer() {
+        let packet = "<x:xmpmeta><rdf:RDF></rdf:RDF></x:xmpmeta>";
+        assert_eq!(
+            set_xmp_producer(packet, "a &amp; b"),
+            "<x:xmpmeta><rdf:RDF><rdf:Description rdf:about=\"\" \
+             xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\"><pdf:Producer>a &amp; b</pdf:Producer>\
+             </rdf:Description></rdf:RDF></x:xmpmeta>"
+        );
+        assert_eq!(
+            set_xmp_producer("<rdf:Description pdf:Producer='old'/>", "new"),
+            "<rdf:Description pdf:Producer='new'/>"
+        );
+    }
+}
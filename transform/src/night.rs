@@ -0,0 +1,139 @@
+//! Night reading: flip each pixel's lightness so pages become light on dark, without changing
+//! the hue of colored content.
+
+use crate::background::{Classifier, Lab};
+use crate::{Color, LittleEndianRgbPixel};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+/// How bright the tinted background is compared to the configured background color
+const TINT_BRIGHTNESS: f64 = 0.2;
+
+pub(crate) struct Night {
+    /// Where the inverted paper ends up, and what it's shifted by to become the tint
+    tint_shift: Option<LittleEndianRgbPixel<f64>>,
+    /// Lab conversion is expensive and a page only has so many distinct colors
+    seen: RefCell<HashMap<LittleEndianRgbPixel<u8>, LittleEndianRgbPixel<u8>>>,
+}
+
+impl Night {
+    /// If tint_color is given, the dark background is tinted with a darkened version of it
+    pub(crate) fn new(
+        paper_color: LittleEndianRgbPixel<u8>,
+        tint_color: Option<LittleEndianRgbPixel<u8>>,
+    ) -> Self {
+        let tint_shift = tint_color.map(|tint| {
+            let inverted_paper = invert(paper_color);
+            let mut shift = [0.0; 3];
+            for channel in 0..3 {
+                shift[channel] =
+                    tint[channel] as f64 * TINT_BRIGHTNESS - inverted_paper[channel] as f64;
+            }
+            shift
+        });
+
+        Night {
+            tint_shift,
+            seen: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Pixel must hold at least 3 items (b, g, r) or may panic
+    pub(crate) fn transform_pixel(&self, pixel: &mut [u8], classifier: &impl Classifier) {
+        let key = [pixel[0], pixel[1], pixel[2]];
+        let cached = self.seen.borrow().get(&key).copied();
+        let inverted = match cached {
+            Some(inverted) => inverted,
+            None => {
+                let inverted = self.transform_color(key, classifier);
+                self.seen.borrow_mut().insert(key, inverted);
+                inverted
+            }
+        };
+
+        pixel[0] = inverted[0];
+        pixel[1] = inverted[1];
+        pixel[2] = inverted[2];
+    }
+
+    fn transform_color(
+        &self,
+        pixel: LittleEndianRgbPixel<u8>,
+        classifier: &impl Classifier,
+    ) -> LittleEndianRgbPixel<u8> {
+        let mut inverted = invert(pixel);
+
+        if let Some(shift) = self.tint_shift {
+            // Like Strategy::Blend, so anti-aliased edges fade into the tint
+            let paper_showing = 1.0 - classifier.coverage(&pixel);
+            for channel in 0..3 {
+                let tinted = inverted[channel] as f64 + shift[channel] * paper_showing;
+                inverted[channel] = tinted.round().max(0.0).min(255.0) as u8;
+            }
+        }
+
+        inverted
+    }
+}
+
+/// Invert the lightness of a (b, g, r) pixel, keeping its hue and chroma
+fn invert(pixel: LittleEndianRgbPixel<u8>) -> LittleEndianRgbPixel<u8> {
+    let lab = Lab::from(Color::new(pixel[2], pixel[1], pixel[0]));
+    let inverted = Color::from(Lab {
+        l: 100.0 - lab.l,
+        ..lab
+    });
+    inverted.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::background::QueenWise;
+
+    const WHITE: LittleEndianRgbPixel<u8> = [255, 255, 255];
+
+    fn white() -> Color {
+        Color::new(255, 255, 255)
+    }
+
+    #[test]
+    fn swaps_black_and_white() {
+        let night = Night::new(WHITE, None);
+        let classifier = QueenWise::new(white(), 90.0);
+
+        let mut paper = [255, 255, 255, 255];
+        night.transform_pixel(&mut paper, &classifier);
+        assert_eq!(paper, [0, 0, 0, 255]);
+
+        let mut text = [0, 0, 0, 255];
+        night.transform_pixel(&mut text, &classifier);
+        assert_eq!(text, [255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn keeps_hue() {
+        let night = Night::new(WHITE, None);
+        let classifier = QueenWise::new(white(), 90.0);
+
+        // A dark blue link becomes a light blue one
+        let mut link = [139, 0, 0, 255];
+        night.transform_pixel(&mut link, &classifier);
+        assert!(link[0] > link[1] && link[0] > link[2], "got {:?}", link);
+        assert!(link[1] > 0 && link[2] > 0, "got {:?}", link);
+    }
+
+    #[test]
+    fn tints_background() {
+        let night = Night::new(WHITE, Some([255, 97, 226]));
+        let classifier = QueenWise::new(white(), 90.0);
+
+        let mut paper = [255, 255, 255, 255];
+        night.transform_pixel(&mut paper, &classifier);
+        assert_eq!(paper, [51, 19, 45, 255]);
+
+        let mut text = [0, 0, 0, 255];
+        night.transform_pixel(&mut text, &classifier);
+        assert_eq!(text, [255, 255, 255, 255]);
+    }
+}
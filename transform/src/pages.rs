@@ -0,0 +1,268 @@
+//! Choosing which pages of a document to transform, with expressions like `1-3,7,10-end`.
+
+use crate::PageRange;
+use serde::de::{self, Deserializer};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A set of pages, parsed from a comma separated list of
+///
+/// - `7`, a single page. Pages are numbered from 1.
+/// - `1-3` or `10-end`, an inclusive range. Backwards ranges like `5-2` are an error.
+/// - `odd` or `even`
+/// - `last-5`, the last five pages
+///
+/// Selected pages are always transformed in document order, once each. Pages past the end of the
+/// document are ignored. An empty string selects no pages, like an empty `PageRange`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageSelection(Vec<Selector>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Selector {
+    Range(Bound, Bound),
+    Odd,
+    Even,
+    Last(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Bound {
+    /// One indexed
+    Page(usize),
+    End,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Invalid page selection {0:?}, expected something like \"1-3,7,10-end\"")]
+pub struct InvalidPageSelection(String);
+
+impl PageSelection {
+    /// The zero indexed page numbers selected from a document of page_count pages, in order
+    pub(crate) fn resolve(&self, page_count: usize) -> Vec<usize> {
+        let mut pages = BTreeSet::new();
+        for selector in &self.0 {
+            match *selector {
+                Selector::Range(first, last) => {
+                    let first = first.resolve(page_count).max(1);
+                    let last = last.resolve(page_count).min(page_count);
+                    pages.extend((first..=last).map(|page| page - 1));
+                }
+                Selector::Odd => pages.extend((0..page_count).step_by(2)),
+                Selector::Even => pages.extend((1..page_count).step_by(2)),
+                Selector::Last(count) => pages.extend(page_count.saturating_sub(count)..page_count),
+            }
+        }
+        pages.into_iter().collect()
+    }
+
+    /// If the zero indexed page_num is selected from a document of page_count pages
+    pub(crate) fn contains(&self, page_num: usize, page_count: usize) -> bool {
+        if page_num >= page_count {
+            return false;
+        }
+        self.0.iter().any(|selector| match *selector {
+            Selector::Range(first, last) => {
+                (first.resolve(page_count)..=last.resolve(page_count)).contains(&(page_num + 1))
+            }
+            Selector::Odd => page_num % 2 == 0,
+            Selector::Even => page_num % 2 == 1,
+            Selector::Last(count) => page_num + count >= page_count,
+        })
+    }
+}
+
+impl Bound {
+    fn resolve(self, page_count: usize) -> usize {
+        match self {
+            Bound::Page(page) => page,
+            Bound::End => page_count,
+        }
+    }
+}
+
+impl From<PageRange> for PageSelection {
+    fn from(range: PageRange) -> Self {
+        if range.count == 0 {
+            return PageSelection(Vec::new());
+        }
+        PageSelection(vec![Selector::Range(
+            Bound::Page(range.starting_index.saturating_add(1)),
+            Bound::Page(range.starting_index.saturating_add(range.count)),
+        )])
+    }
+}
+
+impl FromStr for PageSelection {
+    type Err = InvalidPageSelection;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        if src.trim().is_empty() {
+            return Ok(PageSelection(Vec::new()));
+        }
+        let invalid = || InvalidPageSelection(src.to_string());
+        let page = |page: &str| match page.parse::<usize>() {
+            Ok(0) | Err(_) => Err(invalid()),
+            Ok(page) => Ok(page),
+        };
+        let bound = |bound: &str| match bound {
+            "end" => Ok(Bound::End),
+            bound => page(bound).map(Bound::Page),
+        };
+
+        let selectors = src
+            .split(',')
+            .map(|selector| {
+                let selector = selector.trim().to_lowercase();
+                match selector.as_str() {
+                    "odd" => Ok(Selector::Odd),
+                    "even" => Ok(Selector::Even),
+                    selector if selector.starts_with("last-") => {
+                        page(selector["last-".len()..].trim()).map(Selector::Last)
+                    }
+                    selector => match selector.find('-') {
+                        Some(dash) => match (
+                            bound(selector[..dash].trim())?,
+                            bound(selector[dash + 1..].trim())?,
+                        ) {
+                            // Would select nothing, which is more likely a typo than intended
+                            (Bound::Page(start), Bound::Page(end)) if start > end => Err(invalid()),
+                            (start, end) => Ok(Selector::Range(start, end)),
+                        },
+                        None => page(selector)
+                            .map(|page| Selector::Range(Bound::Page(page), Bound::Page(page))),
+                    },
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(PageSelection(selectors))
+    }
+}
+
+impl fmt::Display for PageSelection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, selector) in self.0.iter().enumerate() {
+            if index > 0 {
+                write!(f, ",")?;
+            }
+            match selector {
+                Selector::Range(first, last) if first == last => write!(f, "{}", first)?,
+                Selector::Range(first, last) => write!(f, "{}-{}", first, last)?,
+                Selector::Odd => write!(f, "odd")?,
+                Selector::Even => write!(f, "even")?,
+                Selector::Last(count) => write!(f, "last-{}", count)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Bound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Bound::Page(page) => write!(f, "{}", page),
+            Bound::End => write!(f, "end"),
+        }
+    }
+}
+
+impl Serialize for PageSelection {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for PageSelection {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn resolve(src: &str, page_count: usize) -> Vec<usize> {
+        src.parse::<PageSelection>().unwrap().resolve(page_count)
+    }
+
+    #[test]
+    fn resolves_expressions() {
+        assert_eq!(resolve("1-3,7,10-end", 12), vec![0, 1, 2, 6, 9, 10, 11]);
+        assert_eq!(resolve("odd", 5), vec![0, 2, 4]);
+        assert_eq!(resolve("even", 5), vec![1, 3]);
+        assert_eq!(resolve("last-2", 5), vec![3, 4]);
+        // Overlaps are only included once, in document order
+        assert_eq!(resolve(" 4 , 1-2, even ", 4), vec![0, 1, 3]);
+        // Past the end of the document
+        assert_eq!(resolve("3-9,20", 4), vec![2, 3]);
+        assert_eq!(resolve("last-10", 3), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn contains_what_it_resolves_to() {
+        for src in &["1-3,7,10-end", "odd", "even", "last-2", "3-9,20", "end-3"] {
+            let selection: PageSelection = src.parse().unwrap();
+            let resolved = selection.resolve(12);
+            for page_num in 0..14 {
+                assert_eq!(
+                    selection.contains(page_num, 12),
+                    resolved.contains(&page_num),
+                    "{:?} page {}",
+                    src,
+                    page_num
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for src in &["0", "1-", "a", "last-", "1,,2", "-3", ",", "5-2"] {
+            assert!(src.parse::<PageSelection>().is_err(), "{:?} parsed", src);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_strings() {
+        let selection: PageSelection = "1-3,7,10-END,odd,last-5".parse().unwrap();
+
+        assert_eq!(selection.to_string(), "1-3,7,10-end,odd,last-5");
+        assert_eq!(selection.to_string().parse(), Ok(selection));
+
+        let empty = PageSelection::from(PageRange {
+            starting_index: 3,
+            count: 0,
+        });
+        assert_eq!(empty.to_string(), "");
+        assert_eq!("".parse(), Ok(empty));
+    }
+
+    #[test]
+    fn converts_page_ranges() {
+        let range = PageRange {
+            starting_index: 1,
+            count: 2,
+        };
+
+        assert_eq!(PageSelection::from(range).resolve(10), vec![1, 2]);
+
+        // All the rest of the document
+        let range = PageRange {
+            starting_index: 8,
+            count: usize::MAX,
+        };
+        assert_eq!(PageSelection::from(range).resolve(10), vec![8, 9]);
+    }
+}
@@ -1,4 +1,8 @@
-use crate::{Color, PageRange, Quality, Result, TransformationState};
+use crate::pool::Pool;
+use crate::{
+    Color, PageSelection, Quality, Result, TransformationError, TransformationState,
+    TransformationStateOptions,
+};
 use serde::Serialize;
 use serde_json;
 use std::{convert::TryInto, io, mem};
@@ -10,16 +14,30 @@ const HEADER_IMG_POSTFIX: &'static [u8; HEADER_POSTFIX_SIZE] = b"IMG";
 const HEADER_META_POSTFIX: &'static [u8; HEADER_POSTFIX_SIZE] = b"MET";
 const HEADER_SIZE: usize = HEADER_PREFIX.len() + HEADER_OFFSET_BYTES + HEADER_POSTFIX_SIZE;
 
-fn transform(
+/// The pages as a stream of PNGs, each after a `PPDF` header, preceded by the document's
+/// metadata as JSON
+pub fn transform(
     in_blob: Vec<u8>,
-    selected_page_range: Option<PageRange>,
+    selected_pages: Option<PageSelection>,
     quality: Quality,
     background_color: Option<Color>,
 ) -> Result<Images> {
-    TransformationState::try_new(in_blob, selected_page_range, quality, background_color)
+    TransformationState::try_new(in_blob, selected_pages, quality, background_color)
         .map(|transformation| Images::new(transformation))
 }
 
+pub fn transform_with_options(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+) -> Result<Images> {
+    TransformationState::try_new_with_options(in_blob, options)
+        .map(|transformation| Images::new(transformation))
+}
+
+fn transform_png(state: &TransformationState, offset: usize) -> Result<Vec<u8>> {
+    state.transform_page(offset).and_then(|page| page.to_png())
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 struct ImageHeader {
     size: u32,
@@ -60,7 +78,7 @@ struct ImagesMetadata {
 }
 
 #[derive(Debug)]
-struct Images {
+pub struct Images {
     transformation: TransformationState,
     /// Bytes that have yet to be read, in reverse order such that one could get
     // the first three bytes in order with
@@ -69,15 +87,19 @@ struct Images {
     /// Offset from the range start to the next page to transform
     next_page: usize,
     has_queued_metadata: bool,
+    /// Transforms pages ahead of time if we have more than one worker
+    pool: Option<Pool<Vec<u8>>>,
 }
 
 impl Images {
     fn new(transformation: TransformationState) -> Images {
+        let pool = transformation.pool(transform_png);
         Images {
             transformation,
             unread: Vec::new(),
             next_page: 0,
             has_queued_metadata: false,
+            pool,
         }
     }
 }
@@ -89,8 +111,8 @@ impl io::Read for Images {
             unread,
             next_page,
             has_queued_metadata,
+            pool,
         } = self;
-        let range = trans.options.page_range;
 
         if !*has_queued_metadata {
             let meta = ImagesMetadata {
@@ -110,17 +132,20 @@ impl io::Read for Images {
             *has_queued_metadata = true;
         }
 
-        if unread.len() == 0 && !range.includes(*next_page, trans.doc.page_count) {
+        if unread.len() == 0 && !trans.includes_offset(*next_page) {
             // finished transforming, so nothing we can output
             return Ok(0);
         }
 
         if unread.len() == 0 {
             // transform another page
-            let mut image = trans
-                .transform_page(*next_page)
-                .and_then(|page| page.to_png())
-                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+            let image = match pool {
+                Some(pool) => pool
+                    .next()
+                    .unwrap_or(Err(TransformationError::NonexistentPage(*next_page))),
+                None => transform_png(trans, *next_page),
+            };
+            let mut image = image.map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
             image.reverse();
 
             let mut header = ImageHeader::try_new(HEADER_IMG_POSTFIX, image.len())?.to_bytes()?;
@@ -153,8 +178,16 @@ pub fn transform_page(
     background_color: Option<Color>,
 ) -> Result<Vec<u8>> {
     TransformationState::try_new(in_blob, None, quality, background_color)
-        .and_then(|trans| trans.transform_page(page))
-        .and_then(|page| page.to_png())
+        .and_then(|trans| transform_png(&trans, page))
+}
+
+pub fn transform_page_with_options(
+    in_blob: Vec<u8>,
+    page: usize,
+    options: TransformationStateOptions,
+) -> Result<Vec<u8>> {
+    TransformationState::try_new_with_options(in_blob, options)
+        .and_then(|trans| transform_png(&trans, page))
 }
 
 #[cfg(test)]
@@ -170,6 +203,25 @@ mod test {
         transform(get_in_blob(), None, Quality::High, None).unwrap()
     }
 
+    #[test]
+    fn parallel_output_matches_serial() {
+        let read_all = |workers| {
+            let options = TransformationStateOptions {
+                quality: Quality::ExtremeLow,
+                workers,
+                ..TransformationStateOptions::default()
+            };
+            let mut buf = Vec::new();
+            transform_with_options(get_in_blob(), options)
+                .unwrap()
+                .read_to_end(&mut buf)
+                .unwrap();
+            buf
+        };
+
+        assert_eq!(read_all(1), read_all(4));
+    }
+
     #[test]
     fn can_create() {
         get_unchecked();
@@ -1,13 +1,62 @@
-use crate::{Color, PageRange, Quality, Result, TransformationState, TransformedPage};
+use crate::budget::{PageCost, SizeBudget};
+use crate::encoding::Encoding;
+use crate::pool::Pool;
+use crate::streaming::StreamingPdf;
+use crate::{
+    Color, Metadata, OutputPage, PageSelection, PageSettings, Quality, Rendering, Result,
+    TransformationError, TransformationState, TransformationStateOptions,
+};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 
 pub fn transform(
     in_blob: Vec<u8>,
-    selected_page_range: Option<PageRange>,
+    selected_pages: Option<PageSelection>,
     quality: Quality,
     background_color: Option<Color>,
 ) -> Result<Progress> {
-    TransformationState::try_new(in_blob, selected_page_range, quality, background_color)
-        .map(|state| Progress::new(0, Vec::new(), state))
+    TransformationState::try_new(in_blob, selected_pages, quality, background_color)
+        .and_then(|state| Progress::start(state, None))
+}
+
+pub fn transform_with_options(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+) -> Result<Progress> {
+    TransformationState::try_new_with_options(in_blob, options)
+        .and_then(|state| Progress::start(state, None))
+}
+
+/// Like `transform_with_options`, but each page is written to sink instead of being kept in
+/// memory, so memory use doesn't grow with the number of pages. `Complete::into_bytes` is
+/// empty, the PDF is in sink.
+pub fn transform_to_writer(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+    sink: Box<dyn Write>,
+) -> Result<Progress> {
+    TransformationState::try_new_with_options(in_blob, options)
+        .and_then(|state| Progress::start(state, Some(sink)))
+}
+
+/// Like `transform_to_writer`, writing to a new file in the temp directory. Returns the path
+/// of the file, which is left for the caller to remove. If the PDF can't be opened no file is
+/// left behind.
+pub fn transform_to_temp_file(
+    in_blob: Vec<u8>,
+    options: TransformationStateOptions,
+) -> Result<(Progress, PathBuf)> {
+    let state = TransformationState::try_new_with_options(in_blob, options)?;
+    let path = std::env::temp_dir().join(format!("{}.pdf", uuid::Uuid::new_v4()));
+    let file = File::create(&path)?;
+    match Progress::start(state, Some(Box::new(BufWriter::new(file)))) {
+        Ok(progress) => Ok((progress, path)),
+        Err(err) => {
+            fs::remove_file(&path).ok();
+            Err(err)
+        }
+    }
 }
 
 pub enum Update {
@@ -18,16 +67,35 @@ pub enum Update {
 pub struct Complete {
     original_title: String,
     bytes: Vec<u8>,
+    renderings: Vec<Rendering>,
+    paper_colors: Vec<Option<Color>>,
+    settings: Vec<PageSettings>,
+    metadata: Metadata,
+    copied_structure: bool,
 }
 
 impl Complete {
-    fn new(original_title: String, bytes: Vec<u8>) -> Self {
+    fn new(
+        original_title: String,
+        bytes: Vec<u8>,
+        renderings: Vec<Rendering>,
+        paper_colors: Vec<Option<Color>>,
+        settings: Vec<PageSettings>,
+        metadata: Metadata,
+        copied_structure: bool,
+    ) -> Self {
         Complete {
             original_title,
             bytes,
+            renderings,
+            paper_colors,
+            settings,
+            metadata,
+            copied_structure,
         }
     }
 
+    /// Empty if the PDF was written to a sink as it was transformed
     pub fn into_bytes(self) -> Vec<u8> {
         self.bytes
     }
@@ -35,62 +103,193 @@ impl Complete {
     pub fn original_title(&self) -> &str {
         self.original_title.as_str()
     }
+
+    /// How each output page was written, in output order
+    pub fn renderings(&self) -> &[Rendering] {
+        &self.renderings
+    }
+
+    /// The paper color each output page was recolored against, in output order. None for pages
+    /// that kept their original content.
+    pub fn paper_colors(&self) -> &[Option<Color>] {
+        &self.paper_colors
+    }
+
+    /// The settings each output page was written with, in output order. Picked per page if the
+    /// output had a target size.
+    pub fn settings(&self) -> &[PageSettings] {
+        &self.settings
+    }
+
+    /// The metadata of the source document, which was also copied into the output. Only the
+    /// title if its structure wasn't copied.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Whether the links, outline, page labels and metadata of the source were copied into the
+    /// output. Not if lopdf couldn't read the source, or couldn't decrypt it, see
+    /// `Rendering::RasterFallback`.
+    pub fn copied_structure(&self) -> bool {
+        self.copied_structure
+    }
+}
+
+/// Where transformed pages are written as soon as they're transformed
+enum Output {
+    Memory(StreamingPdf<Vec<u8>>),
+    Sink(StreamingPdf<Box<dyn Write>>),
+}
+
+impl Output {
+    fn add_page(
+        &mut self,
+        page: OutputPage,
+        encoding: Option<Encoding>,
+    ) -> Result<Option<Encoding>> {
+        match self {
+            Output::Memory(stream) => stream.add_page(page, encoding),
+            Output::Sink(stream) => stream.add_page(page, encoding),
+        }
+    }
+
+    fn bytes_written(&self) -> usize {
+        match self {
+            Output::Memory(stream) => stream.bytes_written(),
+            Output::Sink(stream) => stream.bytes_written(),
+        }
+    }
 }
 
 pub struct Progress {
     percent: f64,
     state: TransformationState,
-    transformed_pages: Vec<TransformedPage>,
+    output: Output,
+    renderings: Vec<Rendering>,
+    paper_colors: Vec<Option<Color>>,
+    settings: Vec<PageSettings>,
     /// The offset from the start of the range to the next page to transform
     next_offset: usize,
+    /// Transforms pages ahead of time if we have more than one worker
+    pool: Option<Pool<OutputPage>>,
+    /// Picks the quality of each page if the output has a target size
+    budget: Option<SizeBudget>,
 }
 
 impl Progress {
-    fn new(
-        next_offset: usize,
-        transformed_pages: Vec<TransformedPage>,
-        state: TransformationState,
-    ) -> Self {
-        // We add one to the rhs to account for the fact that we aren't done
-        // after we process the last page, there's one more step.
-        let percent = next_offset as f64 / (state.options.page_range.count + 1) as f64;
-
-        Progress {
-            next_offset,
-            percent,
-            transformed_pages,
+    /// Writes the PDF into memory if there's no sink
+    fn start(state: TransformationState, sink: Option<Box<dyn Write>>) -> Result<Self> {
+        let title = &state.doc.original_title;
+        let encoding = state.options.encoding;
+        let encryption = state.output_encryption();
+        let encryption = encryption.as_ref();
+        let output = match sink {
+            Some(sink) => Output::Sink(StreamingPdf::new(sink, title, encoding, encryption)?),
+            None => Output::Memory(StreamingPdf::new(Vec::new(), title, encoding, encryption)?),
+        };
+        let budget = match state.options.target_size {
+            Some(target_size) => Some(SizeBudget::sample(&state, target_size)?),
+            None => None,
+        };
+        let pool = state.pool(TransformationState::transform_page_for_pdf);
+
+        Ok(Progress {
+            percent: 0.0,
             state,
-        }
+            output,
+            renderings: Vec::new(),
+            paper_colors: Vec::new(),
+            settings: Vec::new(),
+            next_offset: 0,
+            pool,
+            budget,
+        })
     }
 
     pub fn percent_done(&self) -> f64 {
         self.percent
     }
 
-    pub fn next(self) -> Update {
+    /// The zero indexed number of the page transformed by the next call to `next`, None once
+    /// every page is done
+    pub fn current_page(&self) -> Option<usize> {
+        self.state.page_num(self.next_offset).ok()
+    }
+
+    pub fn next(mut self) -> Update {
+        let next_offset = self.next_offset;
+        if !self.state.includes_offset(next_offset) {
+            return Update::Complete(self.complete());
+        }
+
+        let state = &self.state;
+        // Picked by the budget, otherwise every page uses the document's encoding
+        let mut encoding = None;
+        let page = match (&mut self.pool, &self.budget) {
+            (Some(pool), _) => pool
+                .next()
+                .unwrap_or(Err(TransformationError::NonexistentPage(next_offset))),
+            (None, Some(budget)) => state.page_points(next_offset).and_then(|(width, height)| {
+                let (quality, picked) = budget.plan(width, height);
+                encoding = Some(picked);
+                state.transform_page_for_pdf_at(next_offset, quality)
+            }),
+            (None, None) => state.transform_page_for_pdf(next_offset),
+        };
+        let added = page.and_then(|page| {
+            self.renderings.push(page.rendering());
+            self.paper_colors.push(page.paper_color());
+            let mut settings = page.settings();
+            let cost = PageCost::of(&page);
+            let written_before = self.output.bytes_written();
+            settings.encoding = self.output.add_page(page, encoding)?;
+            if let (Some(budget), Some(encoding)) = (&mut self.budget, encoding) {
+                let written = self.output.bytes_written() - written_before;
+                budget.record(cost, written, encoding);
+            }
+            self.settings.push(settings);
+            Ok(())
+        });
+
+        match added {
+            Ok(()) => {
+                self.next_offset += 1;
+                // We add one to the rhs to account for the fact that we aren't done
+                // after we process the last page, there's one more step.
+                self.percent =
+                    self.next_offset as f64 / (self.state.included_page_count() + 1) as f64;
+                Update::Progress(self)
+            }
+            Err(err) => Update::Complete(Err(err)),
+        }
+    }
+
+    fn complete(self) -> Result<Complete> {
         let Progress {
-            next_offset,
-            mut transformed_pages,
             state,
+            output,
+            renderings,
+            paper_colors,
+            settings,
             ..
         } = self;
-        let range = &state.options.page_range;
-
-        if range.includes(next_offset, state.doc.page_count) {
-            let page = state.transform_page(next_offset);
-            page.map(|page| {
-                transformed_pages.push(page);
-                Update::Progress(Progress::new(next_offset + 1, transformed_pages, state))
-            })
-            .unwrap_or_else(|err| Update::Complete(Err(err)))
-        } else {
-            let original_title = state.doc.original_title.clone();
-            Update::Complete(
-                state
-                    .to_pdf(transformed_pages)
-                    .map(|bytes| Complete::new(original_title, bytes)),
-            )
-        }
+        let original_title = state.doc.original_title.clone();
+
+        let (bytes, metadata) = match output {
+            Output::Memory(stream) => state.finish_stream(stream)?,
+            Output::Sink(stream) => (Vec::new(), state.finish_stream(stream)?.1),
+        };
+        let copied_structure = metadata.is_some();
+        let metadata = metadata.unwrap_or_else(|| Metadata::titled(original_title.clone()));
+        Ok(Complete::new(
+            original_title,
+            bytes,
+            renderings,
+            paper_colors,
+            settings,
+            metadata,
+            copied_structure,
+        ))
     }
 
     pub fn finish(self) -> Result<Complete> {
@@ -107,14 +306,15 @@ impl Progress {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::PageRange;
 
     // TODO: Add visual tests. Right now all we test is that some output bytes are produced.
     fn get_in_blob() -> Vec<u8> {
         include_bytes!("../test_assets/multipage_test.pdf").to_vec()
     }
 
-    fn transform_unchecked_finish(page_range: Option<PageRange>, quality: Quality) -> Vec<u8> {
-        transform(get_in_blob(), page_range, quality, None)
+    fn transform_unchecked_finish(pages: Option<PageSelection>, quality: Quality) -> Vec<u8> {
+        transform(get_in_blob(), pages, quality, None)
             .unwrap()
             .finish()
             .unwrap()
@@ -124,10 +324,13 @@ mod test {
     #[test]
     fn transforms_pdf() {
         let out_blob = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 0,
-                count: 1,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 0,
+                    count: 1,
+                }
+                .into(),
+            ),
             Quality::ExtremeLow,
         );
         assert!(!out_blob.is_empty());
@@ -136,10 +339,13 @@ mod test {
     #[test]
     fn transforms_pdf_high_quality() {
         let out_blob = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 0,
-                count: 1,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 0,
+                    count: 1,
+                }
+                .into(),
+            ),
             Quality::High,
         );
         assert!(!out_blob.is_empty());
@@ -150,34 +356,46 @@ mod test {
         let out_blob_entire = transform_unchecked_finish(None, Quality::ExtremeLow);
 
         let out_blob_partial = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 0,
-                count: 1,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 0,
+                    count: 1,
+                }
+                .into(),
+            ),
             Quality::ExtremeLow,
         );
 
         let out_blob_none = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 0,
-                count: 0,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 0,
+                    count: 0,
+                }
+                .into(),
+            ),
             Quality::ExtremeLow,
         );
 
         let out_blob_none_2 = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 100,
-                count: 10,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 100,
+                    count: 10,
+                }
+                .into(),
+            ),
             Quality::ExtremeLow,
         );
 
         let out_blob_part_of_range = transform_unchecked_finish(
-            Some(PageRange {
-                starting_index: 0,
-                count: 100,
-            }),
+            Some(
+                PageRange {
+                    starting_index: 0,
+                    count: 100,
+                }
+                .into(),
+            ),
             Quality::ExtremeLow,
         );
 
@@ -187,6 +405,29 @@ mod test {
         assert_eq!(out_blob_part_of_range.len(), out_blob_entire.len())
     }
 
+    #[test]
+    fn handles_page_expressions() {
+        let page_count = |pages: &str| {
+            transform(
+                get_in_blob(),
+                Some(pages.parse().unwrap()),
+                Quality::ExtremeLow,
+                None,
+            )
+            .unwrap()
+            .finish()
+            .unwrap()
+            .renderings()
+            .len()
+        };
+
+        assert_eq!(
+            page_count("1,3-end"),
+            page_count("odd") + page_count("even") - 1
+        );
+        assert_eq!(page_count("last-1,1,1-1"), 2);
+    }
+
     #[test]
     fn handles_quality() {
         let qualities = [
@@ -200,10 +441,13 @@ mod test {
             .iter()
             .map(|quality| {
                 transform_unchecked_finish(
-                    Some(PageRange {
-                        starting_index: 0,
-                        count: 1,
-                    }),
+                    Some(
+                        PageRange {
+                            starting_index: 0,
+                            count: 1,
+                        }
+                        .into(),
+                    ),
                     *quality,
                 )
                 .len()
@@ -252,4 +496,196 @@ mod test {
             panic!("Too few updates for document size");
         }
     }
+
+    #[test]
+    fn streams_to_a_file() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            ..TransformationStateOptions::default()
+        };
+        let in_memory = transform_with_options(get_in_blob(), options.clone())
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        let (progress, path) = transform_to_temp_file(get_in_blob(), options).unwrap();
+        let streamed = progress.finish().unwrap();
+        let doc = lopdf::Document::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(streamed.into_bytes().is_empty());
+        assert_eq!(doc.get_pages().len(), in_memory.renderings().len());
+    }
+
+    #[test]
+    fn reports_the_encoding_of_each_page() {
+        use crate::encoding::Encoding;
+
+        let complete = transform_with_options(
+            get_in_blob(),
+            TransformationStateOptions {
+                quality: Quality::ExtremeLow,
+                encoding: Encoding::Jpeg { quality: 50 },
+                ..TransformationStateOptions::default()
+            },
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        assert!(complete
+            .settings()
+            .iter()
+            .all(|settings| settings.encoding == Some(Encoding::Jpeg { quality: 50 })));
+        assert!(lopdf::Document::load_mem(&complete.into_bytes()).is_ok());
+    }
+
+    #[test]
+    fn applies_page_overrides() {
+        use crate::PageOverride;
+
+        let complete = transform_with_options(
+            get_in_blob(),
+            TransformationStateOptions {
+                quality: Quality::ExtremeLow,
+                pages: Some("1-3".parse().unwrap()),
+                overrides: vec![
+                    PageOverride {
+                        pages: "1".parse().unwrap(),
+                        background_color: None,
+                        quality: None,
+                        pass_through: true,
+                    },
+                    PageOverride {
+                        pages: "1-2".parse().unwrap(),
+                        background_color: Some(Color::new(0, 0, 0)),
+                        quality: Some(Quality::Low),
+                        pass_through: false,
+                    },
+                ],
+                ..TransformationStateOptions::default()
+            },
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        // The first override wins for page 1
+        assert_eq!(
+            complete.renderings(),
+            &[Rendering::Original, Rendering::Raster, Rendering::Raster]
+        );
+        let ppis: Vec<_> = complete.settings().iter().map(|s| s.ppi).collect();
+        assert_eq!(ppis[0], None);
+        assert!(ppis[1] > ppis[2]);
+        assert!(lopdf::Document::load_mem(&complete.into_bytes()).is_ok());
+    }
+
+    #[test]
+    fn encrypts_the_output() {
+        use crate::encryption::{Encryption, OutputEncryption, Permissions};
+
+        let transform_encrypted = |in_blob, password: Option<&str>, encryption| {
+            transform_with_options(
+                in_blob,
+                TransformationStateOptions {
+                    quality: Quality::ExtremeLow,
+                    pages: Some("1".parse().unwrap()),
+                    password: password.map(String::from),
+                    encryption,
+                    ..TransformationStateOptions::default()
+                },
+            )
+            .unwrap()
+            .finish()
+            .unwrap()
+            .into_bytes()
+        };
+        let open = |out_blob: &[u8], password: &str| {
+            TransformationState::try_new_with_options(
+                out_blob.to_vec(),
+                TransformationStateOptions {
+                    password: Some(password.to_string()),
+                    ..TransformationStateOptions::default()
+                },
+            )
+        };
+
+        let permissions = Permissions {
+            print: true,
+            copy: false,
+            modify: false,
+        };
+        let out_blob = transform_encrypted(
+            get_in_blob(),
+            None,
+            OutputEncryption::Encrypted(Encryption {
+                user_password: "lavender".to_string(),
+                owner_password: String::new(),
+                permissions,
+            }),
+        );
+        assert!(matches!(
+            open(&out_blob, ""),
+            Err(TransformationError::PasswordRequired)
+        ));
+        let state = open(&out_blob, "lavender").unwrap();
+        assert_eq!(state.doc.raw.as_ref().unwrap().permissions(), permissions);
+
+        let encrypted_source = include_bytes!("../test_assets/encrypted_test.pdf").to_vec();
+        let matching = transform_encrypted(
+            encrypted_source.clone(),
+            Some("purple"),
+            OutputEncryption::MatchSource,
+        );
+        assert!(open(&matching, "purple").is_ok());
+        assert!(open(&matching, "").is_err());
+        // The owner password of the source opens it, but the output keeps the user password
+        // and permissions
+        let restricted = transform_encrypted(
+            include_bytes!("../test_assets/restricted_test.pdf").to_vec(),
+            Some("owner"),
+            OutputEncryption::MatchSource,
+        );
+        assert!(matches!(
+            open(&restricted, "owner"),
+            Err(TransformationError::IncorrectPassword)
+        ));
+        let state = open(&restricted, "purple").unwrap();
+        assert_eq!(
+            state.doc.raw.as_ref().unwrap().permissions(),
+            Permissions {
+                print: true,
+                copy: false,
+                modify: false,
+            }
+        );
+        let unencrypted = transform_encrypted(
+            encrypted_source,
+            Some("purple"),
+            OutputEncryption::Unencrypted,
+        );
+        assert!(open(&unencrypted, "").is_ok());
+    }
+
+    #[test]
+    fn stays_under_target_size() {
+        let target_size = 100_000;
+        let complete = transform_with_options(
+            get_in_blob(),
+            TransformationStateOptions {
+                target_size: Some(target_size),
+                ..TransformationStateOptions::default()
+            },
+        )
+        .unwrap()
+        .finish()
+        .unwrap();
+
+        assert!(complete
+            .settings()
+            .iter()
+            .all(|settings| settings.ppi.is_some()));
+        assert!(complete.into_bytes().len() < target_size as usize);
+    }
 }
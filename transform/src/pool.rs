@@ -0,0 +1,198 @@
+//! Transforming several pages at once.
+//!
+//! Poppler documents can't be shared between threads, so every worker opens its own copy of the
+//! document. Pages come back in order, however long each one takes. A page that panics comes
+//! back as an error, so the pages after it still do.
+
+use crate::{Result, TransformationError, TransformationState, TransformationStateOptions};
+use std::collections::BTreeMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+/// How many pages each worker may be ahead of the page we're waiting on. Bounds the memory
+/// held by finished pages we can't hand out yet.
+const PAGES_IN_FLIGHT_PER_WORKER: usize = 2;
+
+#[derive(Debug)]
+pub(crate) struct Pool<T> {
+    /// None once we're shutting down, which tells the workers to stop
+    jobs: Option<Sender<usize>>,
+    /// Shared by the workers, and emptied when we're dropped
+    queue: Arc<Mutex<Receiver<usize>>>,
+    results: Receiver<(usize, Result<T>)>,
+    workers: Vec<JoinHandle<()>>,
+    /// Results that came back before the pages ahead of them
+    finished: BTreeMap<usize, Result<T>>,
+    next_to_dispatch: usize,
+    next_to_return: usize,
+    /// Offsets 0..page_count are transformed
+    page_count: usize,
+    max_in_flight: usize,
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// Run work for each offset in 0..page_count on up to worker_count threads
+    pub(crate) fn new(
+        bytes: &[u8],
+        options: TransformationStateOptions,
+        worker_count: usize,
+        page_count: usize,
+        work: fn(&TransformationState, usize) -> Result<T>,
+    ) -> Pool<T> {
+        let worker_count = worker_count.min(page_count).max(1);
+        let (jobs, queue) = mpsc::channel::<usize>();
+        let queue = Arc::new(Mutex::new(queue));
+        let (result_sender, results) = mpsc::channel();
+
+        let workers = (0..worker_count)
+            .map(|_| {
+                let bytes = bytes.to_vec();
+                let options = options.clone();
+                let queue = Arc::clone(&queue);
+                let result_sender = result_sender.clone();
+                thread::spawn(move || {
+                    let next_job = || queue.lock().ok()?.recv().ok();
+
+                    let state = match TransformationState::try_new_with_options(bytes, options) {
+                        Ok(state) => state,
+                        Err(err) => {
+                            // Report it against a page, the other workers can carry on
+                            if let Some(offset) = next_job() {
+                                result_sender.send((offset, Err(err))).ok();
+                            }
+                            return;
+                        }
+                    };
+
+                    while let Some(offset) = next_job() {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| work(&state, offset)))
+                            .unwrap_or(Err(TransformationError::Unknown));
+                        if result_sender.send((offset, result)).is_err() {
+                            // Nobody is listening anymore
+                            return;
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        Pool {
+            jobs: Some(jobs),
+            queue,
+            results,
+            workers,
+            finished: BTreeMap::new(),
+            next_to_dispatch: 0,
+            next_to_return: 0,
+            page_count,
+            max_in_flight: worker_count * PAGES_IN_FLIGHT_PER_WORKER,
+        }
+    }
+
+    /// The result for the next offset, or None once every offset has been returned
+    pub(crate) fn next(&mut self) -> Option<Result<T>> {
+        loop {
+            if self.next_to_return >= self.page_count {
+                return None;
+            }
+            self.dispatch();
+
+            if let Some(result) = self.finished.remove(&self.next_to_return) {
+                self.next_to_return += 1;
+                return Some(result);
+            }
+
+            match self.results.recv() {
+                Ok((offset, result)) => {
+                    self.finished.insert(offset, result);
+                }
+                // Every worker is gone, so the pages we're waiting on will never come
+                Err(_) => {
+                    self.next_to_return = self.page_count;
+                    return Some(Err(TransformationError::Unknown));
+                }
+            }
+        }
+    }
+
+    fn dispatch(&mut self) {
+        let limit = (self.next_to_return + self.max_in_flight).min(self.page_count);
+        if let Some(jobs) = &self.jobs {
+            while self.next_to_dispatch < limit {
+                if jobs.send(self.next_to_dispatch).is_err() {
+                    return;
+                }
+                self.next_to_dispatch += 1;
+            }
+        }
+    }
+}
+
+impl<T> Drop for Pool<T> {
+    fn drop(&mut self) {
+        // Closing the job channel stops the workers once they finish their current page, and
+        // emptying it keeps them from starting the pages queued behind it
+        self.jobs.take();
+        if let Ok(queue) = self.queue.lock() {
+            while queue.try_recv().is_ok() {}
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Quality;
+
+    fn get_in_blob() -> Vec<u8> {
+        include_bytes!("../test_assets/multipage_test.pdf").to_vec()
+    }
+
+    #[test]
+    fn returns_pages_in_order() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            ..TransformationStateOptions::default()
+        };
+        let page_count = TransformationState::try_new_with_options(get_in_blob(), options.clone())
+            .unwrap()
+            .page_count();
+
+        let mut pool = Pool::new(&get_in_blob(), options, 3, page_count, |state, offset| {
+            state.transform_page(offset).map(|page| page.page_num)
+        });
+
+        let mut page_nums = Vec::new();
+        while let Some(page_num) = pool.next() {
+            page_nums.push(page_num.unwrap());
+        }
+        assert_eq!(page_nums, (0..page_count).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn reports_pages_that_panic_as_errors() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            ..TransformationStateOptions::default()
+        };
+        let mut pool = Pool::new(&get_in_blob(), options, 2, 3, |_, offset| {
+            if offset == 1 {
+                panic!("Page {} panicked", offset);
+            }
+            Ok(offset)
+        });
+
+        assert_eq!(pool.next().unwrap().unwrap(), 0);
+        assert!(matches!(
+            pool.next(),
+            Some(Err(TransformationError::Unknown))
+        ));
+        assert_eq!(pool.next().unwrap().unwrap(), 2);
+        assert!(pool.next().is_none());
+    }
+}
@@ -0,0 +1,215 @@
+//! Bindings for the parts of poppler-glib the poppler crate doesn't wrap.
+//!
+//! The poppler crate keeps its pointers private, so we open a second handle on the same bytes.
+
+use crate::encryption::Permissions;
+use glib::error::ErrorDomain;
+use glib::Quark;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_uint, c_void};
+use std::ptr;
+
+/// The codes of the errors poppler-glib reports, see `PopplerError` in poppler.h
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum PopplerError {
+    /// The document is encrypted and the password was missing or wrong
+    Encrypted,
+    Other(i32),
+}
+
+const POPPLER_ERROR_ENCRYPTED: i32 = 1;
+
+// From `PopplerPermissions` in poppler.h
+const POPPLER_PERMISSIONS_OK_TO_PRINT: c_uint = 1 << 0;
+const POPPLER_PERMISSIONS_OK_TO_MODIFY: c_uint = 1 << 1;
+const POPPLER_PERMISSIONS_OK_TO_COPY: c_uint = 1 << 2;
+
+impl ErrorDomain for PopplerError {
+    fn domain() -> Quark {
+        Quark::from_string("poppler-quark")
+    }
+
+    fn code(self) -> i32 {
+        match self {
+            PopplerError::Encrypted => POPPLER_ERROR_ENCRYPTED,
+            PopplerError::Other(code) => code,
+        }
+    }
+
+    fn from(code: i32) -> Option<Self> {
+        Some(match code {
+            POPPLER_ERROR_ENCRYPTED => PopplerError::Encrypted,
+            code => PopplerError::Other(code),
+        })
+    }
+}
+
+/// A rectangle in PDF points, origin at the top left of the page
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Rectangle {
+    pub x1: f64,
+    pub y1: f64,
+    pub x2: f64,
+    pub y2: f64,
+}
+
+// The structs below mirror C layouts, so not every field is read
+#[allow(dead_code)]
+#[repr(C)]
+struct ImageMapping {
+    area: Rectangle,
+    image_id: c_int,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct GList {
+    data: *mut c_void,
+    next: *mut GList,
+    prev: *mut GList,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct GError {
+    domain: u32,
+    code: c_int,
+    message: *mut c_char,
+}
+
+#[link(name = "poppler-glib")]
+extern "C" {
+    fn poppler_document_new_from_data(
+        data: *mut c_char,
+        length: c_int,
+        password: *const c_char,
+        error: *mut *mut GError,
+    ) -> *mut c_void;
+    fn poppler_document_get_page(document: *mut c_void, index: c_int) -> *mut c_void;
+    fn poppler_document_get_permissions(document: *mut c_void) -> c_uint;
+    fn poppler_page_get_text(page: *mut c_void) -> *mut c_char;
+    fn poppler_page_get_text_layout(
+        page: *mut c_void,
+        rectangles: *mut *mut Rectangle,
+        n_rectangles: *mut c_uint,
+    ) -> c_int;
+    fn poppler_page_get_image_mapping(page: *mut c_void) -> *mut GList;
+    fn poppler_page_free_image_mapping(list: *mut GList);
+}
+
+#[link(name = "gobject-2.0")]
+extern "C" {
+    fn g_object_unref(object: *mut c_void);
+}
+
+#[link(name = "glib-2.0")]
+extern "C" {
+    fn g_free(mem: *mut c_void);
+    fn g_error_free(error: *mut GError);
+}
+
+#[derive(Debug)]
+pub(crate) struct RawDocument(*mut c_void);
+
+impl RawDocument {
+    /// Poppler doesn't copy data, so it must outlive the returned document
+    pub(crate) fn new(data: &mut [u8], password: &str) -> Option<RawDocument> {
+        let password = CString::new(password).ok()?;
+        let mut error: *mut GError = ptr::null_mut();
+        let document = unsafe {
+            poppler_document_new_from_data(
+                data.as_mut_ptr() as *mut c_char,
+                data.len() as c_int,
+                password.as_ptr(),
+                &mut error,
+            )
+        };
+
+        if !error.is_null() {
+            unsafe { g_error_free(error) };
+        }
+
+        if document.is_null() {
+            None
+        } else {
+            Some(RawDocument(document))
+        }
+    }
+
+    /// What the document's encryption lets readers do. Everything for unencrypted documents.
+    pub(crate) fn permissions(&self) -> Permissions {
+        let flags = unsafe { poppler_document_get_permissions(self.0) };
+        Permissions {
+            print: flags & POPPLER_PERMISSIONS_OK_TO_PRINT != 0,
+            copy: flags & POPPLER_PERMISSIONS_OK_TO_COPY != 0,
+            modify: flags & POPPLER_PERMISSIONS_OK_TO_MODIFY != 0,
+        }
+    }
+
+    pub(crate) fn page(&self, index: usize) -> Option<RawPage> {
+        let page = unsafe { poppler_document_get_page(self.0, index as c_int) };
+        if page.is_null() {
+            None
+        } else {
+            Some(RawPage(page))
+        }
+    }
+}
+
+impl Drop for RawDocument {
+    fn drop(&mut self) {
+        unsafe { g_object_unref(self.0) }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct RawPage(*mut c_void);
+
+impl RawPage {
+    /// The text of the page along with a rectangle for each character in it
+    pub(crate) fn text_layout(&self) -> Option<(String, Vec<Rectangle>)> {
+        unsafe {
+            let text = poppler_page_get_text(self.0);
+            if text.is_null() {
+                return None;
+            }
+            let owned_text = CStr::from_ptr(text).to_string_lossy().into_owned();
+            g_free(text as *mut c_void);
+
+            let mut rectangles: *mut Rectangle = ptr::null_mut();
+            let mut n_rectangles: c_uint = 0;
+            if poppler_page_get_text_layout(self.0, &mut rectangles, &mut n_rectangles) == 0 {
+                return None;
+            }
+            let layout = std::slice::from_raw_parts(rectangles, n_rectangles as usize).to_vec();
+            g_free(rectangles as *mut c_void);
+
+            Some((owned_text, layout))
+        }
+    }
+
+    /// Where each image drawn on the page ends up
+    pub(crate) fn image_areas(&self) -> Vec<Rectangle> {
+        let mut areas = Vec::new();
+        unsafe {
+            let list = poppler_page_get_image_mapping(self.0);
+            let mut node = list;
+            while !node.is_null() {
+                let mapping = (*node).data as *const ImageMapping;
+                if !mapping.is_null() {
+                    areas.push((*mapping).area);
+                }
+                node = (*node).next;
+            }
+            poppler_page_free_image_mapping(list);
+        }
+        areas
+    }
+}
+
+impl Drop for RawPage {
+    fn drop(&mut self) {
+        unsafe { g_object_unref(self.0) }
+    }
+}
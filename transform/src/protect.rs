@@ -0,0 +1,335 @@
+//! Keeping photos and figures as they were rendered, so their white areas aren't recolored.
+
+use crate::links::name_is;
+use crate::poppler_ext::{RawPage, Rectangle};
+use crate::tiles::Tile;
+use crate::vector::{as_dict, inherited, page_id, rectangle};
+use crate::RGBA_PIXEL_SIZE;
+use lopdf::content::Content;
+use lopdf::{Document, Object};
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// Forms covering more of the page than this are more likely a wrapper around the whole page
+/// than a figure
+const MAX_FIGURE_FRACTION: f64 = 0.9;
+
+/// What parts of a page are left untouched
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Protection {
+    Nothing,
+    /// Embedded raster images, like photos and scanned figures
+    Images,
+    /// Images plus vector figures. We can only recognize vector figures that were embedded
+    /// from another file (form XObjects), which is how most tools include PDF figures.
+    ImagesAndFigures,
+}
+
+impl Default for Protection {
+    fn default() -> Self {
+        Protection::Nothing
+    }
+}
+
+/// Areas of a page, in points from its top left
+#[derive(Debug, Default)]
+pub(crate) struct ProtectedAreas(Vec<Rectangle>);
+
+impl ProtectedAreas {
+    pub(crate) fn find(
+        protection: Protection,
+        raw: Option<&RawPage>,
+        structure: Option<&Document>,
+        page_num: usize,
+    ) -> ProtectedAreas {
+        let mut areas = Vec::new();
+        if protection == Protection::Nothing {
+            return ProtectedAreas(areas);
+        }
+
+        if let Some(raw) = raw {
+            areas.extend(raw.image_areas());
+        }
+        if let (Protection::ImagesAndFigures, Some(structure)) = (protection, structure) {
+            areas.extend(figure_areas(structure, page_num));
+        }
+
+        ProtectedAreas(areas)
+    }
+
+    /// Run transform over a rendered tile of a page (b, g, r, a pixels), then put back the
+    /// pixels in the protected areas. scale is pixels per point.
+    pub(crate) fn preserve(
+        &self,
+        img_data: &mut [u8],
+        tile: Tile,
+        scale: f64,
+        transform: impl FnOnce(&mut [u8]),
+    ) {
+        let spans = self.spans(tile, scale);
+        let saved: Vec<Vec<u8>> = spans
+            .iter()
+            .map(|span| img_data[span.clone()].to_vec())
+            .collect();
+
+        transform(img_data);
+
+        for (span, saved) in spans.into_iter().zip(saved) {
+            img_data[span].copy_from_slice(&saved);
+        }
+    }
+
+    /// The byte ranges of the tile's pixels covered by the areas, one per row of each area
+    fn spans(&self, tile: Tile, scale: f64) -> Vec<Range<usize>> {
+        let (width, height) = (tile.width as usize, tile.height as usize);
+        let to_px = |points: f64, origin: u32, max: usize, round: fn(f64) -> f64| {
+            (round(points * scale) - origin as f64)
+                .max(0.0)
+                .min(max as f64) as usize
+        };
+
+        let mut spans = Vec::new();
+        for area in &self.0 {
+            let x1 = to_px(area.x1.min(area.x2), tile.x, width, f64::floor);
+            let x2 = to_px(area.x1.max(area.x2), tile.x, width, f64::ceil);
+            let y1 = to_px(area.y1.min(area.y2), tile.y, height, f64::floor);
+            let y2 = to_px(area.y1.max(area.y2), tile.y, height, f64::ceil);
+
+            for row in y1..y2 {
+                let start = (row * width + x1) * RGBA_PIXEL_SIZE;
+                let end = (row * width + x2) * RGBA_PIXEL_SIZE;
+                spans.push(start..end);
+            }
+        }
+        spans
+    }
+}
+
+/// A PDF transformation matrix [a b c d e f]
+type Matrix = [f64; 6];
+
+const IDENTITY: Matrix = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+
+/// Where form XObjects drawn directly by the page end up, excluding ones that cover nearly
+/// the whole page
+fn figure_areas(doc: &Document, page_num: usize) -> Vec<Rectangle> {
+    let mut areas = Vec::new();
+
+    let page_id = match page_id(doc, page_num) {
+        Some(page_id) => page_id,
+        None => return areas,
+    };
+    // Poppler rotates the page for us, we'd have to rotate the areas to match
+    let rotated = inherited(doc, page_id, b"Rotate")
+        .and_then(|rotate| rotate.as_i64().ok())
+        .map(|rotate| rotate % 360 != 0)
+        .unwrap_or(false);
+    if rotated {
+        return areas;
+    }
+    let crop_box = match inherited(doc, page_id, b"CropBox")
+        .or_else(|| inherited(doc, page_id, b"MediaBox"))
+        .and_then(|crop_box| rectangle(doc, crop_box))
+    {
+        Some(crop_box) => crop_box,
+        None => return areas,
+    };
+    let xobjects = inherited(doc, page_id, b"Resources")
+        .and_then(|resources| as_dict(doc, resources))
+        .and_then(|resources| resources.get(b"XObject").ok())
+        .and_then(|xobjects| as_dict(doc, xobjects));
+    let (xobjects, operations) = match (
+        xobjects,
+        doc.get_page_content(page_id)
+            .ok()
+            .and_then(|content| Content::decode(&content).ok()),
+    ) {
+        (Some(xobjects), Some(content)) => (xobjects, content.operations),
+        _ => return areas,
+    };
+
+    let [x0, y0, x1, y1] = crop_box;
+    let (left, top) = (x0.min(x1), y0.max(y1));
+    let page_area = ((x1 - x0) * (y1 - y0)).abs();
+
+    let mut ctm = IDENTITY;
+    let mut saved = Vec::new();
+    for operation in operations {
+        match operation.operator.as_str() {
+            "q" => saved.push(ctm),
+            "Q" => ctm = saved.pop().unwrap_or(IDENTITY),
+            "cm" => {
+                if let Some(matrix) = matrix(&operation.operands) {
+                    ctm = multiply(matrix, ctm);
+                }
+            }
+            "Do" => {
+                let form = operation
+                    .operands
+                    .first()
+                    .and_then(|name| name.as_name().ok())
+                    .and_then(|name| xobjects.get(name).ok())
+                    .and_then(|form| doc.dereference(form).ok())
+                    .and_then(|(_, form)| form.as_stream().ok())
+                    .filter(|form| name_is(&form.dict, b"Subtype", b"Form"));
+                let form = match form {
+                    Some(form) => form,
+                    None => continue,
+                };
+
+                let bbox = match form
+                    .dict
+                    .get(b"BBox")
+                    .ok()
+                    .and_then(|bbox| rectangle(doc, bbox))
+                {
+                    Some(bbox) => bbox,
+                    None => continue,
+                };
+                let form_matrix = form
+                    .dict
+                    .get(b"Matrix")
+                    .ok()
+                    .and_then(|form_matrix| doc.dereference(form_matrix).ok())
+                    .and_then(|(_, form_matrix)| form_matrix.as_array().ok())
+                    .and_then(|form_matrix| matrix(form_matrix))
+                    .unwrap_or(IDENTITY);
+
+                let [x1, y1, x2, y2] = bounds(bbox, multiply(form_matrix, ctm));
+                if (x2 - x1) * (y2 - y1) > page_area * MAX_FIGURE_FRACTION {
+                    continue;
+                }
+                // Flip to Poppler's coordinates, which start at the top left of the crop box
+                areas.push(Rectangle {
+                    x1: x1 - left,
+                    y1: top - y2,
+                    x2: x2 - left,
+                    y2: top - y1,
+                });
+            }
+            _ => {}
+        }
+    }
+
+    areas
+}
+
+fn matrix(operands: &[Object]) -> Option<Matrix> {
+    let values = operands
+        .iter()
+        .map(|value| match value {
+            Object::Integer(value) => Some(*value as f64),
+            Object::Real(value) => Some(*value),
+            _ => None,
+        })
+        .collect::<Option<Vec<f64>>>()?;
+
+    match values.as_slice() {
+        [a, b, c, d, e, f] => Some([*a, *b, *c, *d, *e, *f]),
+        _ => None,
+    }
+}
+
+/// The matrix that applies first and then second
+fn multiply(first: Matrix, second: Matrix) -> Matrix {
+    let [a1, b1, c1, d1, e1, f1] = first;
+    let [a2, b2, c2, d2, e2, f2] = second;
+    [
+        a1 * a2 + b1 * c2,
+        a1 * b2 + b1 * d2,
+        c1 * a2 + d1 * c2,
+        c1 * b2 + d1 * d2,
+        e1 * a2 + f1 * c2 + e2,
+        e1 * b2 + f1 * d2 + f2,
+    ]
+}
+
+/// The axis aligned bounds [x1 y1 x2 y2] of a rectangle after transforming it
+fn bounds(rectangle: [f64; 4], matrix: Matrix) -> [f64; 4] {
+    let [a, b, c, d, e, f] = matrix;
+    let [x0, y0, x1, y1] = rectangle;
+    let corners = [(x0, y0), (x0, y1), (x1, y0), (x1, y1)];
+
+    let mut bounds = [
+        std::f64::INFINITY,
+        std::f64::INFINITY,
+        std::f64::NEG_INFINITY,
+        std::f64::NEG_INFINITY,
+    ];
+    for (x, y) in corners.iter() {
+        let x_page = a * x + c * y + e;
+        let y_page = b * x + d * y + f;
+        bounds[0] = bounds[0].min(x_page);
+        bounds[1] = bounds[1].min(y_page);
+        bounds[2] = bounds[2].max(x_page);
+        bounds[3] = bounds[3].max(y_page);
+    }
+    bounds
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn restores_protected_pixels() {
+        // A 4x2 page at one pixel per point, where the transform paints everything 0
+        let mut img_data = (0..32).collect::<Vec<u8>>();
+        let areas = ProtectedAreas(vec![Rectangle {
+            x1: 1.0,
+            y1: 1.0,
+            x2: 3.0,
+            y2: 2.0,
+        }]);
+
+        areas.preserve(&mut img_data, Tile::whole(4, 2), 1.0, |img_data| {
+            img_data.iter_mut().for_each(|byte| *byte = 0)
+        });
+
+        let mut expected = vec![0; 32];
+        expected[20..28].copy_from_slice(&[20, 21, 22, 23, 24, 25, 26, 27]);
+        assert_eq!(img_data, expected);
+    }
+
+    #[test]
+    fn areas_outside_the_page_are_clipped() {
+        let areas = ProtectedAreas(vec![Rectangle {
+            x1: -5.0,
+            y1: 1.0,
+            x2: 50.0,
+            y2: 50.0,
+        }]);
+
+        assert_eq!(areas.spans(Tile::whole(4, 2), 1.0), vec![16..32]);
+    }
+
+    #[test]
+    fn spans_are_relative_to_the_tile() {
+        let areas = ProtectedAreas(vec![Rectangle {
+            x1: 1.0,
+            y1: 1.0,
+            x2: 3.0,
+            y2: 3.0,
+        }]);
+        // The bottom right 2x2 of a 4x4 page
+        let tile = Tile {
+            x: 2,
+            y: 2,
+            width: 2,
+            height: 2,
+        };
+
+        assert_eq!(areas.spans(tile, 1.0), vec![0..4]);
+    }
+
+    #[test]
+    fn transforms_form_bounds() {
+        // Scale by 2, then move to (10, 20)
+        let ctm = multiply(
+            [2.0, 0.0, 0.0, 2.0, 0.0, 0.0],
+            [1.0, 0.0, 0.0, 1.0, 10.0, 20.0],
+        );
+
+        assert_eq!(bounds([0.0, 0.0, 5.0, 5.0], ctm), [10.0, 20.0, 20.0, 30.0]);
+    }
+}
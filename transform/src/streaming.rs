@@ -0,0 +1,431 @@
+//! Writing an output PDF page by page, so memory use doesn't grow with the length of the
+//! document.
+//!
+//! Each page's image and content are written to the sink as soon as the page is transformed.
+//! Everything else is small, so we keep it in a lopdf document (the skeleton) until the end,
+//! where it gets the same post-processing as the in-memory path before it is written out.
+
+use crate::encoding::{EncodedImage, Encoding};
+use crate::encryption::{Encryption, Encryptor};
+use crate::vector::VectorPage;
+use crate::{OutputPage, Result, TransformedPage};
+use image::GenericImageView;
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream, StringFormat};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+/// The name of the image on each page's resources
+const IMAGE_NAME: &str = "Im0";
+/// The name of the font of the text layer in each page's resources
+const FONT_NAME: &str = "F0";
+
+pub(crate) struct StreamingPdf<W: Write> {
+    sink: CountingWriter<W>,
+    skeleton: Document,
+    encoding: Encoding,
+    pages_id: ObjectId,
+    kids: Vec<Object>,
+    /// Only added once a page has a text layer
+    font_id: Option<ObjectId>,
+    /// Where each object we already wrote starts in the sink
+    offsets: BTreeMap<u32, usize>,
+    /// (index of the page in the output, page). Spliced in at the end.
+    vector_pages: Vec<(usize, VectorPage)>,
+    /// The zero indexed source page number of each output page
+    page_nums: Vec<usize>,
+    /// Every object is encrypted as it's written, if set
+    encryptor: Option<Encryptor>,
+}
+
+impl<W: Write> StreamingPdf<W> {
+    pub(crate) fn new(
+        sink: W,
+        title: &str,
+        encoding: Encoding,
+        encryption: Option<&Encryption>,
+    ) -> Result<Self> {
+        let mut sink = CountingWriter {
+            inner: sink,
+            written: 0,
+        };
+        // The comment of high bytes tells tools the file is binary
+        sink.write_all(b"%PDF-1.5\n%\xE2\xE3\xCF\xD3\n")?;
+
+        let mut skeleton = Document::with_version("1.5");
+        let pages_id = skeleton.new_object_id();
+        let mut info = Dictionary::new();
+        if !title.is_empty() {
+            info.set("Title", Object::string_literal(title));
+        }
+        let info_id = skeleton.add_object(info);
+        skeleton.trailer.set("Info", Object::Reference(info_id));
+
+        Ok(StreamingPdf {
+            sink,
+            skeleton,
+            encoding,
+            pages_id,
+            kids: Vec::new(),
+            font_id: None,
+            offsets: BTreeMap::new(),
+            vector_pages: Vec::new(),
+            page_nums: Vec::new(),
+            encryptor: encryption.map(Encryptor::new),
+        })
+    }
+
+    pub(crate) fn page_nums(&self) -> &[usize] {
+        &self.page_nums
+    }
+
+    /// How much of the PDF has been written so far. Pages that kept their original content are
+    /// only written by `finish`.
+    pub(crate) fn bytes_written(&self) -> usize {
+        self.sink.written
+    }
+
+    /// Returns the encoding of the page image, or None for pages that kept their original
+    /// content. The image is stored with encoding if given, otherwise with the document's.
+    pub(crate) fn add_page(
+        &mut self,
+        page: OutputPage,
+        encoding: Option<Encoding>,
+    ) -> Result<Option<Encoding>> {
+        self.page_nums.push(page.page_num());
+
+        let page = match page {
+            OutputPage::Raster(page) => page,
+            OutputPage::Vector(page) => {
+                // A placeholder we swap for the original content at the end
+                let size = page.size();
+                let media_box = vec![
+                    Object::Integer(0),
+                    Object::Integer(0),
+                    Object::Real(size.width.as_f64()),
+                    Object::Real(size.height.as_f64()),
+                ];
+                self.add_page_dict(media_box, Dictionary::new(), None);
+                self.vector_pages.push((self.kids.len() - 1, page));
+                return Ok(None);
+            }
+        };
+        let TransformedPage {
+            image,
+            size,
+            text_layer,
+            ..
+        } = page;
+
+        let (width, height) = image.dimensions();
+        let EncodedImage {
+            encoding,
+            dict,
+            data,
+        } = EncodedImage::new(&image, encoding.unwrap_or(self.encoding))?;
+        let image_id = self.write_stream(dict, data)?;
+        // We're done with the biggest part of the page
+        drop(image);
+
+        // One pixel every 1/ppi inches, so the image is slightly smaller than the page (see
+        // `Pt::to_px`)
+        let scale = 72.0 / size.ppi.as_f64();
+        let mut operations = vec![
+            Operation::new("q", vec![]),
+            Operation::new(
+                "cm",
+                vec![
+                    Object::Real(width as f64 * scale),
+                    Object::Integer(0),
+                    Object::Integer(0),
+                    Object::Real(height as f64 * scale),
+                    Object::Integer(0),
+                    Object::Integer(0),
+                ],
+            ),
+            Operation::new("Do", vec![Object::Name(IMAGE_NAME.into())]),
+            Operation::new("Q", vec![]),
+        ];
+
+        let mut resources = Dictionary::new();
+        let mut xobjects = Dictionary::new();
+        xobjects.set(IMAGE_NAME, Object::Reference(image_id));
+        resources.set("XObject", xobjects);
+
+        if let Some(text_layer) = text_layer {
+            let font_id = self.font_id();
+            let mut fonts = Dictionary::new();
+            fonts.set(FONT_NAME, Object::Reference(font_id));
+            resources.set("Font", fonts);
+            operations.extend(text_layer.operations(FONT_NAME, size));
+        }
+
+        let content = Content { operations }.encode()?;
+        let content_id = self.write_stream(Dictionary::new(), content)?;
+
+        let media_box = vec![
+            Object::Integer(0),
+            Object::Integer(0),
+            Object::Real(size.width.as_f64()),
+            Object::Real(size.height.as_f64()),
+        ];
+        self.add_page_dict(media_box, resources, Some(content_id));
+        Ok(Some(encoding))
+    }
+
+    /// Finish the document. post_process is given the skeleton and the output page of each
+    /// vector page to copy over what we couldn't write ourselves.
+    pub(crate) fn finish(
+        mut self,
+        post_process: impl FnOnce(&mut Document, Vec<(usize, VectorPage)>) -> Result<()>,
+    ) -> Result<W> {
+        let mut pages = Dictionary::new();
+        pages.set("Type", "Pages");
+        pages.set("Count", self.kids.len() as i64);
+        pages.set("Kids", std::mem::take(&mut self.kids));
+        self.skeleton
+            .objects
+            .insert(self.pages_id, Object::Dictionary(pages));
+
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", "Catalog");
+        catalog.set("Pages", Object::Reference(self.pages_id));
+        let catalog_id = self.skeleton.add_object(catalog);
+        self.skeleton
+            .trailer
+            .set("Root", Object::Reference(catalog_id));
+
+        let vector_pages = std::mem::take(&mut self.vector_pages);
+        post_process(&mut self.skeleton, vector_pages)?;
+        self.skeleton.compress();
+
+        let objects = std::mem::take(&mut self.skeleton.objects);
+        for (id, object) in &objects {
+            self.write_indirect_object(*id, object)?;
+        }
+
+        // Object 0 is the head of the free list. Ids we never used are free too.
+        let xref_offset = self.sink.written;
+        let size = self.skeleton.max_id + 1;
+        writeln!(self.sink, "xref\n0 {}\n0000000000 65535 f ", size)?;
+        for id in 1..size {
+            match self.offsets.get(&id) {
+                Some(offset) => writeln!(self.sink, "{:010} 00000 n ", offset)?,
+                None => writeln!(self.sink, "0000000000 65535 f ")?,
+            }
+        }
+
+        let mut trailer = self.skeleton.trailer.clone();
+        trailer.set("Size", size as i64);
+        if let Some(encryptor) = &self.encryptor {
+            trailer.set("Encrypt", encryptor.dict());
+            trailer.set("ID", encryptor.id());
+        }
+        self.sink.write_all(b"trailer\n")?;
+        write_object(&mut self.sink, &Object::Dictionary(trailer))?;
+        writeln!(self.sink, "\nstartxref\n{}\n%%EOF", xref_offset)?;
+
+        self.sink.flush()?;
+        Ok(self.sink.inner)
+    }
+
+    fn add_page_dict(
+        &mut self,
+        media_box: Vec<Object>,
+        resources: Dictionary,
+        contents: Option<ObjectId>,
+    ) {
+        let mut page = Dictionary::new();
+        page.set("Type", "Page");
+        page.set("Parent", Object::Reference(self.pages_id));
+        page.set("MediaBox", media_box);
+        page.set("Resources", resources);
+        if let Some(contents) = contents {
+            page.set("Contents", Object::Reference(contents));
+        }
+        let page_id = self.skeleton.add_object(page);
+        self.kids.push(Object::Reference(page_id));
+    }
+
+    fn font_id(&mut self) -> ObjectId {
+        if let Some(font_id) = self.font_id {
+            return font_id;
+        }
+        let mut font = Dictionary::new();
+        font.set("Type", "Font");
+        font.set("Subtype", "Type1");
+        font.set("BaseFont", "Helvetica");
+        font.set("Encoding", "WinAnsiEncoding");
+        let font_id = self.skeleton.add_object(font);
+        self.font_id = Some(font_id);
+        font_id
+    }
+
+    /// Compress a stream, unless it's already compressed, and write it out right away
+    fn write_stream(&mut self, dict: Dictionary, content: Vec<u8>) -> Result<ObjectId> {
+        let stream = compress(dict, content);
+        let id = self.skeleton.new_object_id();
+        self.write_indirect_object(id, &stream)?;
+        Ok(id)
+    }
+
+    fn write_indirect_object(&mut self, id: ObjectId, object: &Object) -> io::Result<()> {
+        let encrypted;
+        let object = match &self.encryptor {
+            Some(encryptor) => {
+                encrypted = encryptor.encrypt(id, object);
+                &encrypted
+            }
+            None => object,
+        };
+        self.offsets.insert(id.0, self.sink.written);
+        writeln!(self.sink, "{} {} obj", id.0, id.1)?;
+        write_object(&mut self.sink, object)?;
+        self.sink.write_all(b"\nendobj\n")
+    }
+}
+
+/// A stream, compressed the way lopdf compresses a whole document. Streams that already have a
+/// filter are left alone.
+pub(crate) fn compress(dict: Dictionary, content: Vec<u8>) -> Object {
+    // Document::compress is the one compression entry point lopdf exposes on every version we
+    // build with, so we borrow a scratch document to run it
+    let mut scratch = Document::new();
+    let scratch_id = scratch.add_object(Stream::new(dict, content));
+    scratch.compress();
+    scratch.objects.remove(&scratch_id).unwrap_or(Object::Null)
+}
+
+/// Tracks where in the output we are, for the cross-reference table
+struct CountingWriter<W: Write> {
+    inner: W,
+    written: usize,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.written += written;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Serialize an object. lopdf only writes whole documents.
+fn write_object(out: &mut impl Write, object: &Object) -> io::Result<()> {
+    match object {
+        Object::Null => out.write_all(b"null"),
+        Object::Boolean(value) => write!(out, "{}", value),
+        Object::Integer(value) => write!(out, "{}", value),
+        Object::Real(value) => write!(out, "{}", value),
+        Object::Name(name) => write_name(out, name),
+        Object::String(text, StringFormat::Literal) => {
+            out.write_all(b"(")?;
+            for byte in text {
+                match byte {
+                    b'(' | b')' | b'\\' => out.write_all(&[b'\\', *byte])?,
+                    b'\r' => out.write_all(b"\\r")?,
+                    _ => out.write_all(&[*byte])?,
+                }
+            }
+            out.write_all(b")")
+        }
+        Object::String(text, StringFormat::Hexadecimal) => {
+            write!(out, "<{}>", hex::encode(text))
+        }
+        Object::Array(array) => {
+            out.write_all(b"[")?;
+            for (index, item) in array.iter().enumerate() {
+                if index > 0 {
+                    out.write_all(b" ")?;
+                }
+                write_object(out, item)?;
+            }
+            out.write_all(b"]")
+        }
+        Object::Dictionary(dict) => write_dictionary(out, dict),
+        Object::Stream(stream) => {
+            let mut dict = stream.dict.clone();
+            dict.set("Length", stream.content.len() as i64);
+            write_dictionary(out, &dict)?;
+            out.write_all(b"\nstream\n")?;
+            out.write_all(&stream.content)?;
+            out.write_all(b"\nendstream")
+        }
+        Object::Reference(id) => write!(out, "{} {} R", id.0, id.1),
+    }
+}
+
+fn write_dictionary(out: &mut impl Write, dict: &Dictionary) -> io::Result<()> {
+    out.write_all(b"<<")?;
+    for (key, value) in dict.iter() {
+        write_name(out, key)?;
+        out.write_all(b" ")?;
+        write_object(out, value)?;
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b">>")
+}
+
+fn write_name(out: &mut impl Write, name: &[u8]) -> io::Result<()> {
+    out.write_all(b"/")?;
+    for byte in name {
+        // Delimiters, whitespace and anything outside printable ASCII must be escaped
+        if b"()<>[]{}/%#".contains(byte) || *byte < b'!' || *byte > b'~' {
+            write!(out, "#{:02X}", byte)?;
+        } else {
+            out.write_all(&[*byte])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn written(object: &Object) -> String {
+        let mut out = Vec::new();
+        write_object(&mut out, object).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn writes_objects() {
+        let mut dict = Dictionary::new();
+        dict.set("Type", "Page");
+        dict.set(
+            "Kids",
+            vec![Object::Reference((3, 0)), 1.into(), Object::Real(0.5)],
+        );
+
+        assert_eq!(
+            written(&Object::Dictionary(dict)),
+            "<</Type /Page\n/Kids [3 0 R 1 0.5]\n>>"
+        );
+        assert_eq!(written(&Object::string_literal("a (b\\")), "(a \\(b\\\\)");
+        assert_eq!(written(&Object::Name(b"A B".to_vec())), "/A#20B");
+    }
+
+    #[test]
+    fn writes_a_readable_document() {
+        let mut out = Vec::new();
+        {
+            let pdf = StreamingPdf::new(&mut out, "Title", Encoding::default(), None).unwrap();
+            pdf.finish(|_, _| Ok(())).unwrap();
+        }
+
+        let doc = Document::load_mem(&out).unwrap();
+        assert_eq!(doc.get_pages().len(), 0);
+        let info = doc
+            .trailer
+            .get(b"Info")
+            .and_then(Object::as_reference)
+            .and_then(|id| doc.get_dictionary(id))
+            .unwrap();
+        assert_eq!(info.get(b"Title").unwrap().as_str().unwrap(), b"Title");
+    }
+}
@@ -0,0 +1,269 @@
+//! An invisible copy of a page's text, laid over the rendered image so search and copy/paste
+//! keep working on rasterized pages.
+//!
+//! The text is written with the builtin Helvetica in WinAnsiEncoding, which only covers Western
+//! European scripts. Words with other characters, e.g. Greek, Cyrillic or CJK, are left out
+//! rather than written wrong, so they can't be found or copied. Writing them would need a font
+//! embedded in every output.
+
+use crate::poppler_ext::{RawPage, Rectangle};
+use crate::{PageSize, Pt};
+use lopdf::content::Operation;
+use lopdf::Object;
+
+/// Helvetica glyphs average roughly half an em wide. We only use this to stretch each word
+/// to the width Poppler measured, so it doesn't need to be exact.
+const AVERAGE_GLYPH_WIDTH_EM: f64 = 0.5;
+
+/// Text rendering mode 3 neither fills nor strokes
+const INVISIBLE_RENDERING_MODE: i64 = 3;
+
+/// The characters of WinAnsiEncoding from 0x80 to 0x9f, where it differs from Latin-1. None
+/// for the codes it leaves unused.
+const WIN_ANSI_EXTRAS: [Option<char>; 32] = [
+    Some('€'),
+    None,
+    Some('‚'),
+    Some('ƒ'),
+    Some('„'),
+    Some('…'),
+    Some('†'),
+    Some('‡'),
+    Some('ˆ'),
+    Some('‰'),
+    Some('Š'),
+    Some('‹'),
+    Some('Œ'),
+    None,
+    Some('Ž'),
+    None,
+    None,
+    Some('‘'),
+    Some('’'),
+    Some('“'),
+    Some('”'),
+    Some('•'),
+    Some('–'),
+    Some('—'),
+    Some('˜'),
+    Some('™'),
+    Some('š'),
+    Some('›'),
+    Some('œ'),
+    None,
+    Some('ž'),
+    Some('Ÿ'),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Word {
+    text: String,
+    bounds: Rectangle,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TextLayer {
+    words: Vec<Word>,
+}
+
+impl TextLayer {
+    pub(crate) fn extract(page: &RawPage) -> Option<TextLayer> {
+        page.text_layout()
+            .map(|(text, layout)| TextLayer::from_layout(&text, &layout))
+    }
+
+    /// Group characters into words. Poppler gives us one rectangle per character of the text.
+    fn from_layout(text: &str, layout: &[Rectangle]) -> TextLayer {
+        let mut words = Vec::new();
+        let mut current: Option<Word> = None;
+
+        for (character, bounds) in text.chars().zip(layout.iter()) {
+            if character.is_whitespace() {
+                words.extend(current.take());
+                continue;
+            }
+
+            match &mut current {
+                Some(word) => {
+                    word.text.push(character);
+                    word.bounds = union(word.bounds, *bounds);
+                }
+                None => {
+                    current = Some(Word {
+                        text: character.to_string(),
+                        bounds: *bounds,
+                    })
+                }
+            }
+        }
+        words.extend(current);
+
+        TextLayer { words }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.words.is_empty()
+    }
+
+    /// The words as invisible text, positioned to match an image placed at the origin of the
+    /// page with `size.ppi`. font is the name of a Helvetica font in the page's resources.
+    pub(crate) fn operations(&self, font: &str, size: PageSize) -> Vec<Operation> {
+        let mut operations = Vec::new();
+        for placement in self.placements(size) {
+            operations.extend(vec![
+                Operation::new("BT", vec![]),
+                Operation::new("Tr", vec![Object::Integer(INVISIBLE_RENDERING_MODE)]),
+                Operation::new(
+                    "Tf",
+                    vec![Object::Name(font.into()), Object::Real(placement.font_size)],
+                ),
+                Operation::new("Tz", vec![Object::Real(placement.scaling)]),
+                Operation::new(
+                    "Td",
+                    vec![Object::Real(placement.x), Object::Real(placement.y)],
+                ),
+                Operation::new("Tj", vec![Object::string_literal(placement.text)]),
+                Operation::new("ET", vec![]),
+            ]);
+        }
+        operations
+    }
+
+    /// Words WinAnsiEncoding can't write are skipped
+    fn placements(&self, size: PageSize) -> impl Iterator<Item = Placement> + '_ {
+        let page_height = size.height.as_f64();
+
+        self.words.iter().filter_map(move |Word { text, bounds }| {
+            let font_size = image_space(bounds.y2 - bounds.y1, size);
+            if font_size <= 0.0 {
+                return None;
+            }
+            let text = text.chars().map(win_ansi).collect::<Option<Vec<u8>>>()?;
+            let width = image_space(bounds.x2 - bounds.x1, size);
+            // One byte per character
+            let natural_width = AVERAGE_GLYPH_WIDTH_EM * font_size * text.len() as f64;
+
+            Some(Placement {
+                text,
+                font_size,
+                scaling: width / natural_width * 100.0,
+                // PDF measures from the bottom left, Poppler from the top left. We put the
+                // baseline at the bottom of the box.
+                x: image_space(bounds.x1, size),
+                y: image_space(page_height - bounds.y2, size),
+            })
+        })
+    }
+}
+
+/// Where and how to write one word
+struct Placement {
+    /// In WinAnsiEncoding
+    text: Vec<u8>,
+    font_size: f64,
+    /// Horizontal scaling in percent
+    scaling: f64,
+    x: f64,
+    y: f64,
+}
+
+/// The page image is slightly smaller than the page (see `Pt::to_px`), so we scale the text
+/// the same way to keep it lined up
+fn image_space(points: f64, size: PageSize) -> f64 {
+    let pixels = Pt::new(points, size.ppi).to_px().as_f64();
+    pixels * 72.0 / size.ppi.as_f64()
+}
+
+/// The code of a character in WinAnsiEncoding, which builtin fonts use
+fn win_ansi(character: char) -> Option<u8> {
+    match character as u32 {
+        0x20..=0x7e | 0xa0..=0xff => Some(character as u8),
+        _ => WIN_ANSI_EXTRAS
+            .iter()
+            .position(|extra| *extra == Some(character))
+            .map(|index| 0x80 + index as u8),
+    }
+}
+
+fn union(a: Rectangle, b: Rectangle) -> Rectangle {
+    Rectangle {
+        x1: a.x1.min(b.x1),
+        y1: a.y1.min(b.y1),
+        x2: a.x2.max(b.x2),
+        y2: a.y2.max(b.y2),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::PPI;
+
+    fn rect(x1: f64, x2: f64) -> Rectangle {
+        Rectangle {
+            x1,
+            y1: 10.0,
+            x2,
+            y2: 20.0,
+        }
+    }
+
+    #[test]
+    fn groups_characters_into_words() {
+        let layout = [
+            rect(0.0, 5.0),
+            rect(5.0, 10.0),
+            rect(10.0, 12.0),
+            rect(12.0, 17.0),
+            rect(17.0, 18.0),
+            rect(0.0, 5.0),
+        ];
+        let layer = TextLayer::from_layout("hi y\nz", &layout);
+
+        assert_eq!(
+            layer.words,
+            vec![
+                Word {
+                    text: "hi".into(),
+                    bounds: rect(0.0, 10.0),
+                },
+                Word {
+                    text: "y".into(),
+                    bounds: rect(12.0, 17.0),
+                },
+                Word {
+                    text: "z".into(),
+                    bounds: rect(0.0, 5.0),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn empty_text_has_no_words() {
+        assert!(TextLayer::from_layout("", &[]).is_empty());
+    }
+
+    #[test]
+    fn encodes_text_as_win_ansi() {
+        let text = "“naïve”—€5 Ωμ café";
+        let layer = TextLayer::from_layout(text, &vec![rect(0.0, 5.0); text.chars().count()]);
+        let ppi = PPI(72.0);
+        let size = PageSize::new(Pt::new(612.0, ppi), Pt::new(792.0, ppi), ppi);
+
+        let written: Vec<Vec<u8>> = layer
+            .operations("F1", size)
+            .into_iter()
+            .filter(|operation| operation.operator == "Tj")
+            .map(|operation| operation.operands[0].as_str().unwrap().to_vec())
+            .collect();
+        assert_eq!(
+            written,
+            vec![
+                vec![0x93, b'n', b'a', 0xef, b'v', b'e', 0x94, 0x97, 0x80, b'5'],
+                // The Greek word is left out
+                vec![b'c', b'a', b'f', 0xe9],
+            ]
+        );
+    }
+}
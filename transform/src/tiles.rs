@@ -0,0 +1,102 @@
+//! Rendering pages a piece at a time. Cairo can't create surfaces longer than 32767 pixels on a
+//! side, and a poster at `Quality::Extreme` would take gigabytes as a single surface.
+
+use crate::RGBA_PIXEL_SIZE;
+use image::{Rgb, RgbImage};
+
+/// The longest side of a tile, so a tile's surface takes at most 64MiB
+pub(crate) const TILE_SIDE: u32 = 4096;
+
+/// A rectangle of a rendered page, in pixels from its top left
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Tile {
+    pub(crate) x: u32,
+    pub(crate) y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+}
+
+impl Tile {
+    /// A single tile covering a whole width x height page
+    pub(crate) fn whole(width: u32, height: u32) -> Tile {
+        Tile {
+            x: 0,
+            y: 0,
+            width,
+            height,
+        }
+    }
+
+    /// The tiles covering a width x height page, row by row
+    pub(crate) fn covering(width: u32, height: u32) -> Vec<Tile> {
+        let mut tiles = Vec::new();
+        for y in (0..height).step_by(TILE_SIDE as usize) {
+            for x in (0..width).step_by(TILE_SIDE as usize) {
+                tiles.push(Tile {
+                    x,
+                    y,
+                    width: TILE_SIDE.min(width - x),
+                    height: TILE_SIDE.min(height - y),
+                });
+            }
+        }
+        tiles
+    }
+
+    /// Copy the rendered tile (b, g, r, a pixels) into its place in the page
+    pub(crate) fn copy_into(&self, bgra_data: &[u8], page: &mut RgbImage) {
+        let rows = bgra_data
+            .chunks_exact(self.width as usize * RGBA_PIXEL_SIZE)
+            .take(self.height as usize);
+        for (y, row) in (self.y..).zip(rows) {
+            for (x, pixel) in (self.x..).zip(row.chunks_exact(RGBA_PIXEL_SIZE)) {
+                page.put_pixel(x, y, Rgb([pixel[2], pixel[1], pixel[0]]));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tiles_cover_the_page() {
+        let tiles = Tile::covering(TILE_SIDE + 10, 20);
+
+        assert_eq!(
+            tiles,
+            vec![
+                Tile {
+                    x: 0,
+                    y: 0,
+                    width: TILE_SIDE,
+                    height: 20
+                },
+                Tile {
+                    x: TILE_SIDE,
+                    y: 0,
+                    width: 10,
+                    height: 20
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_tiles_into_place() {
+        let mut page = RgbImage::new(3, 2);
+        // One b, g, r, a pixel at the bottom right
+        let tile = Tile {
+            x: 2,
+            y: 1,
+            width: 1,
+            height: 1,
+        };
+
+        tile.copy_into(&[1, 2, 3, 255], &mut page);
+
+        assert_eq!(page.get_pixel(2, 1), &Rgb([3, 2, 1]));
+        assert_eq!(page.get_pixel(0, 0), &Rgb([0, 0, 0]));
+    }
+}
@@ -0,0 +1,526 @@
+//! Recoloring that keeps a page's original content instead of rasterizing it.
+//!
+//! We paint a full-page rectangle in the background color and then draw the original content
+//! stream on top of it, so text stays selectable and vectors stay sharp.
+
+use crate::{Color, PageSize, Result, TransformationError};
+use lopdf::content::{Content, Operation};
+use lopdf::{Dictionary, Document, Object, ObjectId, Stream};
+use std::collections::BTreeMap;
+
+/// Keys a page can inherit from its ancestors in the page tree
+const INHERITED_PAGE_KEYS: [&[u8]; 3] = [b"MediaBox", b"CropBox", b"Rotate"];
+
+/// Forms can contain forms, so we bound how deep we look for images
+const MAX_FORM_DEPTH: usize = 8;
+
+pub(crate) struct VectorPage {
+    /// Zero indexed page number in the source document
+    page_num: usize,
+    size: PageSize,
+    content: PageContent,
+}
+
+enum PageContent {
+    /// The rewritten content stream, uncompressed
+    Rewritten(Vec<u8>),
+    /// The source page's content streams are copied as they are. Holds their size in bytes.
+    Original(usize),
+}
+
+impl VectorPage {
+    pub(crate) fn new(page_num: usize, size: PageSize, content: Vec<u8>) -> Self {
+        VectorPage {
+            page_num,
+            size,
+            content: PageContent::Rewritten(content),
+        }
+    }
+
+    /// A page that keeps the source page's content as is. See `original_content_len` for
+    /// content_len.
+    pub(crate) fn original(page_num: usize, size: PageSize, content_len: usize) -> Self {
+        VectorPage {
+            page_num,
+            size,
+            content: PageContent::Original(content_len),
+        }
+    }
+
+    pub(crate) fn is_original(&self) -> bool {
+        match self.content {
+            PageContent::Rewritten(_) => false,
+            PageContent::Original(_) => true,
+        }
+    }
+
+    pub(crate) fn page_num(&self) -> usize {
+        self.page_num
+    }
+
+    pub(crate) fn size(&self) -> PageSize {
+        self.size
+    }
+
+    pub(crate) fn content_len(&self) -> usize {
+        match &self.content {
+            PageContent::Rewritten(content) => content.len(),
+            PageContent::Original(content_len) => *content_len,
+        }
+    }
+}
+
+/// Returns the page's content with the background painted underneath it, or None if the page
+/// can't be safely rewritten and should be rasterized instead.
+pub(crate) fn rewrite_page(
+    source: &Document,
+    page_num: usize,
+    background_color: Color,
+) -> Option<Vec<u8>> {
+    let page_id = page_id(source, page_num)?;
+
+    let content = source.get_page_content(page_id).ok()?;
+    let operations = Content::decode(&content).ok()?.operations;
+
+    // Images would keep their white areas, which looks worse than the raster path
+    if operations.iter().any(|op| op.operator == "BI") {
+        return None;
+    }
+    let resources = inherited(source, page_id, b"Resources").and_then(|res| as_dict(source, res));
+    if let Some(resources) = resources {
+        if draws_images(source, resources, 0) {
+            return None;
+        }
+    }
+
+    let media_box = inherited(source, page_id, b"MediaBox")
+        .and_then(|media_box| rectangle(source, media_box))?;
+
+    let mut rewritten = background_operations(media_box, background_color);
+    // Isolate the original content so it starts from the default graphics state
+    rewritten.push(Operation::new("q", vec![]));
+    rewritten.extend(operations);
+    rewritten.push(Operation::new("Q", vec![]));
+
+    Content {
+        operations: rewritten,
+    }
+    .encode()
+    .ok()
+}
+
+/// The size of the page's content streams as they're stored, or None if they can't be read.
+///
+/// The streams are copied rather than decoded and joined, which could run the last token of one
+/// stream into the first of the next, or drop content in a filter lopdf can't decode.
+pub(crate) fn original_content_len(source: &Document, page_num: usize) -> Option<usize> {
+    let page = source.get_dictionary(page_id(source, page_num)?).ok()?;
+    let contents = match page.get(b"Contents") {
+        Ok(contents) => contents,
+        // A blank page
+        Err(_) => return Some(0),
+    };
+    let streams: Vec<&Object> = match source.dereference(contents).ok()?.1 {
+        Object::Array(streams) => streams.iter().collect(),
+        stream => vec![stream],
+    };
+
+    streams
+        .into_iter()
+        .map(|stream| {
+            let (_, stream) = source.dereference(stream).ok()?;
+            Some(stream.as_stream().ok()?.content.len())
+        })
+        .sum()
+}
+
+fn background_operations(media_box: [f64; 4], color: Color) -> Vec<Operation> {
+    let [x0, y0, x1, y1] = media_box;
+    let component = |value: u8| Object::Real(value as f64 / 255.0);
+
+    vec![
+        Operation::new("q", vec![]),
+        Operation::new(
+            "rg",
+            vec![component(color.r), component(color.g), component(color.b)],
+        ),
+        Operation::new(
+            "re",
+            vec![
+                Object::Real(x0.min(x1)),
+                Object::Real(y0.min(y1)),
+                Object::Real((x1 - x0).abs()),
+                Object::Real((y1 - y0).abs()),
+            ],
+        ),
+        Operation::new("f", vec![]),
+        Operation::new("Q", vec![]),
+    ]
+}
+
+fn draws_images(doc: &Document, resources: &Dictionary, depth: usize) -> bool {
+    if depth > MAX_FORM_DEPTH {
+        // Be conservative, we can't tell what's in there
+        return true;
+    }
+
+    let xobjects = match resources
+        .get(b"XObject")
+        .ok()
+        .and_then(|xobjects| as_dict(doc, xobjects))
+    {
+        Some(xobjects) => xobjects,
+        None => return false,
+    };
+
+    xobjects.iter().any(|(_, xobject)| {
+        let stream = match doc
+            .dereference(xobject)
+            .ok()
+            .and_then(|(_, object)| object.as_stream().ok())
+        {
+            Some(stream) => stream,
+            None => return false,
+        };
+
+        match stream.dict.get(b"Subtype").and_then(Object::as_name) {
+            Ok(b"Image") => true,
+            Ok(b"Form") => stream
+                .dict
+                .get(b"Resources")
+                .ok()
+                .and_then(|resources| as_dict(doc, resources))
+                .map(|resources| draws_images(doc, resources, depth + 1))
+                .unwrap_or(false),
+            _ => false,
+        }
+    })
+}
+
+/// Replace the placeholder pages of an output PDF with the rewritten originals.
+///
+/// pages are (index of the page in the output, page). See `import_object` for imported.
+pub(crate) fn splice(
+    out: &mut Document,
+    source: &Document,
+    pages: Vec<(usize, VectorPage)>,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Result<()> {
+    let out_pages = out.get_pages();
+
+    for (index, page) in pages {
+        let out_id = *out_pages
+            .get(&(index as u32 + 1))
+            .ok_or(TransformationError::NonexistentPage(index))?;
+        let source_id =
+            page_id(source, page.page_num).ok_or(TransformationError::NonexistentPage(index))?;
+
+        let resources = match inherited(source, source_id, b"Resources") {
+            Some(resources) => import_object(out, source, resources, imported),
+            None => Object::Dictionary(Dictionary::new()),
+        };
+        let inherited_values: Vec<(&[u8], Object)> = INHERITED_PAGE_KEYS
+            .iter()
+            .filter_map(|key| {
+                inherited(source, source_id, key)
+                    .map(|value| (*key, import_object(out, source, value, imported)))
+            })
+            .collect();
+        let contents = match page.content {
+            PageContent::Rewritten(content) => Some(Object::Reference(
+                out.add_object(Stream::new(Dictionary::new(), content)),
+            )),
+            PageContent::Original(_) => source
+                .get_dictionary(source_id)?
+                .get(b"Contents")
+                .ok()
+                .map(|contents| import_object(out, source, contents, imported)),
+        };
+
+        let out_page = out.get_object_mut(out_id)?.as_dict_mut()?;
+        out_page.set("Resources", resources);
+        if let Some(contents) = contents {
+            out_page.set("Contents", contents);
+        }
+        for (key, value) in inherited_values {
+            out_page.set(key.to_vec(), value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Deep copy an object from source into dest, copying each referenced object at most once.
+///
+/// imported maps ids in source to ids in dest.
+pub(crate) fn import_object(
+    dest: &mut Document,
+    source: &Document,
+    object: &Object,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Object {
+    match object {
+        Object::Reference(id) => {
+            if let Some(dest_id) = imported.get(id) {
+                return Object::Reference(*dest_id);
+            }
+
+            let dest_id = dest.new_object_id();
+            // Insert before recursing so cycles resolve to the id we just allocated
+            imported.insert(*id, dest_id);
+            let copy = match source.get_object(*id) {
+                Ok(object) => import_object(dest, source, object, imported),
+                Err(_) => Object::Null,
+            };
+            dest.objects.insert(dest_id, copy);
+            Object::Reference(dest_id)
+        }
+        Object::Array(array) => Object::Array(
+            array
+                .iter()
+                .map(|item| import_object(dest, source, item, imported))
+                .collect(),
+        ),
+        Object::Dictionary(dict) => Object::Dictionary(import_dict(dest, source, dict, imported)),
+        Object::Stream(stream) => {
+            let dict = import_dict(dest, source, &stream.dict, imported);
+            let mut copy = stream.clone();
+            copy.dict = dict;
+            Object::Stream(copy)
+        }
+        other => other.clone(),
+    }
+}
+
+fn import_dict(
+    dest: &mut Document,
+    source: &Document,
+    dict: &Dictionary,
+    imported: &mut BTreeMap<ObjectId, ObjectId>,
+) -> Dictionary {
+    let mut copy = Dictionary::new();
+    for (key, value) in dict.iter() {
+        // Following Parent would drag in the source's entire page tree
+        if key.as_slice() == b"Parent" {
+            continue;
+        }
+        copy.set(key.clone(), import_object(dest, source, value, imported));
+    }
+    copy
+}
+
+pub(crate) fn page_id(doc: &Document, page_num: usize) -> Option<ObjectId> {
+    doc.get_pages().get(&(page_num as u32 + 1)).copied()
+}
+
+/// Look up a key on a page, walking up the page tree for inheritable attributes
+pub(crate) fn inherited<'a>(
+    doc: &'a Document,
+    page_id: ObjectId,
+    key: &[u8],
+) -> Option<&'a Object> {
+    let mut node = doc.get_dictionary(page_id).ok()?;
+    // Bound the walk in case the page tree has a cycle
+    for _ in 0..32 {
+        if let Ok(value) = node.get(key) {
+            return Some(value);
+        }
+        node = node
+            .get(b"Parent")
+            .and_then(Object::as_reference)
+            .and_then(|parent| doc.get_dictionary(parent))
+            .ok()?;
+    }
+    None
+}
+
+pub(crate) fn as_dict<'a>(doc: &'a Document, object: &'a Object) -> Option<&'a Dictionary> {
+    doc.dereference(object)
+        .ok()
+        .and_then(|(_, object)| object.as_dict().ok())
+}
+
+pub(crate) fn rectangle(doc: &Document, object: &Object) -> Option<[f64; 4]> {
+    let (_, object) = doc.dereference(object).ok()?;
+    let values = object
+        .as_array()
+        .ok()?
+        .iter()
+        .map(|value| match value {
+            Object::Integer(value) => Some(*value as f64),
+            Object::Real(value) => Some(*value),
+            _ => None,
+        })
+        .collect::<Option<Vec<f64>>>()?;
+
+    match values.as_slice() {
+        [x0, y0, x1, y1] => Some([*x0, *y0, *x1, *y1]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pdf_to_pdf::transform_with_options;
+    use crate::{Mode, Pt, Quality, Rendering, TransformationStateOptions, PPI};
+
+    fn get_in_blob() -> Vec<u8> {
+        include_bytes!("../test_assets/multipage_test.pdf").to_vec()
+    }
+
+    /// A one page document with nothing but a rectangle on it. The test PDFs are all scans,
+    /// which draw images and so are never rewritten.
+    fn vector_doc() -> Document {
+        let mut doc = Document::new();
+        let pages_id = doc.new_object_id();
+        let content = Content {
+            operations: vec![
+                Operation::new(
+                    "re",
+                    vec![
+                        Object::Integer(10),
+                        Object::Integer(10),
+                        Object::Integer(50),
+                        Object::Integer(50),
+                    ],
+                ),
+                Operation::new("f", vec![]),
+            ],
+        };
+        let content_id = doc.add_object(Stream::new(Dictionary::new(), content.encode().unwrap()));
+
+        let mut page = Dictionary::new();
+        page.set("Type", "Page");
+        page.set("Parent", Object::Reference(pages_id));
+        page.set(
+            "MediaBox",
+            vec![
+                Object::Integer(0),
+                Object::Integer(0),
+                Object::Integer(612),
+                Object::Integer(792),
+            ],
+        );
+        page.set("Contents", Object::Reference(content_id));
+        let page_id = doc.add_object(page);
+
+        let mut pages = Dictionary::new();
+        pages.set("Type", "Pages");
+        pages.set("Kids", vec![Object::Reference(page_id)]);
+        pages.set("Count", Object::Integer(1));
+        doc.objects.insert(pages_id, Object::Dictionary(pages));
+
+        let mut catalog = Dictionary::new();
+        catalog.set("Type", "Catalog");
+        catalog.set("Pages", Object::Reference(pages_id));
+        let catalog_id = doc.add_object(catalog);
+        doc.trailer.set("Root", Object::Reference(catalog_id));
+        doc
+    }
+
+    #[test]
+    fn rewrites_page_with_background_first() {
+        let source = vector_doc();
+        let content = rewrite_page(&source, 0, Color::new(255, 0, 0)).unwrap();
+        let operations = Content::decode(&content).unwrap().operations;
+        let operators: Vec<&str> = operations
+            .iter()
+            .take(5)
+            .map(|op| op.operator.as_str())
+            .collect();
+        assert_eq!(operators, vec!["q", "rg", "re", "f", "Q"]);
+
+        // Scans stay rasterized
+        let scan = Document::load_mem(&get_in_blob()).unwrap();
+        assert!(rewrite_page(&scan, 0, Color::new(255, 0, 0)).is_none());
+    }
+
+    #[test]
+    fn copies_original_content_streams_as_they_are() {
+        let mut source = vector_doc();
+        let source_page = page_id(&source, 0).unwrap();
+        // Joined, the first two would read "0 0 10 10 ref"
+        let first = source.add_object(Stream::new(Dictionary::new(), b"0 0 10 10 re".to_vec()));
+        let second = source.add_object(Stream::new(Dictionary::new(), b"f".to_vec()));
+        let mut undecodable = Dictionary::new();
+        undecodable.set("Filter", "LZWDecode");
+        let third = source.add_object(Stream::new(undecodable, b"\x80\x0b\x60".to_vec()));
+        source
+            .get_object_mut(source_page)
+            .and_then(Object::as_dict_mut)
+            .unwrap()
+            .set(
+                "Contents",
+                vec![
+                    Object::Reference(first),
+                    Object::Reference(second),
+                    Object::Reference(third),
+                ],
+            );
+        let content_len = original_content_len(&source, 0).unwrap();
+        assert_eq!(content_len, 16);
+
+        let mut out = vector_doc();
+        let ppi = PPI(72.0);
+        let size = PageSize::new(Pt::new(612.0, ppi), Pt::new(792.0, ppi), ppi);
+        let page = VectorPage::original(0, size, content_len);
+        splice(&mut out, &source, vec![(0, page)], &mut BTreeMap::new()).unwrap();
+
+        let out_page = out.get_dictionary(page_id(&out, 0).unwrap()).unwrap();
+        let streams: Vec<&Stream> = out_page
+            .get(b"Contents")
+            .and_then(Object::as_array)
+            .unwrap()
+            .iter()
+            .map(|stream| out.dereference(stream).unwrap().1.as_stream().unwrap())
+            .collect();
+        assert_eq!(streams.len(), 3);
+        assert_eq!(streams[0].content, b"0 0 10 10 re");
+        assert_eq!(streams[1].content, b"f");
+        assert_eq!(
+            streams[2]
+                .dict
+                .get(b"Filter")
+                .and_then(Object::as_name)
+                .unwrap(),
+            b"LZWDecode"
+        );
+        assert_eq!(streams[2].content, b"\x80\x0b\x60");
+    }
+
+    #[test]
+    fn reports_rendering_per_page() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            pages: Some("1-2".parse().unwrap()),
+            mode: Mode::Vector,
+            ..TransformationStateOptions::default()
+        };
+        let complete = transform_with_options(get_in_blob(), options)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        assert_eq!(complete.renderings().len(), 2);
+        assert!(!complete.into_bytes().is_empty());
+    }
+
+    #[test]
+    fn raster_mode_never_keeps_vectors() {
+        let options = TransformationStateOptions {
+            quality: Quality::ExtremeLow,
+            mode: Mode::Raster,
+            ..TransformationStateOptions::default()
+        };
+        let complete = transform_with_options(get_in_blob(), options)
+            .unwrap()
+            .finish()
+            .unwrap();
+
+        assert!(complete
+            .renderings()
+            .iter()
+            .all(|rendering| *rendering == Rendering::Raster));
+    }
+}